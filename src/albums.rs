@@ -0,0 +1,34 @@
+//! Documents sent together as an album, collected so they're converted as one batch.
+//! Telegram delivers each document of an album as its own update, sharing a media group id.
+
+use std::{collections::HashMap, sync::Mutex};
+
+use crate::StoredInput;
+
+#[derive(Default)]
+pub struct Albums {
+    /// Documents received so far, keyed by chat and media group id
+    pending: Mutex<HashMap<(i64, String), Vec<StoredInput>>>,
+}
+
+impl Albums {
+    /// Add `input` to the album `media_group_id` of `chat_id`.
+    /// Returns `true` for the album's first document, whose handler is the one to submit the album.
+    pub fn push(&self, chat_id: i64, media_group_id: &str, input: StoredInput) -> bool {
+        let mut pending = self.pending.lock().unwrap();
+        let inputs = pending
+            .entry((chat_id, media_group_id.to_owned()))
+            .or_default();
+        inputs.push(input);
+        inputs.len() == 1
+    }
+
+    /// Documents of the album received so far, which are then forgotten
+    pub fn take(&self, chat_id: i64, media_group_id: &str) -> Vec<StoredInput> {
+        self.pending
+            .lock()
+            .unwrap()
+            .remove(&(chat_id, media_group_id.to_owned()))
+            .unwrap_or_default()
+    }
+}
@@ -0,0 +1,79 @@
+//! The AMQP connection shared by everything talking to the broker, re-established when it drops
+
+use std::{
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
+
+use anyhow::{bail, Context, Result};
+use tokio::sync::Mutex;
+use tracing::{info, warn};
+
+/// First delay before re-subscribing a consumer that ended, doubled on each failed attempt
+pub const RECONNECT_INITIAL_BACKOFF: Duration = Duration::from_secs(1);
+pub const RECONNECT_MAX_BACKOFF: Duration = Duration::from_secs(30);
+
+pub struct AmqpConnection {
+    addr: String,
+    /// Replaced by the first caller that finds it no longer connected
+    conn: Mutex<Arc<lapin::Connection>>,
+    /// Set on shutdown, after which the connection isn't re-established
+    closed: AtomicBool,
+}
+
+impl AmqpConnection {
+    pub async fn connect(addr: &str) -> Result<Self> {
+        Ok(Self {
+            addr: addr.to_owned(),
+            conn: Mutex::new(Arc::new(open(addr).await?)),
+            closed: AtomicBool::new(false),
+        })
+    }
+
+    /// The connection, re-established first if the broker dropped it.
+    /// Fails once `close` was called, or if the broker can't be reached.
+    pub async fn get(&self) -> Result<Arc<lapin::Connection>> {
+        let mut conn = self.conn.lock().await;
+        if self.is_closed() {
+            bail!("The AMQP connection was closed");
+        }
+        if !conn.status().connected() {
+            warn!(
+                "AMQP connection is {:?}, reconnecting",
+                conn.status().state()
+            );
+            *conn = Arc::new(open(&self.addr).await?);
+            info!("Reconnected to AMQP");
+        }
+        Ok(conn.clone())
+    }
+
+    /// Whether the connection was closed on purpose
+    pub fn is_closed(&self) -> bool {
+        self.closed.load(Ordering::SeqCst)
+    }
+
+    /// Close the connection for good, ending the consumers on it
+    pub async fn close(&self) -> Result<()> {
+        self.closed.store(true, Ordering::SeqCst);
+        let conn = self.conn.lock().await.clone();
+        if conn.status().connected() {
+            conn.close(0, "").await?;
+        }
+        Ok(())
+    }
+}
+
+async fn open(addr: &str) -> Result<lapin::Connection> {
+    lapin::Connection::connect(
+        addr,
+        lapin::ConnectionProperties::default()
+            .with_executor(tokio_executor_trait::Tokio::current())
+            .with_reactor(tokio_reactor_trait::Tokio),
+    )
+    .await
+    .context("Failed to connect to AMQP")
+}
@@ -0,0 +1,401 @@
+//! Settings read from environment variables once at startup
+
+use std::{
+    env, fs,
+    net::SocketAddr,
+    path::{Path, PathBuf},
+    str::FromStr,
+    time::Duration,
+};
+
+use anyhow::{bail, Context, Result};
+use sentry::types::Dsn;
+use url::Url;
+
+pub const BYTES_PER_MB: u64 = 1024 * 1024;
+
+/// Names of the AMQP queues shared with the worker, each prefixed with `$QUEUE_PREFIX`
+pub struct QueueNames {
+    /// `pandoc-bot-jobs-heavy`, requests for outputs typeset with LaTeX
+    pub heavy_jobs: String,
+    /// `pandoc-bot-jobs-light`, requests for every other output
+    pub light_jobs: String,
+    /// `pandoc-bot-dlq`, jobs that expired or were rejected
+    pub dead_letters: String,
+    /// `pandoc-outputs`, responses from the worker to the bot
+    pub outputs: String,
+    /// `pandoc-bot-heartbeat`, liveness messages from the workers
+    pub heartbeat: String,
+}
+
+impl QueueNames {
+    fn with_prefix(prefix: &str) -> Self {
+        Self {
+            heavy_jobs: format!("{prefix}pandoc-bot-jobs-heavy"),
+            light_jobs: format!("{prefix}pandoc-bot-jobs-light"),
+            dead_letters: format!("{prefix}pandoc-bot-dlq"),
+            outputs: format!("{prefix}pandoc-outputs"),
+            heartbeat: format!("{prefix}pandoc-bot-heartbeat"),
+        }
+    }
+
+    /// Both job queues, heavy first
+    pub fn jobs(&self) -> [&str; 2] {
+        [&self.heavy_jobs, &self.light_jobs]
+    }
+
+    /// The job queue converting to `to_filetype` goes to, so heavy and light workers scale separately.
+    /// Unknown targets go to the light queue.
+    pub fn queue_for_target(&self, to_filetype: &str) -> &str {
+        match to_filetype {
+            "pdf" | "beamer" => &self.heavy_jobs,
+            _ => &self.light_jobs,
+        }
+    }
+}
+
+/// Where outputs too large for Telegram are served from
+pub struct DownloadLinks {
+    /// `$PUBLIC_BASE_URL`, which links are built on
+    pub base_url: Url,
+    /// `$DOWNLOAD_BIND_ADDR`, defaults to `0.0.0.0:8080`
+    pub bind_addr: SocketAddr,
+    /// `$DOWNLOAD_PATH`, defaults to `./downloads`
+    pub path: PathBuf,
+    /// `$DOWNLOAD_TTL_SECS`, defaults to 1 day
+    pub ttl: Duration,
+}
+
+/// How updates are received from Telegram
+pub enum BotMode {
+    Polling,
+    Webhook { url: Url, bind_addr: SocketAddr },
+}
+
+/// Where the dialogue states are kept
+pub enum DialogueStorage {
+    /// `$DIALOGUE_DB_PATH`, defaults to `<state_path>/dialogue.sqlite3`
+    Sqlite { path: PathBuf },
+    /// Lost on restart, for ephemeral deployments
+    InMemory,
+    /// `$REDIS_URL`, shared by several instances of the bot
+    Redis { url: String },
+}
+
+pub struct Config {
+    /// `$AMQP_ADDR`, defaults to `amqp://127.0.0.1:5672`
+    pub amqp_addr: String,
+    /// `$AMQP_PREFETCH`, unacknowledged responses the broker may send ahead, defaults to 10
+    pub amqp_prefetch: u16,
+    /// Queue names with `$QUEUE_PREFIX` applied, which defaults to none
+    pub queues: QueueNames,
+    /// `$INPUT_BASE_PATH`, defaults to `./inputs`
+    pub input_base_path: PathBuf,
+    /// `$STATE_PATH`, defaults to `./`
+    pub state_path: PathBuf,
+    /// `$DIALOGUE_STORAGE`, one of `sqlite`, `inmemory` or `redis`, defaults to `sqlite`
+    pub dialogue_storage: DialogueStorage,
+    /// `$MAX_FILE_SIZE_BYTES`, defaults to 20 MB
+    pub max_file_size_bytes: u64,
+    /// `$INPUT_MAX_AGE_SECS`, defaults to 1 day
+    pub input_max_age: Duration,
+    /// `$INPUT_CLEANUP_INTERVAL_SECS`, how often inputs older than `input_max_age` are removed,
+    /// defaults to 1 hour
+    pub input_cleanup_interval: Duration,
+    /// `$JOB_TIMEOUT_SECS`, defaults to 5 minutes
+    pub job_timeout: Duration,
+    /// `$MAX_JOBS_PER_HOUR`, defaults to 10
+    pub max_jobs_per_hour: usize,
+    /// `$MAX_CONCURRENT_JOBS_PER_USER`, defaults to 2
+    pub max_concurrent_jobs_per_user: usize,
+    /// `$METRICS_ADDR`, the metrics server is disabled if unset
+    pub metrics_addr: Option<SocketAddr>,
+    /// `$SHARED_FILE_PATH`, a volume shared with the worker.
+    /// Files are always embedded in the messages if unset.
+    pub shared_file_path: Option<PathBuf>,
+    /// `$MAX_INLINE_FILE_SIZE_BYTES`, defaults to 8 MB.
+    /// Larger files go through `shared_file_path` if it's set.
+    pub max_inline_file_size_bytes: u64,
+    /// `$LOG_DEAD_LETTERS`, defaults to `false`
+    pub log_dead_letters: bool,
+    /// `$CONFIRM_JOBS`, whether to show a summary to confirm before each conversion, defaults to `false`
+    pub confirm_jobs: bool,
+    /// `$SHUTDOWN_GRACE_SECS`, how long to wait for pending jobs on shutdown, defaults to 30 seconds
+    pub shutdown_grace: Duration,
+    /// `$WORKER_HEARTBEAT_TIMEOUT_SECS`, defaults to 1 minute
+    pub worker_heartbeat_timeout: Duration,
+    /// `$KEYBOARD_BUTTONS_PER_ROW`, defaults to 3
+    pub keyboard_buttons_per_row: usize,
+    /// `$MAX_CONCURRENT_DOWNLOADS`, defaults to 4
+    pub max_concurrent_downloads: usize,
+    /// `$OUTPUT_CACHE_ENTRIES`, outputs kept for identical conversions, defaults to 20
+    pub output_cache_entries: usize,
+    /// `$OUTPUT_CACHE_TTL_SECS`, defaults to 1 hour
+    pub output_cache_ttl: Duration,
+    /// `$ADMIN_CHAT_IDS`, comma separated, defaults to none
+    pub admin_chat_ids: Vec<i64>,
+    /// Enabled by setting `$PUBLIC_BASE_URL`, large outputs are rejected otherwise
+    pub download_links: Option<DownloadLinks>,
+    /// `$SENTRY_DSN`, errors and panics are only logged if unset
+    pub sentry_dsn: Option<Dsn>,
+    /// `$BOT_MODE`, either `polling` (the default) or `webhook`.
+    /// Webhooks take `$WEBHOOK_URL` and `$WEBHOOK_BIND_ADDR`, which defaults to `0.0.0.0:8443`.
+    pub bot_mode: BotMode,
+}
+
+impl Config {
+    /// Read the config from env vars, and make sure the paths are writable directories
+    pub fn from_env() -> Result<Self> {
+        let state_path: PathBuf = var_or("STATE_PATH", "./".into())?;
+        let config = Self {
+            amqp_addr: var_or("AMQP_ADDR", "amqp://127.0.0.1:5672".into())?,
+            amqp_prefetch: var_or("AMQP_PREFETCH", 10)?,
+            queues: QueueNames::with_prefix(&var_or::<String>("QUEUE_PREFIX", String::new())?),
+            input_base_path: var_or("INPUT_BASE_PATH", "inputs".into())?,
+            dialogue_storage: dialogue_storage(&state_path)?,
+            state_path,
+            max_file_size_bytes: var_or("MAX_FILE_SIZE_BYTES", 20 * BYTES_PER_MB)?,
+            input_max_age: Duration::from_secs(var_or("INPUT_MAX_AGE_SECS", 24 * 60 * 60)?),
+            input_cleanup_interval: Duration::from_secs(var_or(
+                "INPUT_CLEANUP_INTERVAL_SECS",
+                60 * 60,
+            )?),
+            job_timeout: Duration::from_secs(var_or("JOB_TIMEOUT_SECS", 5 * 60)?),
+            max_jobs_per_hour: var_or("MAX_JOBS_PER_HOUR", 10)?,
+            max_concurrent_jobs_per_user: var_or("MAX_CONCURRENT_JOBS_PER_USER", 2)?,
+            metrics_addr: var("METRICS_ADDR")?,
+            shared_file_path: var("SHARED_FILE_PATH")?,
+            max_inline_file_size_bytes: var_or("MAX_INLINE_FILE_SIZE_BYTES", 8 * BYTES_PER_MB)?,
+            log_dead_letters: var_or("LOG_DEAD_LETTERS", false)?,
+            confirm_jobs: var_or("CONFIRM_JOBS", false)?,
+            shutdown_grace: Duration::from_secs(var_or("SHUTDOWN_GRACE_SECS", 30)?),
+            worker_heartbeat_timeout: Duration::from_secs(var_or(
+                "WORKER_HEARTBEAT_TIMEOUT_SECS",
+                60,
+            )?),
+            keyboard_buttons_per_row: var_or("KEYBOARD_BUTTONS_PER_ROW", 3)?,
+            max_concurrent_downloads: var_or("MAX_CONCURRENT_DOWNLOADS", 4)?,
+            output_cache_entries: var_or("OUTPUT_CACHE_ENTRIES", 20)?,
+            output_cache_ttl: Duration::from_secs(var_or("OUTPUT_CACHE_TTL_SECS", 60 * 60)?),
+            admin_chat_ids: list_var("ADMIN_CHAT_IDS")?,
+            download_links: download_links()?,
+            sentry_dsn: var("SENTRY_DSN")?,
+            bot_mode: bot_mode()?,
+        };
+
+        fs::create_dir_all(&config.input_base_path).with_context(|| {
+            format!(
+                "Failed to create input base path {:?}",
+                config.input_base_path
+            )
+        })?;
+        ensure_writable_dir(&config.input_base_path)?;
+        ensure_writable_dir(&config.state_path)?;
+        if let Some(shared_file_path) = &config.shared_file_path {
+            ensure_writable_dir(shared_file_path)?;
+        }
+        if let Some(download_links) = &config.download_links {
+            fs::create_dir_all(&download_links.path).with_context(|| {
+                format!("Failed to create download path {:?}", download_links.path)
+            })?;
+            ensure_writable_dir(&download_links.path)?;
+        }
+
+        Ok(config)
+    }
+
+    /// `<input_base_path>/<chat_id>/<file_id>`, with `file_id` escaped so it can't leave the directory.
+    /// Grouping by chat lets files left behind by a crash be traced back to their users.
+    pub fn input_file_path(&self, chat_id: i64, file_id: &str) -> PathBuf {
+        self.input_base_path
+            .join(chat_id.to_string())
+            .join(escape_file_name(file_id))
+    }
+
+    /// Path on the shared volume to hand a file of `size` bytes to the worker through,
+    /// or `None` if it should be embedded in the message
+    pub fn shared_file_path_for(&self, job_id: &str, size: u64) -> Option<PathBuf> {
+        let shared_file_path = self.shared_file_path.as_ref()?;
+        (size > self.max_inline_file_size_bytes).then(|| shared_file_path.join(job_id))
+    }
+
+    /// Check that `path` received from the worker is a file directly under the shared volume
+    pub fn resolve_shared_file(&self, path: &str) -> Option<PathBuf> {
+        let shared_file_path = self.shared_file_path.as_ref()?;
+        let path = Path::new(path);
+        let file_name = path.file_name()?;
+        (path.parent() == Some(shared_file_path.as_path()))
+            .then(|| shared_file_path.join(file_name))
+    }
+}
+
+#[cfg(test)]
+impl Config {
+    /// The defaults of `from_env`, without reading the environment or touching the disk
+    pub fn for_tests() -> Self {
+        Self {
+            amqp_addr: "amqp://127.0.0.1:5672".into(),
+            amqp_prefetch: 10,
+            queues: QueueNames::with_prefix(""),
+            input_base_path: "inputs".into(),
+            state_path: "./".into(),
+            dialogue_storage: DialogueStorage::InMemory,
+            max_file_size_bytes: 20 * BYTES_PER_MB,
+            input_max_age: Duration::from_secs(24 * 60 * 60),
+            input_cleanup_interval: Duration::from_secs(60 * 60),
+            job_timeout: Duration::from_secs(5 * 60),
+            max_jobs_per_hour: 10,
+            max_concurrent_jobs_per_user: 2,
+            metrics_addr: None,
+            shared_file_path: None,
+            max_inline_file_size_bytes: 8 * BYTES_PER_MB,
+            log_dead_letters: false,
+            confirm_jobs: false,
+            shutdown_grace: Duration::from_secs(30),
+            worker_heartbeat_timeout: Duration::from_secs(60),
+            keyboard_buttons_per_row: 3,
+            max_concurrent_downloads: 4,
+            output_cache_entries: 20,
+            output_cache_ttl: Duration::from_secs(60 * 60),
+            admin_chat_ids: vec![],
+            download_links: None,
+            sentry_dsn: None,
+            bot_mode: BotMode::Polling,
+        }
+    }
+}
+
+/// Percent-encode every byte of `name` other than `[A-Za-z0-9_-]`.
+/// Telegram file ids only use those, so they come out unchanged,
+/// while separators and dots in `..` can't turn the result into a path.
+fn escape_file_name(name: &str) -> String {
+    let mut escaped = String::with_capacity(name.len());
+    for byte in name.bytes() {
+        if byte.is_ascii_alphanumeric() || byte == b'_' || byte == b'-' {
+            escaped.push(byte as char);
+        } else {
+            escaped.push_str(&format!("%{byte:02X}"));
+        }
+    }
+    escaped
+}
+
+/// Parse env var `key`, or `None` if it's unset
+fn var<T>(key: &str) -> Result<Option<T>>
+where
+    T: FromStr,
+    T::Err: std::error::Error + Send + Sync + 'static,
+{
+    env::var(key)
+        .ok()
+        .map(|value| value.parse())
+        .transpose()
+        .with_context(|| format!("Failed to parse {key}"))
+}
+
+/// Parse env var `key`, or `default` if it's unset
+fn var_or<T>(key: &str, default: T) -> Result<T>
+where
+    T: FromStr,
+    T::Err: std::error::Error + Send + Sync + 'static,
+{
+    Ok(var(key)?.unwrap_or(default))
+}
+
+fn bot_mode() -> Result<BotMode> {
+    let mode: String = var_or("BOT_MODE", "polling".into())?;
+    match mode.as_str() {
+        "polling" => Ok(BotMode::Polling),
+        "webhook" => Ok(BotMode::Webhook {
+            url: var("WEBHOOK_URL")?.context("WEBHOOK_URL is required in webhook mode")?,
+            bind_addr: var_or("WEBHOOK_BIND_ADDR", ([0, 0, 0, 0], 8443).into())?,
+        }),
+        _ => bail!("Invalid BOT_MODE {mode:?}, expected polling or webhook"),
+    }
+}
+
+fn dialogue_storage(state_path: &Path) -> Result<DialogueStorage> {
+    let storage: String = var_or("DIALOGUE_STORAGE", "sqlite".into())?;
+    match storage.as_str() {
+        "sqlite" => Ok(DialogueStorage::Sqlite {
+            path: var_or("DIALOGUE_DB_PATH", state_path.join("dialogue.sqlite3"))?,
+        }),
+        "inmemory" => Ok(DialogueStorage::InMemory),
+        "redis" => Ok(DialogueStorage::Redis {
+            url: var("REDIS_URL")?
+                .context("REDIS_URL is required with the redis dialogue storage")?,
+        }),
+        _ => bail!("Invalid DIALOGUE_STORAGE {storage:?}, expected sqlite, inmemory or redis"),
+    }
+}
+
+fn download_links() -> Result<Option<DownloadLinks>> {
+    let base_url = match var("PUBLIC_BASE_URL")? {
+        Some(base_url) => base_url,
+        None => return Ok(None),
+    };
+    Ok(Some(DownloadLinks {
+        base_url,
+        bind_addr: var_or("DOWNLOAD_BIND_ADDR", ([0, 0, 0, 0], 8080).into())?,
+        path: var_or("DOWNLOAD_PATH", "downloads".into())?,
+        ttl: Duration::from_secs(var_or("DOWNLOAD_TTL_SECS", 24 * 60 * 60)?),
+    }))
+}
+
+/// Parse comma separated env var `key`, or an empty list if it's unset
+fn list_var<T>(key: &str) -> Result<Vec<T>>
+where
+    T: FromStr,
+    T::Err: std::error::Error + Send + Sync + 'static,
+{
+    let value: String = var_or(key, String::new())?;
+    value
+        .split(',')
+        .map(str::trim)
+        .filter(|item| !item.is_empty())
+        .map(str::parse)
+        .collect::<Result<_, _>>()
+        .with_context(|| format!("Failed to parse {key}"))
+}
+
+fn ensure_writable_dir(path: &Path) -> Result<()> {
+    let probe = path.join(".write-probe");
+    fs::write(&probe, b"").with_context(|| format!("{path:?} is not a writable directory"))?;
+    fs::remove_file(&probe).with_context(|| format!("Failed to remove {probe:?}"))?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::Component;
+
+    use super::*;
+
+    #[test]
+    fn input_file_path_stays_in_chat_directory() {
+        let config = Config::for_tests();
+        let chat_dir = config.input_base_path.join("42");
+        for file_id in ["../../etc/passwd", "..", "a/b", "a\\b", "a\0b"] {
+            let path = config.input_file_path(42, file_id);
+            assert!(
+                path.starts_with(&chat_dir),
+                "{file_id:?} escaped to {path:?}"
+            );
+            let components: Vec<_> = path.strip_prefix(&chat_dir).unwrap().components().collect();
+            assert!(
+                matches!(components[..], [Component::Normal(_)]),
+                "{file_id:?} became {path:?}"
+            );
+        }
+    }
+
+    #[test]
+    fn telegram_file_ids_are_unchanged() {
+        assert_eq!(
+            escape_file_name("BQACAgIAAxkBAAIC-_x"),
+            "BQACAgIAAxkBAAIC-_x"
+        );
+        assert_eq!(escape_file_name(".."), "%2E%2E");
+        assert_eq!(escape_file_name("a\0b"), "a%00b");
+    }
+}
@@ -0,0 +1,204 @@
+//! Serving outputs too large for Telegram through expiring links
+
+use std::{
+    collections::HashMap,
+    convert::Infallible,
+    net::SocketAddr,
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use anyhow::{anyhow, Result};
+use hyper::{
+    header::{CONTENT_DISPOSITION, CONTENT_LENGTH, CONTENT_TYPE},
+    service::{make_service_fn, service_fn},
+    Body, Method, Request, Response, Server, StatusCode,
+};
+use tokio_util::io::ReaderStream;
+use tracing::{info, warn};
+use url::Url;
+use uuid::Uuid;
+
+use crate::config::DownloadLinks;
+
+struct Link {
+    file_name: String,
+    expires_at: Instant,
+}
+
+pub struct Downloads {
+    base_url: Url,
+    path: PathBuf,
+    ttl: Duration,
+    /// Served files by token, each stored as `<path>/<token>`
+    links: Mutex<HashMap<String, Link>>,
+}
+
+impl Downloads {
+    /// Start with an empty download directory, since links don't survive restarts
+    pub async fn new(config: &DownloadLinks) -> Result<Self> {
+        let mut entries = tokio::fs::read_dir(&config.path).await?;
+        while let Some(entry) = entries.next_entry().await? {
+            if entry.metadata().await?.is_file() {
+                tokio::fs::remove_file(entry.path()).await?;
+            }
+        }
+
+        Ok(Self {
+            base_url: config.base_url.clone(),
+            path: config.path.clone(),
+            ttl: config.ttl,
+            links: Mutex::new(HashMap::new()),
+        })
+    }
+
+    pub fn ttl(&self) -> Duration {
+        self.ttl
+    }
+
+    /// Serve `contents` as `file_name` until the TTL runs out
+    pub async fn add(&self, file_name: &str, contents: &[u8]) -> Result<Url> {
+        let token = Uuid::new_v4().simple().to_string();
+        tokio::fs::write(self.path.join(&token), contents).await?;
+        self.register(token, file_name)
+    }
+
+    /// Serve a copy of the file at `source` as `file_name` until the TTL runs out
+    pub async fn add_file(&self, file_name: &str, source: &Path) -> Result<Url> {
+        let token = Uuid::new_v4().simple().to_string();
+        tokio::fs::copy(source, self.path.join(&token)).await?;
+        self.register(token, file_name)
+    }
+
+    fn register(&self, token: String, file_name: &str) -> Result<Url> {
+        let mut url = self.base_url.clone();
+        url.path_segments_mut()
+            .map_err(|_| anyhow!("PUBLIC_BASE_URL can't be a base"))?
+            .pop_if_empty()
+            .extend(["download", token.as_str()]);
+
+        self.links.lock().unwrap().insert(
+            token,
+            Link {
+                file_name: file_name.to_owned(),
+                expires_at: Instant::now() + self.ttl,
+            },
+        );
+        Ok(url)
+    }
+
+    /// File name and path of the file behind `token`, if it hasn't expired
+    fn lookup(&self, token: &str) -> Option<(String, PathBuf)> {
+        let links = self.links.lock().unwrap();
+        let link = links.get(token)?;
+        (link.expires_at > Instant::now()).then(|| (link.file_name.clone(), self.path.join(token)))
+    }
+
+    /// Forget the expired links and return their tokens
+    fn take_expired(&self) -> Vec<String> {
+        let now = Instant::now();
+        let mut links = self.links.lock().unwrap();
+        let expired: Vec<_> = links
+            .iter()
+            .filter(|(_, link)| link.expires_at <= now)
+            .map(|(token, _)| token.clone())
+            .collect();
+        for token in &expired {
+            links.remove(token);
+        }
+        expired
+    }
+}
+
+/// Periodically delete the files behind expired links
+pub async fn remove_expired(downloads: Arc<Downloads>) {
+    let mut interval = tokio::time::interval(Duration::from_secs(60));
+    loop {
+        interval.tick().await;
+        for token in downloads.take_expired() {
+            let path = downloads.path.join(&token);
+            if let Err(e) = tokio::fs::remove_file(&path).await {
+                warn!("Failed to remove expired download {path:?}: {e}");
+            }
+        }
+    }
+}
+
+/// Serve `/download/<token>` on `addr`
+pub async fn serve(addr: SocketAddr, downloads: Arc<Downloads>) -> Result<()> {
+    let make_service = make_service_fn(move |_| {
+        let downloads = downloads.clone();
+        async move {
+            Ok::<_, Infallible>(service_fn(move |req| {
+                let downloads = downloads.clone();
+                async move { Ok::<_, Infallible>(handle(req, &downloads).await) }
+            }))
+        }
+    });
+
+    info!("Serving downloads on {addr}");
+    Server::try_bind(&addr)?.serve(make_service).await?;
+    Ok(())
+}
+
+async fn handle(req: Request<Body>, downloads: &Downloads) -> Response<Body> {
+    if req.method() != Method::GET {
+        return with_status(StatusCode::NOT_FOUND);
+    }
+    // The reverse proxy may keep the path of `PUBLIC_BASE_URL` in front
+    let found = req
+        .uri()
+        .path()
+        .rsplit_once("/download/")
+        .and_then(|(_, token)| downloads.lookup(token));
+    let (file_name, path) = match found {
+        Some(found) => found,
+        None => return with_status(StatusCode::NOT_FOUND),
+    };
+
+    let file = match tokio::fs::File::open(&path).await {
+        Ok(file) => file,
+        Err(e) => {
+            warn!("Failed to open download {path:?}: {e}");
+            return with_status(StatusCode::NOT_FOUND);
+        }
+    };
+    let len = file.metadata().await.map(|metadata| metadata.len()).ok();
+
+    let mut response = Response::new(Body::wrap_stream(ReaderStream::new(file)));
+    let headers = response.headers_mut();
+    headers.insert(
+        CONTENT_TYPE,
+        "application/octet-stream"
+            .parse()
+            .expect("Invalid content type"),
+    );
+    if let Ok(value) = format!("attachment; filename=\"{}\"", quote_file_name(&file_name)).parse() {
+        headers.insert(CONTENT_DISPOSITION, value);
+    }
+    if let Some(len) = len {
+        headers.insert(CONTENT_LENGTH, len.into());
+    }
+    response
+}
+
+/// Replace the characters that can't appear in a quoted header value
+fn quote_file_name(file_name: &str) -> String {
+    file_name
+        .chars()
+        .map(|c| {
+            if (c.is_ascii_graphic() && c != '"' && c != '\\') || c == ' ' {
+                c
+            } else {
+                '_'
+            }
+        })
+        .collect()
+}
+
+fn with_status(status: StatusCode) -> Response<Body> {
+    let mut response = Response::new(Body::empty());
+    *response.status_mut() = status;
+    response
+}
@@ -0,0 +1,159 @@
+//! Downloading inputs from URLs pasted by users
+
+use std::{
+    net::{IpAddr, SocketAddr},
+    time::Duration,
+};
+
+use anyhow::{anyhow, Context};
+use reqwest::{header::LOCATION, redirect::Policy, Client};
+use url::{Host, Url};
+
+/// How long fetching a URL may take in total, redirects included
+const FETCH_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Redirects followed before giving up
+const MAX_REDIRECTS: usize = 5;
+
+pub enum FetchError {
+    /// Only http and https URLs are fetched
+    Scheme,
+    /// The host is or resolves to an address that isn't public
+    Forbidden,
+    /// Larger than the limit, going by Content-Length or the bytes received so far
+    TooLarge,
+    Failed(anyhow::Error),
+}
+
+impl From<anyhow::Error> for FetchError {
+    fn from(e: anyhow::Error) -> Self {
+        Self::Failed(e)
+    }
+}
+
+/// A downloaded file
+pub struct Fetched {
+    pub contents: Vec<u8>,
+    /// Last path segment of the final URL, if there is one
+    pub file_name: Option<String>,
+}
+
+/// Download `url` if it's at most `limit` bytes.
+/// Every hop is checked against private addresses and pinned to the checked one,
+/// so the bot can't be used to reach the network it runs in.
+pub async fn fetch(url: &Url, limit: u64) -> Result<Fetched, FetchError> {
+    tokio::time::timeout(FETCH_TIMEOUT, fetch_following_redirects(url.clone(), limit))
+        .await
+        .map_err(|_| FetchError::Failed(anyhow!("Timed out after {FETCH_TIMEOUT:?}")))?
+}
+
+async fn fetch_following_redirects(mut url: Url, limit: u64) -> Result<Fetched, FetchError> {
+    for _ in 0..=MAX_REDIRECTS {
+        let mut response = request(&url).await?;
+
+        if response.status().is_redirection() {
+            let location = response
+                .headers()
+                .get(LOCATION)
+                .and_then(|location| location.to_str().ok())
+                .context("Redirect without a location")?;
+            url = url.join(location).context("Invalid redirect location")?;
+            continue;
+        }
+
+        let response_url = response.url().clone();
+        let status = response.status();
+        if !status.is_success() {
+            return Err(anyhow!("Server responded with {status}").into());
+        }
+        if response.content_length().map_or(false, |len| len > limit) {
+            return Err(FetchError::TooLarge);
+        }
+
+        let mut contents = vec![];
+        while let Some(chunk) = response.chunk().await.context("Failed to read body")? {
+            if (contents.len() + chunk.len()) as u64 > limit {
+                return Err(FetchError::TooLarge);
+            }
+            contents.extend_from_slice(&chunk);
+        }
+
+        let file_name = response_url
+            .path_segments()
+            .and_then(|mut segments| segments.next_back())
+            .filter(|segment| !segment.is_empty())
+            .map(str::to_owned);
+        return Ok(Fetched {
+            contents,
+            file_name,
+        });
+    }
+    Err(anyhow!("More than {MAX_REDIRECTS} redirects").into())
+}
+
+/// GET `url` without following redirects, connecting only to a public address
+async fn request(url: &Url) -> Result<reqwest::Response, FetchError> {
+    if !matches!(url.scheme(), "http" | "https") {
+        return Err(FetchError::Scheme);
+    }
+    let port = url.port_or_known_default().context("URL without a port")?;
+
+    let mut client = Client::builder().redirect(Policy::none());
+    match url.host().context("URL without a host")? {
+        Host::Ipv4(ip) => check_public(ip.into())?,
+        Host::Ipv6(ip) => check_public(ip.into())?,
+        Host::Domain(domain) => {
+            let addr = tokio::net::lookup_host((domain, port))
+                .await
+                .with_context(|| format!("Failed to resolve {domain}"))?
+                .next()
+                .with_context(|| format!("{domain} has no addresses"))?;
+            check_public(addr.ip())?;
+            // Connect to the checked address rather than resolving again
+            client = client.resolve(domain, SocketAddr::new(addr.ip(), port));
+        }
+    }
+
+    let client = client.build().context("Failed to build HTTP client")?;
+    Ok(client
+        .get(url.clone())
+        .send()
+        .await
+        .context("Request failed")?)
+}
+
+fn check_public(ip: IpAddr) -> Result<(), FetchError> {
+    if is_public(ip) {
+        Ok(())
+    } else {
+        Err(FetchError::Forbidden)
+    }
+}
+
+fn is_public(ip: IpAddr) -> bool {
+    match ip {
+        IpAddr::V4(ip) => {
+            let [a, b, ..] = ip.octets();
+            !(ip.is_private()
+                || ip.is_loopback()
+                || ip.is_link_local()
+                || ip.is_unspecified()
+                || ip.is_broadcast()
+                || ip.is_documentation()
+                // Carrier-grade NAT, 100.64.0.0/10
+                || (a == 100 && (b & 0xc0) == 64))
+        }
+        IpAddr::V6(ip) => match ip.to_ipv4_mapped() {
+            Some(ip) => is_public(ip.into()),
+            None => {
+                let first = ip.segments()[0];
+                !(ip.is_loopback()
+                    || ip.is_unspecified()
+                    // Unique local, fc00::/7
+                    || (first & 0xfe00) == 0xfc00
+                    // Link local, fe80::/10
+                    || (first & 0xffc0) == 0xfe80)
+            }
+        },
+    }
+}
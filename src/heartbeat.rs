@@ -0,0 +1,99 @@
+//! Tracking of heartbeats that workers publish while they're consuming jobs
+
+use std::{
+    sync::{Arc, Mutex},
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+
+use anyhow::Result;
+use futures_lite::stream::StreamExt;
+use tracing::{info, warn};
+
+use crate::{
+    amqp::{AmqpConnection, RECONNECT_INITIAL_BACKOFF, RECONNECT_MAX_BACKOFF},
+    metrics::Metrics,
+};
+
+pub struct WorkerHeartbeat {
+    timeout: Duration,
+    /// Starts at startup so workers get one `timeout` to check in after a restart
+    last_seen: Mutex<Instant>,
+}
+
+impl WorkerHeartbeat {
+    pub fn new(timeout: Duration) -> Self {
+        Self {
+            timeout,
+            last_seen: Mutex::new(Instant::now()),
+        }
+    }
+
+    fn record(&self) {
+        *self.last_seen.lock().unwrap() = Instant::now();
+    }
+
+    /// Time since a worker last checked in, or since startup if none has yet
+    pub fn since_last_seen(&self) -> Duration {
+        self.last_seen.lock().unwrap().elapsed()
+    }
+
+    /// Whether a worker has checked in within the timeout
+    pub fn is_alive(&self) -> bool {
+        self.since_last_seen() <= self.timeout
+    }
+}
+
+/// Record every heartbeat the workers publish to `queue`, the contents are ignored.
+/// Reconnects and re-subscribes with exponential backoff until `amqp_conn` is closed on shutdown.
+pub async fn listen(
+    amqp_conn: Arc<AmqpConnection>,
+    queue: String,
+    heartbeat: Arc<WorkerHeartbeat>,
+    metrics: Arc<Metrics>,
+) {
+    let mut backoff = RECONNECT_INITIAL_BACKOFF;
+    loop {
+        let consumed = consume(&amqp_conn, &queue, &heartbeat, &metrics, &mut backoff).await;
+        if amqp_conn.is_closed() {
+            info!("AMQP connection closed, stop listening for heartbeats");
+            return;
+        }
+        match consumed {
+            Ok(()) => warn!("Heartbeat consumer stopped"),
+            Err(e) => warn!("Heartbeat consumer failed: {e:?}"),
+        }
+
+        warn!("Reconnecting to heartbeat queue in {backoff:?}");
+        tokio::time::sleep(backoff).await;
+        backoff = (backoff * 2).min(RECONNECT_MAX_BACKOFF);
+    }
+}
+
+/// Subscribe to `queue` and record heartbeats until the consumer ends.
+/// `backoff` is reset once the subscription succeeds.
+async fn consume(
+    amqp_conn: &AmqpConnection,
+    queue: &str,
+    heartbeat: &WorkerHeartbeat,
+    metrics: &Metrics,
+    backoff: &mut Duration,
+) -> Result<()> {
+    let channel = amqp_conn.get().await?.create_channel().await?;
+    let declared = channel
+        .queue_declare(queue, Default::default(), Default::default())
+        .await?;
+    info!("Declared queue {declared:?}");
+
+    let mut consumer = channel
+        .basic_consume(queue, "", Default::default(), Default::default())
+        .await?;
+    *backoff = RECONNECT_INITIAL_BACKOFF;
+    while let Some(delivery) = consumer.next().await {
+        let delivery = delivery?;
+        heartbeat.record();
+        let now = SystemTime::now().duration_since(UNIX_EPOCH)?;
+        metrics.worker_last_heartbeat.set(now.as_secs() as i64);
+        delivery.ack(Default::default()).await?;
+    }
+    Ok(())
+}
@@ -0,0 +1,93 @@
+//! Recent conversions of each chat, listed by /history
+
+use std::sync::Arc;
+
+use serde::{Deserialize, Serialize};
+use teloxide::{
+    dispatching::dialogue::{ErasedStorage, Storage},
+    types::ChatId,
+};
+use tokio::sync::Mutex;
+
+/// Conversions kept per chat, the oldest are dropped first
+pub const MAX_HISTORY_ENTRIES: usize = 10;
+
+type StorageError = Box<dyn std::error::Error + Send + Sync>;
+
+#[derive(Clone, Copy, Serialize, Deserialize)]
+pub enum Outcome {
+    Pending,
+    Succeeded,
+    Failed,
+}
+
+#[derive(Clone, Serialize, Deserialize)]
+pub struct HistoryEntry {
+    pub job_id: String,
+    /// Unix time in seconds
+    pub submitted_at: u64,
+    pub from_filetype: String,
+    pub to_filetype: String,
+    pub outcome: Outcome,
+}
+
+pub struct History {
+    /// Entries of each chat, oldest first, keyed by chat like the dialogues but stored apart from them
+    storage: Arc<ErasedStorage<Vec<HistoryEntry>>>,
+    /// Held across each read and write, so submissions and responses don't overwrite each other
+    lock: Mutex<()>,
+}
+
+impl History {
+    pub fn new(storage: Arc<ErasedStorage<Vec<HistoryEntry>>>) -> Self {
+        Self {
+            storage,
+            lock: Mutex::new(()),
+        }
+    }
+
+    /// Entries of `chat_id`, oldest first
+    pub async fn recent(&self, chat_id: ChatId) -> Result<Vec<HistoryEntry>, StorageError> {
+        let _guard = self.lock.lock().await;
+        Ok(self
+            .storage
+            .clone()
+            .get_dialogue(chat_id)
+            .await?
+            .unwrap_or_default())
+    }
+
+    pub async fn record(&self, chat_id: ChatId, entry: HistoryEntry) -> Result<(), StorageError> {
+        let _guard = self.lock.lock().await;
+        let mut entries = self
+            .storage
+            .clone()
+            .get_dialogue(chat_id)
+            .await?
+            .unwrap_or_default();
+        entries.push(entry);
+        if entries.len() > MAX_HISTORY_ENTRIES {
+            entries.drain(..entries.len() - MAX_HISTORY_ENTRIES);
+        }
+        self.storage.clone().update_dialogue(chat_id, entries).await
+    }
+
+    /// Set the outcome of the job `job_id`, if it's still among the chat's entries
+    pub async fn set_outcome(
+        &self,
+        chat_id: ChatId,
+        job_id: &str,
+        outcome: Outcome,
+    ) -> Result<(), StorageError> {
+        let _guard = self.lock.lock().await;
+        let mut entries = match self.storage.clone().get_dialogue(chat_id).await? {
+            Some(entries) => entries,
+            None => return Ok(()),
+        };
+        match entries.iter_mut().find(|entry| entry.job_id == job_id) {
+            Some(entry) => entry.outcome = outcome,
+            None => return Ok(()),
+        }
+        self.storage.clone().update_dialogue(chat_id, entries).await
+    }
+}
@@ -0,0 +1,133 @@
+//! Translations of the messages sent while choosing formats and delivering outputs
+
+/// One language's messages, the functions fill in the arguments
+pub struct Messages {
+    /// Language code that `messages` maps back to this catalog
+    pub code: &'static str,
+    pub start: &'static str,
+    pub ask_from_filetype: &'static str,
+    pub upload_received: &'static str,
+    pub from_filetype_set: fn(from_filetype: &str) -> String,
+    pub from_filetype_detected: fn(from_filetype: &str) -> String,
+    pub ask_to_filetypes: &'static str,
+    pub unsupported_conversion: fn(from_filetype: &str, to_filetype: &str) -> String,
+    pub to_filetypes_set: fn(to_filetypes: &str) -> String,
+    pub ask_pdf_engine: &'static str,
+    /// Announces the conversion of an album of `count` documents
+    pub converting_album: fn(count: usize) -> String,
+    /// Appended to `converting_album` while no worker is available
+    pub album_waiting_for_workers: &'static str,
+    pub converted: fn(to_filetype: &str) -> String,
+    /// Appended to the `converted` caption when pandoc warned
+    pub warnings: fn(count: usize) -> String,
+    pub output_unavailable: &'static str,
+    pub output_too_large: fn(size_mb: u64, limit_mb: u64) -> String,
+    pub output_link: fn(size_mb: u64, url: &str, hours: u64) -> String,
+    pub conversion_failed: fn(error_msg: &str) -> String,
+    pub timed_out: &'static str,
+    pub button_expired: &'static str,
+    pub validation_passed: &'static str,
+    pub validation_failed: fn(error_msg: &str) -> String,
+}
+
+pub static EN: Messages = Messages {
+    code: "en",
+    start: "Let's start! Tell me the type of the original document.",
+    ask_from_filetype: "Tell me the type of the original document.",
+    upload_received: "Got your file, it will be converted once you've picked the formats.",
+    from_filetype_set: |from_filetype| {
+        format!("The type of the original document is set to <b>{from_filetype}</b>.")
+    },
+    from_filetype_detected: |from_filetype| {
+        format!("Detected the type of the original document as <b>{from_filetype}</b>.")
+    },
+    ask_to_filetypes: "What formats do you want for the output? Tap Done when finished.",
+    unsupported_conversion: |from_filetype, to_filetype| {
+        format!("Can't convert {from_filetype} → {to_filetype} yet.")
+    },
+    to_filetypes_set: |to_filetypes| {
+        format!("The output formats are set to <b>{to_filetypes}</b>.")
+    },
+    ask_pdf_engine: "Which PDF engine should be used?",
+    converting_album: |count| format!("Converting {count} files..."),
+    album_waiting_for_workers: "No conversion workers are currently available, \
+                                they will run when one comes online.",
+    converted: |to_filetype| format!("Converted successfully to <b>{to_filetype}</b>!"),
+    warnings: |count| match count {
+        1 => "⚠ 1 warning".to_owned(),
+        count => format!("⚠ {count} warnings"),
+    },
+    output_unavailable: "Failed to retrieve the converted file.",
+    output_too_large: |size_mb, limit_mb| {
+        format!("Output too large to deliver ({size_mb} MB, limit {limit_mb} MB)")
+    },
+    output_link: |size_mb, url, hours| {
+        format!(
+            "The output is too large for Telegram ({size_mb} MB). \
+             Download it within {hours} hours from {url}"
+        )
+    },
+    conversion_failed: |error_msg| {
+        format!("Failed to perform the conversion:\n<pre>{error_msg}</pre>")
+    },
+    timed_out: "Conversion timed out, please try again",
+    button_expired: "This button has expired, send /start.",
+    validation_passed: "Validation passed.",
+    validation_failed: |error_msg| format!("Validation failed:\n<pre>{error_msg}</pre>"),
+};
+
+pub static DE: Messages = Messages {
+    code: "de",
+    start: "Los geht's! Welchen Typ hat das Originaldokument?",
+    ask_from_filetype: "Welchen Typ hat das Originaldokument?",
+    upload_received: "Datei erhalten, sie wird konvertiert, sobald du die Formate gewählt hast.",
+    from_filetype_set: |from_filetype| {
+        format!("Der Typ des Originaldokuments ist <b>{from_filetype}</b>.")
+    },
+    from_filetype_detected: |from_filetype| {
+        format!("Der Typ des Originaldokuments wurde als <b>{from_filetype}</b> erkannt.")
+    },
+    ask_to_filetypes:
+        "In welche Formate soll konvertiert werden? Tippe auf Done, wenn du fertig bist.",
+    unsupported_conversion: |from_filetype, to_filetype| {
+        format!("{from_filetype} → {to_filetype} wird noch nicht unterstützt.")
+    },
+    to_filetypes_set: |to_filetypes| format!("Die Ausgabeformate sind <b>{to_filetypes}</b>."),
+    ask_pdf_engine: "Welche PDF-Engine soll verwendet werden?",
+    converting_album: |count| format!("{count} Dateien werden konvertiert..."),
+    album_waiting_for_workers: "Derzeit ist kein Konvertierungsdienst verfügbar, \
+                                sie werden konvertiert, sobald einer verfügbar ist.",
+    converted: |to_filetype| format!("Erfolgreich in <b>{to_filetype}</b> konvertiert!"),
+    warnings: |count| match count {
+        1 => "⚠ 1 Warnung".to_owned(),
+        count => format!("⚠ {count} Warnungen"),
+    },
+    output_unavailable: "Die konvertierte Datei konnte nicht abgerufen werden.",
+    output_too_large: |size_mb, limit_mb| {
+        format!("Die Ausgabe ist zu groß zum Senden ({size_mb} MB, Limit {limit_mb} MB)")
+    },
+    output_link: |size_mb, url, hours| {
+        format!(
+            "Die Ausgabe ist zu groß für Telegram ({size_mb} MB). \
+             Lade sie innerhalb von {hours} Stunden herunter: {url}"
+        )
+    },
+    conversion_failed: |error_msg| {
+        format!("Die Konvertierung ist fehlgeschlagen:\n<pre>{error_msg}</pre>")
+    },
+    timed_out: "Die Konvertierung hat zu lange gedauert, bitte versuche es erneut",
+    button_expired: "Dieser Button ist abgelaufen, sende /start.",
+    validation_passed: "Validierung erfolgreich.",
+    validation_failed: |error_msg| format!("Validierung fehlgeschlagen:\n<pre>{error_msg}</pre>"),
+};
+
+/// Messages for a Telegram `language_code` such as `de` or `en-US`, English if unknown
+pub fn messages(language_code: Option<&str>) -> &'static Messages {
+    let language = language_code
+        .and_then(|code| code.split(['-', '_']).next())
+        .unwrap_or_default();
+    match language {
+        "de" => &DE,
+        _ => &EN,
+    }
+}
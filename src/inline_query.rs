@@ -0,0 +1,69 @@
+//! Inline mode: render short markdown snippets in place, without going through the worker
+
+use pulldown_cmark::{html, Event, Parser, Tag};
+use teloxide::{
+    prelude::*,
+    types::{
+        InlineQueryResult, InlineQueryResultArticle, InputMessageContent, InputMessageContentText,
+    },
+};
+
+use crate::HandlerResult;
+
+/// Longer queries are truncated so rendering stays fast
+const MAX_QUERY_LEN: usize = 256;
+
+pub async fn inline_query(bot: Bot, q: InlineQuery) -> HandlerResult {
+    let source = truncate(q.query.trim(), MAX_QUERY_LEN);
+    if source.is_empty() {
+        bot.answer_inline_query(q.id, Vec::<InlineQueryResult>::new())
+            .send()
+            .await?;
+        return Ok(());
+    }
+
+    let results = vec![
+        article("html", "HTML", markdown_to_html(source)),
+        article("plain", "Plain text", markdown_to_plain(source)),
+    ];
+    bot.answer_inline_query(q.id, results).send().await?;
+    Ok(())
+}
+
+fn article(id: &str, title: &str, text: String) -> InlineQueryResult {
+    let content = InputMessageContent::Text(InputMessageContentText::new(text.clone()));
+    InlineQueryResult::Article(InlineQueryResultArticle::new(id, title, content).description(text))
+}
+
+/// Cut `s` to at most `max_len` bytes without splitting a character
+fn truncate(s: &str, max_len: usize) -> &str {
+    if s.len() <= max_len {
+        return s;
+    }
+    let end = (0..=max_len)
+        .rev()
+        .find(|&i| s.is_char_boundary(i))
+        .unwrap_or(0);
+    &s[..end]
+}
+
+fn markdown_to_html(source: &str) -> String {
+    let mut output = String::new();
+    html::push_html(&mut output, Parser::new(source));
+    output.trim_end().to_owned()
+}
+
+fn markdown_to_plain(source: &str) -> String {
+    let mut output = String::new();
+    for event in Parser::new(source) {
+        match event {
+            Event::Text(text) | Event::Code(text) => output.push_str(&text),
+            Event::SoftBreak | Event::HardBreak => output.push('\n'),
+            Event::End(Tag::Paragraph | Tag::Heading(..) | Tag::Item | Tag::CodeBlock(_)) => {
+                output.push('\n')
+            }
+            _ => {}
+        }
+    }
+    output.trim_end().to_owned()
+}
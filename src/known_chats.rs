@@ -0,0 +1,63 @@
+//! Chats that have interacted with the bot, persisted so operators can reach them later
+
+use std::{
+    collections::HashSet,
+    fs::{self, OpenOptions},
+    io::Write,
+    path::PathBuf,
+    sync::Mutex,
+};
+
+use anyhow::{Context, Result};
+use tracing::warn;
+
+/// Chat ids stored one per line in an append-only file
+pub struct KnownChats {
+    path: PathBuf,
+    chats: Mutex<HashSet<i64>>,
+}
+
+impl KnownChats {
+    /// Load the chats recorded at `path`, which is created on the first insert if missing
+    pub fn load(path: PathBuf) -> Result<Self> {
+        let chats = match fs::read_to_string(&path) {
+            Ok(contents) => contents
+                .lines()
+                .filter(|line| !line.trim().is_empty())
+                .map(|line| line.trim().parse())
+                .collect::<Result<_, _>>()
+                .with_context(|| format!("Malformed known chats file {path:?}"))?,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => HashSet::new(),
+            Err(e) => return Err(e).with_context(|| format!("Failed to read {path:?}")),
+        };
+
+        Ok(Self {
+            path,
+            chats: Mutex::new(chats),
+        })
+    }
+
+    /// Record `chat_id`, only touching the file if it's new
+    pub fn insert(&self, chat_id: i64) {
+        let mut chats = self.chats.lock().unwrap();
+        if chats.contains(&chat_id) {
+            return;
+        }
+
+        let res = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.path)
+            .and_then(|mut file| writeln!(file, "{chat_id}"));
+        match res {
+            Ok(()) => {
+                chats.insert(chat_id);
+            }
+            Err(e) => warn!("Failed to record chat {chat_id} in {:?}: {e}", self.path),
+        }
+    }
+
+    pub fn all(&self) -> Vec<i64> {
+        self.chats.lock().unwrap().iter().copied().collect()
+    }
+}
@@ -0,0 +1,57 @@
+//! Delivered outputs kept for a while so they can be sent again, by chat for /resend
+//! or by job for the "Get as file" button under outputs shown as text
+
+use std::{
+    collections::HashMap,
+    hash::Hash,
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+/// A delivered output, as it was named when sent
+#[derive(Clone)]
+pub struct LastOutput {
+    pub file_name: String,
+    pub contents: Vec<u8>,
+}
+
+pub struct LastOutputs<K> {
+    max_outputs: usize,
+    ttl: Duration,
+    outputs: Mutex<HashMap<K, (LastOutput, Instant)>>,
+}
+
+impl<K: Clone + Eq + Hash> LastOutputs<K> {
+    /// At most `max_outputs` outputs, each kept for `ttl`
+    pub fn new(max_outputs: usize, ttl: Duration) -> Self {
+        Self {
+            max_outputs,
+            ttl,
+            outputs: Mutex::new(HashMap::new()),
+        }
+    }
+
+    pub fn get(&self, key: &K) -> Option<LastOutput> {
+        let outputs = self.outputs.lock().unwrap();
+        outputs
+            .get(key)
+            .filter(|(_, delivered_at)| delivered_at.elapsed() < self.ttl)
+            .map(|(output, _)| output.clone())
+    }
+
+    /// Remember `output` under `key`, forgetting the one delivered longest ago if too many are kept
+    pub fn insert(&self, key: K, output: LastOutput) {
+        let mut outputs = self.outputs.lock().unwrap();
+        outputs.retain(|_, (_, delivered_at)| delivered_at.elapsed() < self.ttl);
+        if outputs.len() >= self.max_outputs && !outputs.contains_key(&key) {
+            let oldest = outputs
+                .iter()
+                .min_by_key(|(_, (_, delivered_at))| *delivered_at)
+                .map(|(key, _)| key.clone());
+            if let Some(oldest) = oldest {
+                outputs.remove(&oldest);
+            }
+        }
+        outputs.insert(key, (output, Instant::now()));
+    }
+}
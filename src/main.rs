@@ -1,342 +1,5690 @@
-use std::{env, path::PathBuf, sync::Arc};
+mod albums;
+mod amqp;
+mod config;
+mod downloads;
+mod fetch;
+mod heartbeat;
+mod history;
+mod i18n;
+mod inline_query;
+mod known_chats;
+mod last_outputs;
+mod maintenance;
+mod metrics;
+mod output_cache;
+mod prefs;
+mod publisher;
+mod rate_limit;
+mod recent_uploads;
+mod reporting;
+mod webhook;
+
+use std::{
+    collections::HashMap,
+    io::{Cursor, Write},
+    path::{Component, Path, PathBuf},
+    sync::{Arc, Mutex},
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
 
 use anyhow::{Context, Result};
 use futures_lite::stream::StreamExt;
-use lapin::{options::BasicPublishOptions, BasicProperties};
-use log::info;
-use serde::{Deserialize, Serialize};
+use infer::MatcherType;
+use lapin::{
+    options::{BasicNackOptions, BasicQosOptions, QueueDeclareOptions},
+    protocol::{AMQPErrorKind, AMQPSoftError},
+    types::{AMQPValue, FieldTable},
+    Queue,
+};
+use serde::{de::IgnoredAny, Deserialize, Deserializer, Serialize, Serializer};
 use teloxide::{
     dispatching::{
-        dialogue::{self, serializer::Json, ErasedStorage, GetChatId, SqliteStorage, Storage},
+        dialogue::{
+            self, serializer::Json, ErasedStorage, GetChatId, InMemStorage, RedisStorage,
+            SqliteStorage, Storage,
+        },
         UpdateHandler,
     },
     net::Download,
     prelude::*,
-    types::{File as TgFile, InlineKeyboardButton, InlineKeyboardMarkup, InputFile, ParseMode},
+    requests::{Output, Request},
+    types::{
+        ChatAction, Document, File as TgFile, InlineKeyboardButton, InlineKeyboardMarkup,
+        InputFile, ParseMode, User,
+    },
+    utils::command::BotCommands,
+    RequestError,
+};
+use tokio::{
+    fs::File,
+    io::AsyncReadExt,
+    sync::{Semaphore, SemaphorePermit},
+};
+use tracing::{error, info, instrument, warn};
+use tracing_subscriber::EnvFilter;
+use url::Url;
+use uuid::Uuid;
+use zip::{write::FileOptions, ZipWriter};
+
+use crate::{
+    albums::Albums,
+    amqp::{AmqpConnection, RECONNECT_INITIAL_BACKOFF, RECONNECT_MAX_BACKOFF},
+    config::{BotMode, Config, DialogueStorage, QueueNames, BYTES_PER_MB},
+    downloads::Downloads,
+    fetch::FetchError,
+    heartbeat::WorkerHeartbeat,
+    history::{History, HistoryEntry, Outcome},
+    i18n::Messages,
+    known_chats::KnownChats,
+    last_outputs::{LastOutput, LastOutputs},
+    maintenance::MaintenanceMode,
+    metrics::Metrics,
+    output_cache::{CacheKey, OutputCache},
+    prefs::Prefs,
+    publisher::{is_retryable, AmqpPublisher, JobPublisher},
+    rate_limit::RateLimiter,
+    recent_uploads::RecentUploads,
 };
-use tokio::fs::File;
 
 type MyDialogue = Dialogue<State, ErasedStorage<State>>;
 type MyStorage = std::sync::Arc<ErasedStorage<State>>;
+/// The last document each chat uploaded, keyed by chat like the dialogues
+type LastInputStorage = Arc<ErasedStorage<StoredInput>>;
+/// The default options of each chat, keyed by chat like the dialogues
+type PrefsStorage = Arc<ErasedStorage<Prefs>>;
+/// The most recent failed conversions of each chat, oldest first
+type FailedJobsStorage = Arc<ErasedStorage<Vec<RetryableJob>>>;
 type HandlerResult = Result<(), Box<dyn std::error::Error + Send + Sync>>;
+/// Jobs still waiting for a response, keyed by `job_id`
+type PendingJobs = Arc<Mutex<HashMap<String, PendingJob>>>;
 
+/// `Serialize` and `Deserialize` are implemented below on top of the derived ones
 #[derive(Clone, Serialize, Deserialize)]
+#[serde(remote = "Self")]
 pub enum State {
     Start,
-    ReceiveFullName,
-    ReceiveAge {
-        full_name: String,
-    },
-    ReceiveLocation {
-        full_name: String,
-        age: u8,
+    ReceiveFromFiletype {
+        /// A document sent before its type was known, converted once the formats are picked
+        #[serde(default)]
+        upload: Option<PendingUpload>,
     },
-    ReceiveFromFiletype,
+    /// Like `ReceiveFromFiletype`, but for /validate
+    ReceiveValidationFiletype,
     ReceiveToFiletype {
         from_filetype: String,
+        /// Set by /again to convert a previous upload instead of asking for one
+        #[serde(default)]
+        reused_input: Option<StoredInput>,
+        /// Output formats toggled on so far
+        #[serde(default)]
+        to_filetypes: Vec<String>,
+    },
+    ReceivePdfEngine {
+        from_filetype: String,
+        to_filetypes: Vec<String>,
+        #[serde(default)]
+        reused_input: Option<StoredInput>,
+    },
+    /// Page size and margins, only asked for with the wkhtmltopdf engine
+    ReceivePageLayout {
+        from_filetype: String,
+        to_filetypes: Vec<String>,
+        options: JobOptions,
+        #[serde(default)]
+        reused_input: Option<StoredInput>,
+    },
+    ReceiveEpubCover {
+        from_filetype: String,
+        to_filetypes: Vec<String>,
+        options: JobOptions,
+        #[serde(default)]
+        reused_input: Option<StoredInput>,
+    },
+    ReceiveReferenceDoc {
+        from_filetype: String,
+        to_filetypes: Vec<String>,
+        options: JobOptions,
+        #[serde(default)]
+        reused_input: Option<StoredInput>,
+    },
+    /// Only asked for Markdown converted to PDF or DOCX
+    ReceiveBibliography {
+        from_filetype: String,
+        to_filetypes: Vec<String>,
+        options: JobOptions,
+        #[serde(default)]
+        reused_input: Option<StoredInput>,
+    },
+    ReceiveHighlightStyle {
+        from_filetype: String,
+        to_filetypes: Vec<String>,
+        options: JobOptions,
+        #[serde(default)]
+        reused_input: Option<StoredInput>,
+    },
+    /// Only asked for when reveal.js slides are among the outputs
+    ReceiveRevealjsTheme {
+        from_filetype: String,
+        to_filetypes: Vec<String>,
+        options: JobOptions,
+        #[serde(default)]
+        reused_input: Option<StoredInput>,
+    },
+    ReceiveMarkdownFlavor {
+        from_filetype: String,
+        to_filetypes: Vec<String>,
+        options: JobOptions,
+        #[serde(default)]
+        reused_input: Option<StoredInput>,
+    },
+    /// Pandoc extensions toggled on top of the chosen Markdown flavor
+    ReceiveMarkdownExtensions {
+        from_filetype: String,
+        to_filetypes: Vec<String>,
+        options: JobOptions,
+        #[serde(default)]
+        reused_input: Option<StoredInput>,
+    },
+    ReceiveMetadata {
+        from_filetype: String,
+        to_filetypes: Vec<String>,
+        options: JobOptions,
+        #[serde(default)]
+        reused_input: Option<StoredInput>,
     },
     ReceiveInputFile {
         from_filetype: String,
-        to_filetype: String,
+        to_filetypes: Vec<String>,
+        #[serde(default)]
+        options: JobOptions,
+    },
+    /// The chosen options summarized, waiting for Confirm or Cancel. Only with `CONFIRM_JOBS`.
+    ConfirmJob {
+        from_filetype: String,
+        to_filetypes: Vec<String>,
+        options: JobOptions,
+        #[serde(default)]
+        reused_input: Option<StoredInput>,
+    },
+    /// The uploaded document's extension doesn't match `input.from_filetype`, waiting for /yes
+    ConfirmMismatch {
+        input: StoredInput,
+        to_filetypes: Vec<String>,
+        options: JobOptions,
     },
 }
 
+/// Options chosen for a conversion before the input is sent, passed on to the worker
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct JobOptions {
+    /// Passed to pandoc as `--pdf-engine` when set
+    pdf_engine: Option<String>,
+    /// Precise pandoc reader to use instead of `from_filetype`, e.g. `gfm` for markdown,
+    /// followed by any extensions turned on or off like `gfm+footnotes-raw_html`
+    source_format_variant: Option<String>,
+    /// Passed to pandoc as `-M key=value` pairs
+    #[serde(default)]
+    metadata: HashMap<String, String>,
+    /// Only parse the input and report whether that worked, set by /validate
+    #[serde(default)]
+    dry_run: bool,
+    /// Passed to pandoc as `--toc` when set
+    #[serde(default)]
+    toc: bool,
+    /// Passed to pandoc as `-s` for HTML output when set, otherwise only the body is produced
+    #[serde(default = "default_standalone")]
+    standalone: bool,
+    /// Telegram file id of the EPUB cover, downloaded into `ConvertRequest::cover_image` on submission
+    #[serde(default)]
+    cover_file_id: Option<String>,
+    /// Telegram file id of the reference DOCX, downloaded into `ConvertRequest::reference_doc`
+    #[serde(default)]
+    reference_doc_file_id: Option<String>,
+    /// Telegram file id of the BibTeX file, downloaded into `ConvertRequest::bibliography`
+    #[serde(default)]
+    bibliography_file_id: Option<String>,
+    /// Passed to pandoc as `--highlight-style` when set, otherwise pandoc's default applies
+    #[serde(default)]
+    highlight_style: Option<String>,
+    /// `A4` or `Letter`, passed as the `papersize` variable. Only set for wkhtmltopdf.
+    #[serde(default)]
+    page_size: Option<String>,
+    /// Margin on every side in millimeters, passed as the `margin-*` variables. Only set for wkhtmltopdf.
+    #[serde(default)]
+    margin_mm: Option<u32>,
+    /// Passed as the `theme` variable for reveal.js output, otherwise reveal.js' default applies
+    #[serde(default)]
+    revealjs_theme: Option<String>,
+    /// Name for the delivered output instead of the source's, set with /name
+    #[serde(default)]
+    output_filename_stem: Option<String>,
+}
+
+impl Default for JobOptions {
+    fn default() -> Self {
+        Self {
+            pdf_engine: None,
+            source_format_variant: None,
+            metadata: HashMap::new(),
+            dry_run: false,
+            toc: false,
+            standalone: default_standalone(),
+            cover_file_id: None,
+            reference_doc_file_id: None,
+            bibliography_file_id: None,
+            highlight_style: None,
+            page_size: None,
+            margin_mm: None,
+            revealjs_theme: None,
+            output_filename_stem: None,
+        }
+    }
+}
+
+fn default_standalone() -> bool {
+    true
+}
+
+/// A document uploaded for conversion, kept so it can be converted again without re-uploading
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct StoredInput {
+    file_id: String,
+    file_size: u64,
+    original_filename: Option<String>,
+    from_filetype: String,
+}
+
+/// A document uploaded before the user told its type
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct PendingUpload {
+    file_id: String,
+    file_size: u64,
+    original_filename: Option<String>,
+}
+
+impl PendingUpload {
+    fn from_document(doc: &Document) -> Self {
+        Self {
+            file_id: doc.file_id.clone(),
+            file_size: doc.file_size.into(),
+            original_filename: doc.file_name.clone(),
+        }
+    }
+
+    fn with_filetype(self, from_filetype: String) -> StoredInput {
+        StoredInput {
+            file_id: self.file_id,
+            file_size: self.file_size,
+            original_filename: self.original_filename,
+            from_filetype,
+        }
+    }
+}
+
+impl From<StoredInput> for PendingUpload {
+    fn from(input: StoredInput) -> Self {
+        Self {
+            file_id: input.file_id,
+            file_size: input.file_size,
+            original_filename: input.original_filename,
+        }
+    }
+}
+
+impl Serialize for State {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        State::serialize(self, serializer)
+    }
+}
+
+impl<'de> Deserialize<'de> for State {
+    /// Stored states that no longer exist, such as the ones from the teloxide dialogue example,
+    /// are reset to `State::Start` rather than failing every update from their chat
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        #[derive(Deserialize)]
+        #[serde(untagged)]
+        enum StoredState {
+            #[serde(deserialize_with = "State::deserialize")]
+            Known(State),
+            LegacyInputFile {
+                #[serde(rename = "ReceiveInputFile")]
+                state: LegacyInputFile,
+            },
+            Unknown(IgnoredAny),
+        }
+
+        /// `ReceiveInputFile` from before several output formats could be picked,
+        /// when the PDF engine was kept outside of the options
+        #[derive(Deserialize)]
+        struct LegacyInputFile {
+            from_filetype: String,
+            to_filetype: String,
+            #[serde(default)]
+            pdf_engine: Option<String>,
+            #[serde(default)]
+            options: JobOptions,
+        }
+
+        match StoredState::deserialize(deserializer)? {
+            StoredState::Known(state) => Ok(state),
+            StoredState::LegacyInputFile { state } => Ok(State::ReceiveInputFile {
+                from_filetype: state.from_filetype,
+                to_filetypes: vec![state.to_filetype],
+                options: JobOptions {
+                    pdf_engine: state.pdf_engine.or(state.options.pdf_engine),
+                    ..state.options
+                },
+            }),
+            StoredState::Unknown(_) => {
+                warn!("Resetting unknown stored dialogue state");
+                Ok(State::Start)
+            }
+        }
+    }
+}
+
 impl Default for State {
     fn default() -> Self {
         Self::Start
     }
 }
 
+/// A job published to the worker
+#[derive(Clone)]
+struct PendingJob {
+    chat_id: i64,
+    published_at: Instant,
+    /// Language of the user who submitted it, for the messages delivering the output
+    messages: &'static Messages,
+    dry_run: bool,
+    /// Format of the input, to offer converting the output back
+    from_filetype: String,
+    /// Chosen with /name, the output is named after the input otherwise
+    output_filename_stem: Option<String>,
+    /// What's needed to submit the job again if it fails, `None` for text inputs
+    retry: Option<RetryableJob>,
+    /// Where to cache the output, `None` for dry runs
+    cache_key: Option<CacheKey>,
+}
+
+/// A `PendingJob` as saved across restarts
+#[derive(Serialize, Deserialize)]
+struct SavedPendingJob {
+    chat_id: i64,
+    /// Unix time in seconds
+    published_at: u64,
+    /// `Messages::code` of the job's language
+    language: String,
+    dry_run: bool,
+    from_filetype: String,
+    #[serde(default)]
+    output_filename_stem: Option<String>,
+    retry: Option<RetryableJob>,
+    #[serde(default)]
+    cache_key: Option<CacheKey>,
+}
+
+fn unix_now() -> Duration {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+}
+
+impl From<&PendingJob> for SavedPendingJob {
+    fn from(job: &PendingJob) -> Self {
+        Self {
+            chat_id: job.chat_id,
+            published_at: unix_now()
+                .saturating_sub(job.published_at.elapsed())
+                .as_secs(),
+            language: job.messages.code.to_owned(),
+            dry_run: job.dry_run,
+            from_filetype: job.from_filetype.clone(),
+            output_filename_stem: job.output_filename_stem.clone(),
+            retry: job.retry.clone(),
+            cache_key: job.cache_key,
+        }
+    }
+}
+
+impl SavedPendingJob {
+    /// Time since the job was published
+    fn age(&self) -> Duration {
+        unix_now().saturating_sub(Duration::from_secs(self.published_at))
+    }
+
+    fn into_pending_job(self) -> PendingJob {
+        PendingJob {
+            chat_id: self.chat_id,
+            published_at: Instant::now()
+                .checked_sub(self.age())
+                .unwrap_or_else(Instant::now),
+            messages: i18n::messages(Some(&self.language)),
+            dry_run: self.dry_run,
+            from_filetype: self.from_filetype,
+            output_filename_stem: self.output_filename_stem,
+            retry: self.retry,
+            cache_key: self.cache_key,
+        }
+    }
+}
+
+/// A conversion of an uploaded document to a single format, kept so /retry can resubmit it
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct RetryableJob {
+    input: StoredInput,
+    to_filetype: String,
+    options: JobOptions,
+}
+
+/// Number of failed conversions kept per chat for /retry
+const MAX_FAILED_JOBS: usize = 5;
+
+/// How long a submitted upload is remembered, so submitting it again is ignored
+const RECENT_UPLOAD_TTL: Duration = Duration::from_secs(10);
+
+/// How long to wait for the rest of an album after its first document
+const ALBUM_WAIT: Duration = Duration::from_secs(1);
+
+/// How long /resend can send the last output again
+const RESEND_TTL: Duration = Duration::from_secs(60 * 60);
+
+/// Chats whose last output is kept for /resend, bounding the memory it takes
+const MAX_RESENDABLE_OUTPUTS: usize = 100;
+
+#[derive(BotCommands, Clone)]
+#[command(rename = "lowercase", description = "These commands are supported:")]
+enum Command {
+    #[command(description = "start a new conversion.")]
+    Start,
+    #[command(description = "abort the current conversion.")]
+    Cancel,
+    #[command(description = "list the supported formats.")]
+    Formats,
+    #[command(description = "show which formats can be converted to which.")]
+    Matrix,
+    #[command(description = "skip an optional step.")]
+    Skip,
+    #[command(description = "confirm a question.")]
+    Yes,
+    #[command(description = "convert the last uploaded file to another format.")]
+    Again,
+    #[command(description = "show your conversions in progress.")]
+    Status,
+    #[command(description = "check that a document parses without converting it.")]
+    Validate,
+    #[command(
+        description = "show your default options, forget them with /prefs reset, \
+        or choose with /prefs download on|off|auto whether outputs are sent as plain downloads."
+    )]
+    Prefs(String),
+    #[command(description = "retry your last failed conversion, /retry list shows the others.")]
+    Retry(String),
+    #[command(description = "name the output file while choosing the options, e.g. /name report.")]
+    Name(String),
+    #[command(description = "send feedback or a bug report to the maintainers.")]
+    Feedback(String),
+    #[command(description = "send your last converted file again.")]
+    Resend,
+    #[command(description = "list your recent conversions.")]
+    History,
+    /// Admin only, so it's left out of the command list
+    #[command(description = "off")]
+    Broadcast(String),
+    #[command(description = "off")]
+    Health,
+    #[command(description = "off")]
+    Maintenance(String),
+}
+
 #[tokio::main]
 async fn main() -> Result<()> {
-    pretty_env_logger::init();
+    tracing_subscriber::fmt()
+        .with_env_filter(EnvFilter::from_default_env())
+        .init();
+
+    let config = Arc::new(Config::from_env().context("Failed to load config")?);
+    // Held until the end of main, dropping it flushes the pending reports
+    let _reporting = config.sentry_dsn.clone().map(reporting::init);
 
     // Connect to queue
-    let amqp_addr = env::var("AMQP_ADDR").unwrap_or_else(|_| "amqp://127.0.0.1:5672".into());
-    let amqp_conn = lapin::Connection::connect(
-        &amqp_addr,
-        lapin::ConnectionProperties::default()
-            .with_executor(tokio_executor_trait::Tokio::current())
-            .with_reactor(tokio_reactor_trait::Tokio),
-    )
-    .await?;
-    let amqp_conn = Arc::new(amqp_conn);
+    let amqp_conn = Arc::new(AmqpConnection::connect(&config.amqp_addr).await?);
 
     info!("Connected to AMQP");
 
+    declare_job_queues(&*amqp_conn.get().await?, &config.queues)
+        .await
+        .context("Failed to declare job queues")?;
+
     // Setup bot
     info!("Starting dialogue bot ...");
 
     let bot = Bot::from_env();
 
-    let storage: MyStorage = SqliteStorage::open(
-        path_for_persistent_state()
-            .join("dialogue.sqlite3")
+    let storage = open_dialogue_storage(&config.dialogue_storage).await?;
+
+    let last_inputs: LastInputStorage = SqliteStorage::open(
+        config
+            .state_path
+            .join("last_inputs.sqlite3")
             .to_str()
             .context("Failed to convert state path to str")?,
         Json,
     )
     .await
-    .context("Failed to open SqliteStorage")?
+    .context("Failed to open SqliteStorage for last inputs")?
     .erase();
 
-    // Start the returning queue listener
-    let returning_queue_task = tokio::spawn(listen_returning_queue(bot.clone(), amqp_conn.clone()));
-
-    // Start the bot
-    Dispatcher::builder(bot, bot_scheme())
-        .dependencies(dptree::deps![storage, amqp_conn.clone()])
-        .build()
-        .setup_ctrlc_handler()
-        .dispatch()
-        .await;
-
-    // Gracefully shutdown returning queue task
-    amqp_conn.close(0, "").await?;
-    returning_queue_task.await??;
+    let prefs: PrefsStorage = SqliteStorage::open(
+        config
+            .state_path
+            .join("prefs.sqlite3")
+            .to_str()
+            .context("Failed to convert state path to str")?,
+        Json,
+    )
+    .await
+    .context("Failed to open SqliteStorage for prefs")?
+    .erase();
 
-    Ok(())
-}
+    let failed_jobs: FailedJobsStorage = SqliteStorage::open(
+        config
+            .state_path
+            .join("failed_jobs.sqlite3")
+            .to_str()
+            .context("Failed to convert state path to str")?,
+        Json,
+    )
+    .await
+    .context("Failed to open SqliteStorage for failed jobs")?
+    .erase();
 
-fn bot_scheme() -> UpdateHandler<Box<dyn std::error::Error + Send + Sync>> {
-    dialogue::enter::<Update, ErasedStorage<State>, State, _>()
-        .branch(
-            Update::filter_message()
-                .branch(dptree::case![State::Start].endpoint(start))
-                .branch(
-                    dptree::case![State::ReceiveInputFile {
-                        from_filetype,
-                        to_filetype
-                    }]
-                    .endpoint(receive_input_file),
-                ),
-        )
-        .branch(
-            Update::filter_callback_query()
-                .branch(dptree::case![State::ReceiveFromFiletype].endpoint(receive_from_filetype))
-                .branch(
-                    dptree::case![State::ReceiveToFiletype { from_filetype }]
-                        .endpoint(receive_to_filetype),
-                ),
+    let history = Arc::new(History::new(
+        SqliteStorage::open(
+            config
+                .state_path
+                .join("history.sqlite3")
+                .to_str()
+                .context("Failed to convert state path to str")?,
+            Json,
         )
-}
-
-/// Listen on the returning queue and return the results to bot users
-async fn listen_returning_queue(bot: Bot, amqp_conn: Arc<lapin::Connection>) -> Result<()> {
-    let channel = amqp_conn.create_channel().await?;
-    let queue = channel
-        .queue_declare("pandoc-outputs", Default::default(), Default::default())
-        .await?;
-    info!("Declared queue {queue:?}");
-    let mut consumer = channel
-        .basic_consume("pandoc-outputs", "", Default::default(), Default::default())
-        .await?;
-    while let Some(delivery) = consumer.next().await {
-        let delivery = delivery?;
-        let res: ConvertResponse = bson::from_slice(&delivery.data)?;
-
-        delivery.ack(Default::default()).await?;
-
-        match res {
-            ConvertResponse::Success {
-                chat_id,
-                file,
-                to_filetype,
-            } => {
-                info!("Received successful conversion");
-
-                let text = format!("Converted succesffully to <b>{to_filetype}</b>!");
-
-                let output_filename = format!("output.{}", filetype_to_extension(&to_filetype));
-                let document = InputFile::memory(file).file_name(output_filename);
-
-                bot.send_document(ChatId(chat_id), document)
-                    .caption(text)
-                    .parse_mode(ParseMode::Html)
-                    .send()
-                    .await?;
-            }
-            ConvertResponse::Failure { chat_id, error_msg } => {
-                info!("Received failed conversion");
+        .await
+        .context("Failed to open SqliteStorage for history")?
+        .erase(),
+    ));
 
-                bot.send_message(
-                    ChatId(chat_id),
-                    format!(
-                        "Failed to perform the conversion:\n<pre>{}</pre>",
-                        error_msg
-                    ),
-                )
-                .parse_mode(ParseMode::Html)
-                .send()
-                .await?;
-            }
-        }
+    let known_chats = Arc::new(
+        KnownChats::load(config.state_path.join("known_chats.txt"))
+            .context("Failed to load known chats")?,
+    );
 
-        info!("Got convert response from queue");
+    // Input files only outlive their submission if the bot went down in the middle of it
+    match reconcile_orphaned_inputs(&bot, &storage, &config.input_base_path).await {
+        Ok(count) => info!("Notified {count} chats of interrupted uploads"),
+        Err(e) => warn!("Failed to reconcile orphaned input files: {e:?}"),
     }
-    Ok(())
-}
-
-/* Bot handlers */
-
-async fn start(bot: Bot, msg: Message, dialogue: MyDialogue) -> HandlerResult {
-    let keyboard = make_from_keyboard();
-    bot.send_message(
-        msg.chat.id,
-        "Let's start! Tell me the type of the original document.",
-    )
-    .reply_markup(keyboard)
-    .send()
-    .await?;
-
-    dialogue.update(State::ReceiveFromFiletype).await?;
-    Ok(())
-}
 
-async fn receive_from_filetype(bot: Bot, q: CallbackQuery, dialogue: MyDialogue) -> HandlerResult {
-    bot.answer_callback_query(q.id.clone()).send().await?;
-    let chat_id = q.chat_id().context("No chat id found")?;
+    // Start the metrics server if requested
+    let metrics = Arc::new(Metrics::new()?);
+    if let Some(addr) = config.metrics_addr {
+        tokio::spawn(metrics::serve(addr, metrics.clone()));
+    }
 
-    let make_fail_msg = || {
-        let keyboard = make_from_keyboard();
-        bot.send_message(chat_id, "Tell me the type of the original document.")
-            .reply_markup(keyboard)
+    // Start serving large outputs if requested
+    let downloads = match &config.download_links {
+        Some(download_links) => {
+            let downloads = Arc::new(
+                Downloads::new(download_links)
+                    .await
+                    .context("Failed to set up download links")?,
+            );
+            tokio::spawn(downloads::serve(
+                download_links.bind_addr,
+                downloads.clone(),
+            ));
+            tokio::spawn(downloads::remove_expired(downloads.clone()));
+            Some(downloads)
+        }
+        None => None,
     };
 
-    let make_success_msg = |from_filetype| {
-        let keyboard = make_to_keyboard();
+    // Pick up the jobs published before a restart, so their responses still reach their users
+    let pending_jobs_path = config.state_path.join("pending_jobs.json");
+    let pending_jobs: PendingJobs = Arc::new(Mutex::new(
+        load_pending_jobs(&pending_jobs_path, config.job_timeout)
+            .context("Failed to load pending jobs")?,
+    ));
+    tokio::spawn(persist_pending_jobs(
+        pending_jobs.clone(),
+        pending_jobs_path.clone(),
+    ));
 
-        let text = format!(
-            "The type of the original document is set to <b>{}</b>. \
-             What format do you want for the output?",
-            from_filetype
-        );
-        bot.send_message(chat_id, text)
-            .parse_mode(ParseMode::Html)
-            .reply_markup(keyboard)
-    };
+    let output_cache = Arc::new(OutputCache::new(
+        config.output_cache_entries,
+        config.output_cache_ttl,
+    ));
 
-    remove_keyboard_from(&bot, &q).await?;
+    let last_outputs: Arc<LastOutputs<i64>> =
+        Arc::new(LastOutputs::new(MAX_RESENDABLE_OUTPUTS, RESEND_TTL));
+    let text_outputs: Arc<LastOutputs<String>> =
+        Arc::new(LastOutputs::new(MAX_RESENDABLE_OUTPUTS, RESEND_TTL));
 
-    if let Some(from_filetype) = q.data {
-        if FROM_FILETYPES.contains(&from_filetype.as_str()) {
-            let next_state = State::ReceiveToFiletype {
-                from_filetype: from_filetype.clone(),
-            };
+    // Start the returning queue listener
+    let delivery = Arc::new(Delivery {
+        bot: bot.clone(),
+        pending_jobs: pending_jobs.clone(),
+        failed_jobs: failed_jobs.clone(),
+        prefs: prefs.clone(),
+        history: history.clone(),
+        metrics: metrics.clone(),
+        downloads,
+        output_cache: output_cache.clone(),
+        last_outputs: last_outputs.clone(),
+        text_outputs: text_outputs.clone(),
+        config: config.clone(),
+    });
+    let returning_queue_task = tokio::spawn(listen_returning_queue(amqp_conn.clone(), delivery));
 
-            make_success_msg(&from_filetype).send().await?;
-            dialogue.update(next_state).await?;
-        } else {
-            make_fail_msg().send().await?;
-        }
-    } else {
-        make_fail_msg().send().await?;
+    // Start logging jobs that were dead-lettered if requested
+    if config.log_dead_letters {
+        tokio::spawn(log_dead_letters(amqp_conn.clone(), config.clone()));
     }
 
-    Ok(())
-}
-
-async fn receive_to_filetype(
-    bot: Bot,
-    q: CallbackQuery,
-    dialogue: MyDialogue,
-    from_filetype: String,
-) -> HandlerResult {
-    bot.answer_callback_query(q.id.clone()).send().await?;
-    let chat_id = q.chat_id().context("No chat id found")?;
-
-    let make_fail_msg = || {
-        let keyboard = make_to_keyboard();
+    // Start tracking whether any worker is alive
+    let heartbeat = Arc::new(WorkerHeartbeat::new(config.worker_heartbeat_timeout));
+    tokio::spawn(heartbeat::listen(
+        amqp_conn.clone(),
+        config.queues.heartbeat.clone(),
+        heartbeat.clone(),
+        metrics.clone(),
+    ));
 
-        let text = format!("What format do you want for the output?");
-        bot.send_message(chat_id, text).reply_markup(keyboard)
-    };
+    // Start notifying users of jobs that never got a response
+    tokio::spawn(watch_job_timeouts(
+        bot.clone(),
+        pending_jobs.clone(),
+        history.clone(),
+        config.job_timeout,
+    ));
 
-    let make_success_msg = |from_filetype| {
-        let text = format!(
-            "The output format is set to <b>{}</b>. \
-             Now send me the file to be converted.",
-            from_filetype
-        );
-        bot.send_message(chat_id, text).parse_mode(ParseMode::Html)
-    };
+    // Show the chats with pending jobs that the bot is working on them
+    tokio::spawn(send_pending_chat_actions(bot.clone(), pending_jobs.clone()));
+    tokio::spawn(clean_up_inputs(config.clone()));
 
-    remove_keyboard_from(&bot, &q).await?;
+    let recent_uploads = Arc::new(RecentUploads::new(RECENT_UPLOAD_TTL));
+    let albums = Arc::new(Albums::default());
+    let maintenance_mode = Arc::new(MaintenanceMode::load(config.state_path.join("maintenance")));
+    if maintenance_mode.is_enabled() {
+        warn!("Starting in maintenance mode, new conversions are refused");
+    }
+    // Only /feedback takes a RateLimiter from the dependencies, the submitter owns its own
+    let feedback_limiter = Arc::new(RateLimiter::new(
+        MAX_FEEDBACK_PER_HOUR,
+        Duration::from_secs(60 * 60),
+    ));
 
-    if let Some(to_filetype) = q.data {
-        if TO_FILETYPES.contains(&to_filetype.as_str()) {
-            let next_state = State::ReceiveInputFile {
-                from_filetype,
-                to_filetype: to_filetype.clone(),
-            };
+    let submitter = Arc::new(JobSubmitter {
+        publisher: Arc::new(AmqpPublisher {
+            amqp_conn: amqp_conn.clone(),
+        }),
+        pending_jobs: pending_jobs.clone(),
+        metrics,
+        rate_limiter: RateLimiter::new(config.max_jobs_per_hour, Duration::from_secs(60 * 60)),
+        heartbeat,
+        last_inputs: last_inputs.clone(),
+        prefs: prefs.clone(),
+        download_permits: Semaphore::new(config.max_concurrent_downloads.max(1)),
+        maintenance_mode: maintenance_mode.clone(),
+        output_cache,
+        last_outputs: last_outputs.clone(),
+        history: history.clone(),
+        config: config.clone(),
+    });
 
-            make_success_msg(&to_filetype).send().await?;
-            dialogue.update(next_state).await?;
-        } else {
-            make_fail_msg().send().await?;
+    // Start the bot
+    let mut dispatcher = Dispatcher::builder(bot.clone(), bot_scheme())
+        .dependencies(dptree::deps![
+            storage,
+            last_inputs,
+            prefs,
+            failed_jobs,
+            submitter,
+            recent_uploads,
+            albums,
+            feedback_limiter,
+            maintenance_mode,
+            known_chats,
+            last_outputs,
+            text_outputs,
+            history,
+            amqp_conn.clone(),
+            config.clone()
+        ])
+        .error_handler(Arc::new(
+            |e: Box<dyn std::error::Error + Send + Sync>| async move {
+                error!("Error from an update handler: {e:?}");
+                reporting::report_error(e.as_ref(), None, None);
+            },
+        ))
+        .build();
+    dispatcher.setup_ctrlc_handler();
+    match &config.bot_mode {
+        BotMode::Polling => dispatcher.dispatch().await,
+        BotMode::Webhook { url, bind_addr } => {
+            let listener = webhook::listener(bot, url.clone(), *bind_addr)
+                .await
+                .context("Failed to set up webhook")?;
+            dispatcher
+                .dispatch_with_listener(
+                    listener,
+                    LoggingErrorHandler::with_custom_text("An error from the webhook listener"),
+                )
+                .await
         }
-    } else {
-        make_fail_msg().send().await?;
     }
 
+    // The dispatcher no longer takes updates, but the worker may still be converting
+    info!("Waiting up to {:?} for pending jobs", config.shutdown_grace);
+    let pending = wait_for_pending_jobs(&pending_jobs, config.shutdown_grace).await;
+    if pending > 0 {
+        warn!("Shutting down with {pending} jobs still pending");
+    }
+    if let Err(e) = save_pending_jobs(&pending_jobs, &pending_jobs_path).await {
+        warn!("Failed to save pending jobs: {e:?}");
+    }
+
+    // Gracefully shutdown returning queue task
+    amqp_conn.close().await?;
+    returning_queue_task.await??;
+
     Ok(())
 }
 
-#[derive(Serialize, Deserialize, Debug)]
-struct ConvertRequest {
-    chat_id: i64,
-    #[serde(with = "serde_bytes")]
-    file: Vec<u8>,
-    file_id: String,
-    from_filetype: String,
-    to_filetype: String,
+/// Open the dialogue storage selected with `$DIALOGUE_STORAGE`.
+/// Opening the Redis storage connects to it, so a wrong URL fails here rather than on the first update.
+async fn open_dialogue_storage(storage: &DialogueStorage) -> Result<MyStorage> {
+    Ok(match storage {
+        // sqlx, which backs `SqliteStorage`, opens databases in WAL mode with a busy timeout,
+        // so concurrent dialogue writes wait for each other instead of failing as locked
+        DialogueStorage::Sqlite { path } => SqliteStorage::open(
+            path.to_str()
+                .context("Failed to convert dialogue database path to str")?,
+            Json,
+        )
+        .await
+        .context("Failed to open SqliteStorage")?
+        .erase(),
+        DialogueStorage::InMemory => {
+            warn!("Dialogue states are kept in memory and lost on restart");
+            InMemStorage::new().erase()
+        }
+        DialogueStorage::Redis { url } => RedisStorage::open(url.as_str(), Json)
+            .await
+            .context("Failed to connect to the Redis dialogue storage at REDIS_URL")?
+            .erase(),
+    })
 }
 
-#[derive(Serialize, Deserialize, Debug)]
-#[serde(untagged)]
-enum ConvertResponse {
-    Success {
-        chat_id: i64,
+fn bot_scheme() -> UpdateHandler<Box<dyn std::error::Error + Send + Sync>> {
+    // Inline queries don't belong to any chat, so they can't enter the dialogue
+    let inline_query_handler = Update::filter_inline_query().endpoint(inline_query::inline_query);
+
+    let dialogue_handler = dialogue::enter::<Update, ErasedStorage<State>, State, _>()
+        .branch(
+            Update::filter_message()
+                .chain(dptree::inspect(
+                    |msg: Message, known_chats: Arc<KnownChats>| known_chats.insert(msg.chat.id.0),
+                ))
+                .branch(
+                    dptree::entry()
+                        .filter_command::<Command>()
+                        .branch(dptree::case![Command::Start].endpoint(welcome))
+                        .branch(dptree::case![Command::Cancel].endpoint(cancel))
+                        .branch(dptree::case![Command::Formats].endpoint(formats))
+                        .branch(dptree::case![Command::Matrix].endpoint(matrix))
+                        .branch(dptree::case![Command::Again].endpoint(again))
+                        .branch(dptree::case![Command::Status].endpoint(status))
+                        .branch(dptree::case![Command::Validate].endpoint(validate))
+                        .branch(dptree::case![Command::Prefs(arg)].endpoint(show_prefs))
+                        .branch(dptree::case![Command::Retry(arg)].endpoint(retry))
+                        .branch(dptree::case![Command::Feedback(text)].endpoint(feedback))
+                        .branch(dptree::case![Command::Resend].endpoint(resend))
+                        .branch(dptree::case![Command::History].endpoint(show_history))
+                        .branch(
+                            dptree::filter(is_admin_command)
+                                .branch(
+                                    dptree::filter(is_admin)
+                                        .branch(
+                                            dptree::case![Command::Broadcast(text)]
+                                                .endpoint(broadcast),
+                                        )
+                                        .branch(dptree::case![Command::Health].endpoint(health))
+                                        .branch(
+                                            dptree::case![Command::Maintenance(arg)]
+                                                .endpoint(maintenance),
+                                        ),
+                                )
+                                .endpoint(unknown_command),
+                        ),
+                )
+                .branch(
+                    dptree::case![State::Start]
+                        .branch(
+                            dptree::filter_map(detect_from_filetype).endpoint(start_with_filetype),
+                        )
+                        .branch(
+                            dptree::filter(|msg: Message| msg.document().is_some()).endpoint(start),
+                        )
+                        .endpoint(nudge_start),
+                )
+                .branch(
+                    dptree::case![State::ReceivePageLayout {
+                        from_filetype,
+                        to_filetypes,
+                        options,
+                        reused_input
+                    }]
+                    .branch(
+                        dptree::entry()
+                            .filter_command::<Command>()
+                            .branch(dptree::case![Command::Skip].endpoint(skip_page_layout)),
+                    )
+                    .branch(dptree::filter(is_input).endpoint(skip_to_input))
+                    .endpoint(nudge_page_layout),
+                )
+                .branch(
+                    dptree::case![State::ReceiveEpubCover {
+                        from_filetype,
+                        to_filetypes,
+                        options,
+                        reused_input
+                    }]
+                    .branch(
+                        dptree::entry()
+                            .filter_command::<Command>()
+                            .branch(dptree::case![Command::Skip].endpoint(skip_epub_cover)),
+                    )
+                    .branch(dptree::filter(is_pasted_input).endpoint(skip_to_input))
+                    .endpoint(receive_epub_cover),
+                )
+                .branch(
+                    dptree::case![State::ReceiveReferenceDoc {
+                        from_filetype,
+                        to_filetypes,
+                        options,
+                        reused_input
+                    }]
+                    .branch(
+                        dptree::entry()
+                            .filter_command::<Command>()
+                            .branch(dptree::case![Command::Skip].endpoint(skip_reference_doc)),
+                    )
+                    .branch(dptree::filter(is_pasted_input).endpoint(skip_to_input))
+                    .endpoint(receive_reference_doc),
+                )
+                .branch(
+                    dptree::case![State::ReceiveBibliography {
+                        from_filetype,
+                        to_filetypes,
+                        options,
+                        reused_input
+                    }]
+                    .branch(
+                        dptree::entry()
+                            .filter_command::<Command>()
+                            .branch(dptree::case![Command::Skip].endpoint(skip_bibliography)),
+                    )
+                    .branch(dptree::filter(is_pasted_input).endpoint(skip_to_input))
+                    .endpoint(receive_bibliography),
+                )
+                .branch(
+                    dptree::case![State::ReceiveHighlightStyle {
+                        from_filetype,
+                        to_filetypes,
+                        options,
+                        reused_input
+                    }]
+                    .branch(
+                        dptree::entry()
+                            .filter_command::<Command>()
+                            .branch(dptree::case![Command::Skip].endpoint(skip_highlight_style)),
+                    )
+                    .branch(dptree::filter(is_input).endpoint(skip_to_input))
+                    .endpoint(nudge_highlight_style),
+                )
+                .branch(
+                    dptree::case![State::ReceiveRevealjsTheme {
+                        from_filetype,
+                        to_filetypes,
+                        options,
+                        reused_input
+                    }]
+                    .branch(
+                        dptree::entry()
+                            .filter_command::<Command>()
+                            .branch(dptree::case![Command::Skip].endpoint(skip_revealjs_theme)),
+                    )
+                    .branch(dptree::filter(is_input).endpoint(skip_to_input))
+                    .endpoint(nudge_revealjs_theme),
+                )
+                .branch(
+                    dptree::case![State::ReceiveMarkdownFlavor {
+                        from_filetype,
+                        to_filetypes,
+                        options,
+                        reused_input
+                    }]
+                    .branch(
+                        dptree::entry()
+                            .filter_command::<Command>()
+                            .branch(dptree::case![Command::Skip].endpoint(skip_markdown_flavor)),
+                    )
+                    .branch(dptree::filter(is_input).endpoint(skip_to_input))
+                    .endpoint(nudge_markdown_flavor),
+                )
+                .branch(
+                    dptree::case![State::ReceiveMarkdownExtensions {
+                        from_filetype,
+                        to_filetypes,
+                        options,
+                        reused_input
+                    }]
+                    .branch(
+                        dptree::entry().filter_command::<Command>().branch(
+                            dptree::case![Command::Skip].endpoint(skip_markdown_extensions),
+                        ),
+                    )
+                    .branch(dptree::filter(is_input).endpoint(skip_to_input))
+                    .endpoint(nudge_markdown_extensions),
+                )
+                .branch(
+                    dptree::case![State::ReceiveMetadata {
+                        from_filetype,
+                        to_filetypes,
+                        options,
+                        reused_input
+                    }]
+                    .branch(
+                        dptree::entry()
+                            .filter_command::<Command>()
+                            .branch(dptree::case![Command::Skip].endpoint(skip_metadata))
+                            .branch(dptree::case![Command::Name(name)].endpoint(set_output_name)),
+                    )
+                    .branch(dptree::filter(is_document_or_link).endpoint(skip_to_input))
+                    .endpoint(receive_metadata),
+                )
+                .branch(
+                    dptree::case![State::ReceivePdfEngine {
+                        from_filetype,
+                        to_filetypes,
+                        reused_input
+                    }]
+                    .branch(dptree::filter(is_input).endpoint(skip_pdf_engine_to_input)),
+                )
+                .branch(
+                    dptree::case![State::ReceiveInputFile {
+                        from_filetype,
+                        to_filetypes,
+                        options
+                    }]
+                    .endpoint(receive_input_file),
+                )
+                .branch(
+                    dptree::case![State::ConfirmMismatch {
+                        input,
+                        to_filetypes,
+                        options
+                    }]
+                    .branch(
+                        dptree::entry()
+                            .filter_command::<Command>()
+                            .branch(dptree::case![Command::Yes].endpoint(confirm_mismatch)),
+                    )
+                    .endpoint(reconsider_mismatch),
+                )
+                .branch(
+                    dptree::case![State::ConfirmJob {
+                        from_filetype,
+                        to_filetypes,
+                        options,
+                        reused_input
+                    }]
+                    .endpoint(nudge_job_confirmation),
+                ),
+        )
+        .branch(
+            Update::filter_callback_query()
+                // Delivered outputs can be converted back from any state
+                .branch(dptree::filter_map(parse_swap_button).endpoint(swap_formats))
+                .branch(dptree::filter_map(parse_file_button).endpoint(send_text_output))
+                .branch(
+                    dptree::case![State::ReceiveFromFiletype { upload }]
+                        .endpoint(receive_from_filetype),
+                )
+                .branch(
+                    dptree::case![State::ReceiveValidationFiletype]
+                        .endpoint(receive_validation_filetype),
+                )
+                .branch(
+                    dptree::case![State::ReceiveToFiletype {
+                        from_filetype,
+                        reused_input,
+                        to_filetypes
+                    }]
+                    .endpoint(receive_to_filetype),
+                )
+                .branch(
+                    dptree::case![State::ReceivePdfEngine {
+                        from_filetype,
+                        to_filetypes,
+                        reused_input
+                    }]
+                    .endpoint(receive_pdf_engine),
+                )
+                .branch(
+                    dptree::case![State::ReceivePageLayout {
+                        from_filetype,
+                        to_filetypes,
+                        options,
+                        reused_input
+                    }]
+                    .endpoint(receive_page_layout),
+                )
+                .branch(
+                    dptree::case![State::ReceiveHighlightStyle {
+                        from_filetype,
+                        to_filetypes,
+                        options,
+                        reused_input
+                    }]
+                    .endpoint(receive_highlight_style),
+                )
+                .branch(
+                    dptree::case![State::ReceiveRevealjsTheme {
+                        from_filetype,
+                        to_filetypes,
+                        options,
+                        reused_input
+                    }]
+                    .endpoint(receive_revealjs_theme),
+                )
+                .branch(
+                    dptree::case![State::ReceiveMarkdownFlavor {
+                        from_filetype,
+                        to_filetypes,
+                        options,
+                        reused_input
+                    }]
+                    .endpoint(receive_markdown_flavor),
+                )
+                .branch(
+                    dptree::case![State::ReceiveMarkdownExtensions {
+                        from_filetype,
+                        to_filetypes,
+                        options,
+                        reused_input
+                    }]
+                    .endpoint(receive_markdown_extension),
+                )
+                .branch(
+                    dptree::case![State::ReceiveMetadata {
+                        from_filetype,
+                        to_filetypes,
+                        options,
+                        reused_input
+                    }]
+                    .endpoint(toggle_option),
+                )
+                .branch(
+                    dptree::case![State::ConfirmJob {
+                        from_filetype,
+                        to_filetypes,
+                        options,
+                        reused_input
+                    }]
+                    .endpoint(receive_job_confirmation),
+                )
+                // Buttons of earlier steps, so their spinner doesn't keep going
+                .endpoint(answer_stale_button),
+        );
+
+    dptree::entry()
+        .branch(inline_query_handler)
+        .branch(dialogue_handler)
+}
+
+/// Listen on the returning queue and return the results to bot users.
+/// Reconnects and re-subscribes with exponential backoff until `amqp_conn` is closed on shutdown.
+#[instrument(skip_all)]
+async fn listen_returning_queue(
+    amqp_conn: Arc<AmqpConnection>,
+    delivery: Arc<Delivery>,
+) -> Result<()> {
+    let mut backoff = RECONNECT_INITIAL_BACKOFF;
+    loop {
+        let consumed = match amqp_conn.get().await {
+            Ok(conn) => consume_returning_queue(&conn, &delivery, &mut backoff).await,
+            Err(e) => Err(e),
+        };
+        if amqp_conn.is_closed() {
+            info!("AMQP connection closed, stop listening on returning queue");
+            return Ok(());
+        }
+        match consumed {
+            Ok(()) => warn!("Returning queue consumer stopped"),
+            Err(e) => {
+                warn!("Returning queue consumer failed: {e:?}");
+                reporting::report_error(e.as_ref(), None, None);
+            }
+        }
+
+        warn!("Reconnecting to returning queue in {backoff:?}");
+        tokio::time::sleep(backoff).await;
+        backoff = (backoff * 2).min(RECONNECT_MAX_BACKOFF);
+    }
+}
+
+/// Subscribe to the returning queue and handle responses until the consumer ends.
+/// `backoff` is reset once the subscription succeeds.
+async fn consume_returning_queue(
+    amqp_conn: &lapin::Connection,
+    delivery: &Delivery,
+    backoff: &mut Duration,
+) -> Result<()> {
+    let config = &delivery.config;
+    let channel = amqp_conn.create_channel().await?;
+    let queue = channel
+        .queue_declare(
+            &config.queues.outputs,
+            Default::default(),
+            Default::default(),
+        )
+        .await?;
+    info!("Declared queue {queue:?}");
+    channel
+        .basic_qos(config.amqp_prefetch, BasicQosOptions::default())
+        .await?;
+    let mut consumer = channel
+        .basic_consume(
+            &config.queues.outputs,
+            "",
+            Default::default(),
+            Default::default(),
+        )
+        .await?;
+    *backoff = RECONNECT_INITIAL_BACKOFF;
+
+    handle_deliveries(
+        consumer,
+        delivery,
+        &delivery.metrics,
+        &delivery.pending_jobs,
+    )
+    .await
+}
+
+/// Handles responses, behind a trait so the consumer loop can be driven without Telegram
+#[async_trait::async_trait]
+trait ResponseHandler: Sync {
+    async fn handle_response(&self, res: ConvertResponse) -> Result<()>;
+}
+
+#[async_trait::async_trait]
+impl ResponseHandler for Delivery {
+    async fn handle_response(&self, res: ConvertResponse) -> Result<()> {
+        Delivery::handle_response(self, res).await
+    }
+}
+
+/// A message from the returning queue, behind a trait so the consumer loop can be driven without a broker
+#[async_trait::async_trait]
+trait ReturnedMessage: Send + Sync {
+    fn data(&self) -> &[u8];
+    async fn ack(&self) -> Result<()>;
+    async fn requeue(&self) -> Result<()>;
+}
+
+#[async_trait::async_trait]
+impl ReturnedMessage for lapin::message::Delivery {
+    fn data(&self) -> &[u8] {
+        &self.data
+    }
+
+    async fn ack(&self) -> Result<()> {
+        Ok(self.acker.ack(Default::default()).await?)
+    }
+
+    async fn requeue(&self) -> Result<()> {
+        Ok(self
+            .acker
+            .nack(BasicNackOptions {
+                requeue: true,
+                ..Default::default()
+            })
+            .await?)
+    }
+}
+
+/// Handle the messages of the returning queue until it ends.
+/// Malformed and undeliverable responses are acked and skipped; transient send errors requeue the
+/// response and end the loop, so it's delivered once the consumer reconnects.
+async fn handle_deliveries<S, M, E>(
+    mut messages: S,
+    handler: &dyn ResponseHandler,
+    metrics: &Metrics,
+    pending_jobs: &PendingJobs,
+) -> Result<()>
+where
+    S: futures_lite::stream::Stream<Item = Result<M, E>> + Unpin,
+    M: ReturnedMessage,
+    E: std::error::Error + Send + Sync + 'static,
+{
+    while let Some(message) = messages.next().await {
+        let message = message?;
+        let res = match decode_or_drop(message.data(), metrics) {
+            Some(res) => res,
+            None => {
+                message.ack().await?;
+                continue;
+            }
+        };
+
+        let (job_id, chat_id) = (res.job_id().to_owned(), res.chat_id());
+        match handler.handle_response(res).await {
+            Ok(()) => {}
+            Err(e) if is_transient_send_error(&e) => {
+                // Requeued to be delivered once the consumer reconnects
+                reporting::report_error(e.as_ref(), Some(chat_id), Some(&job_id));
+                message.requeue().await?;
+                return Err(e);
+            }
+            Err(e) => {
+                warn!("Dropping response to job {job_id}: {e:?}");
+                reporting::report_error(e.as_ref(), Some(chat_id), Some(&job_id));
+            }
+        }
+        pending_jobs.lock().unwrap().remove(&job_id);
+        message.ack().await?;
+    }
+    Ok(())
+}
+
+/// Turn a success without any output into a failure.
+/// Telegram rejects empty documents with a confusing error, so this is a failure of the worker.
+/// `shared_output_size` is the size of an external output, if it was resolved on the shared volume.
+fn fail_empty_output(res: ConvertResponse, shared_output_size: Option<u64>) -> ConvertResponse {
+    match res {
+        ConvertResponse::Success {
+            job_id,
+            chat_id,
+            output,
+            to_filetype,
+            extra_files,
+            ..
+        } if extra_files.is_empty()
+            && match &output {
+                JobFile::Inline { file } => file.is_empty(),
+                JobFile::External { .. } => shared_output_size == Some(0),
+            } =>
+        {
+            warn!("Job {job_id} in chat {chat_id} succeeded with an empty {to_filetype} output");
+            ConvertResponse::Failure {
+                job_id,
+                chat_id,
+                error_msg: "Conversion produced no output".to_owned(),
+            }
+        }
+        res => res,
+    }
+}
+
+/// Decode a response from the returning queue, or `None` if it's malformed and should be dropped.
+/// A corrupt message can't become valid on redelivery, so it's only logged and counted.
+fn decode_or_drop(data: &[u8], metrics: &Metrics) -> Option<ConvertResponse> {
+    match decode_response(data) {
+        Ok(res) => Some(res),
+        Err(e) => {
+            warn!("Dropping malformed response of {} bytes: {e}", data.len());
+            metrics.malformed_responses.inc();
+            None
+        }
+    }
+}
+
+/// Send `req`, waiting as long as Telegram asks whenever it's rate limited
+async fn send_retrying<R>(req: &R) -> Result<Output<R>, RequestError>
+where
+    R: Request<Err = RequestError>,
+{
+    loop {
+        match req.send_ref().await {
+            Err(RequestError::RetryAfter(secs)) => {
+                warn!("Rate limited by Telegram, retrying in {secs}s");
+                tokio::time::sleep(Duration::from_secs(secs as u64)).await;
+            }
+            res => return res,
+        }
+    }
+}
+
+/// Whether `e` is a failure to reach Telegram that may pass, unlike Telegram rejecting the request
+fn is_transient_send_error(e: &anyhow::Error) -> bool {
+    matches!(
+        e.downcast_ref::<RequestError>(),
+        Some(RequestError::Network(_) | RequestError::Io(_) | RequestError::RetryAfter(_))
+    )
+}
+
+/// Largest document bots may send
+const TELEGRAM_MAX_UPLOAD_BYTES: u64 = 50 * BYTES_PER_MB;
+/// Text outputs shown in the chat instead of sent as a file if they're short enough
+const INLINE_TEXT_FILETYPES: &[&str] = &["latex", "html", "markdown", "rst", "typst"];
+/// Longest text output shown in the chat, leaving room in the message for the caption
+const INLINE_TEXT_MAX_CHARS: usize = 3000;
+/// Longest caption of a document, counted after parsing the markup
+const TELEGRAM_MAX_CAPTION_CHARS: usize = 1024;
+/// Longest text message, counted after parsing the markup
+const TELEGRAM_MAX_MESSAGE_CHARS: usize = 4096;
+
+/// Shared state needed to deliver the responses of the worker
+struct Delivery {
+    bot: Bot,
+    pending_jobs: PendingJobs,
+    failed_jobs: FailedJobsStorage,
+    prefs: PrefsStorage,
+    history: Arc<History>,
+    metrics: Arc<Metrics>,
+    /// Serves outputs too large to send, if download links are set up
+    downloads: Option<Arc<Downloads>>,
+    output_cache: Arc<OutputCache>,
+    last_outputs: Arc<LastOutputs<i64>>,
+    text_outputs: Arc<LastOutputs<String>>,
+    config: Arc<Config>,
+}
+
+impl Delivery {
+    /// Update the history, which only informs /history, so failing to is just logged
+    async fn set_history_outcome(&self, chat_id: ChatId, job_id: &str, outcome: Outcome) {
+        if let Err(e) = self.history.set_outcome(chat_id, job_id, outcome).await {
+            warn!("Failed to record the outcome in the history: {e}");
+        }
+    }
+
+    /// Deliver the result of a conversion to the user who requested it.
+    /// Fails only before anything reached the user, messages following the output are just logged
+    /// if they can't be sent, so that a response retried after an error isn't delivered twice.
+    #[instrument(skip_all, fields(job_id = %res.job_id(), chat_id = res.chat_id()))]
+    async fn handle_response(&self, res: ConvertResponse) -> Result<()> {
+        let Self {
+            bot,
+            pending_jobs,
+            failed_jobs,
+            prefs,
+            metrics,
+            downloads,
+            output_cache,
+            last_outputs,
+            text_outputs,
+            config,
+            ..
+        } = self;
+        info!("Got convert response for job {}", res.job_id());
+        // The size of a single external output, read once for the empty check and the delivery
+        let mut shared_output_size = None;
+        if let ConvertResponse::Success {
+            output: JobFile::External { path },
+            extra_files,
+            ..
+        } = &res
+        {
+            if let Some(path) = extra_files
+                .is_empty()
+                .then(|| config.resolve_shared_file(path))
+                .flatten()
+            {
+                let size = tokio::fs::metadata(&path).await?.len();
+                if size == 0 {
+                    // It won't be delivered, but the worker handed it over all the same
+                    if let Err(e) = tokio::fs::remove_file(&path).await {
+                        warn!("Failed to remove shared output file {path:?}: {e}");
+                    }
+                }
+                shared_output_size = Some(size);
+            }
+        }
+        let res = fail_empty_output(res, shared_output_size);
+        // Jobs that timed out are no longer tracked, so their language is unknown
+        let mut messages = &i18n::EN;
+        let mut dry_run = false;
+        let mut from_filetype = None;
+        let mut output_filename_stem = None;
+        let mut retry = None;
+        let mut cache_key = None;
+        let mut latency = None;
+        // The job stays pending until the response is delivered, in case it's redelivered
+        let job = pending_jobs.lock().unwrap().get(res.job_id()).cloned();
+        if let Some(job) = job {
+            let elapsed = job.published_at.elapsed();
+            info!("Job {} took {:?}", res.job_id(), elapsed);
+            latency = Some(elapsed);
+            messages = job.messages;
+            dry_run = job.dry_run;
+            from_filetype = Some(job.from_filetype);
+            output_filename_stem = job.output_filename_stem;
+            retry = job.retry;
+            cache_key = job.cache_key;
+        }
+
+        // Counted once the response reached the user, so a retried one isn't counted twice
+        let record_delivery = |succeeded: bool, to_filetype: Option<&str>| {
+            if let Some(latency) = latency {
+                metrics.conversion_latency.observe(latency.as_secs_f64());
+                if let (true, Some(from_filetype), Some(to_filetype)) =
+                    (succeeded, &from_filetype, to_filetype)
+                {
+                    metrics
+                        .conversion_durations
+                        .record(from_filetype, to_filetype, latency);
+                }
+            }
+            if succeeded {
+                metrics.conversions_succeeded.inc();
+            } else {
+                metrics.conversions_failed.inc();
+            }
+        };
+
+        let outcome = match &res {
+            ConvertResponse::Success { .. } | ConvertResponse::Validated { .. } => {
+                Outcome::Succeeded
+            }
+            ConvertResponse::Failure { .. } => Outcome::Failed,
+        };
+        self.set_history_outcome(ChatId(res.chat_id()), res.job_id(), outcome)
+            .await;
+
+        match res {
+            ConvertResponse::Success {
+                job_id,
+                chat_id,
+                output,
+                to_filetype,
+                original_filename,
+                extra_files,
+                warnings,
+                ..
+            } => {
+                info!("Received successful conversion");
+
+                let mut text = (messages.converted)(&to_filetype);
+                if !warnings.is_empty() {
+                    text = format!("{text}\n{}", (messages.warnings)(warnings.len()));
+                }
+
+                let extension = output_extension(&to_filetype);
+                let name_output = |extension: &str| {
+                    delivered_filename(
+                        output_filename_stem.as_deref(),
+                        original_filename.as_deref(),
+                        extension,
+                    )
+                };
+                let main_filename = name_output(extension);
+                // A zip archive of several files can't be converted back
+                let swap_button = from_filetype
+                    .clone()
+                    .filter(|from_filetype| {
+                        extra_files.is_empty()
+                            && FROM_FILETYPES.contains(&to_filetype.as_str())
+                            && is_supported_conversion(&to_filetype, from_filetype)
+                    })
+                    .map(|from_filetype| {
+                        InlineKeyboardButton::callback(
+                            "Swap & convert back".to_owned(),
+                            format!("{SWAP_BUTTON_PREFIX}{to_filetype}:{from_filetype}"),
+                        )
+                    });
+                // Cached once delivered, like the metrics
+                let mut cached = None;
+                let (file_name, contents, size, shared_file_paths) = if extra_files.is_empty() {
+                    match output {
+                        JobFile::Inline { file } => {
+                            cached = cache_key.map(|cache_key| (cache_key, file.clone()));
+                            let size = file.len() as u64;
+                            (main_filename, OutputContents::Memory(file), size, vec![])
+                        }
+                        JobFile::External { path } => match config.resolve_shared_file(&path) {
+                            Some(path) => {
+                                let size = match shared_output_size {
+                                    Some(size) => size,
+                                    None => tokio::fs::metadata(&path).await?.len(),
+                                };
+                                (
+                                    main_filename,
+                                    OutputContents::File(path.clone()),
+                                    size,
+                                    vec![path],
+                                )
+                            }
+                            None => {
+                                warn!("Ignoring output outside of the shared volume: {path:?}");
+                                send_retrying(
+                                    &bot.send_message(ChatId(chat_id), messages.output_unavailable),
+                                )
+                                .await?;
+                                record_delivery(false, None);
+                                self.set_history_outcome(ChatId(chat_id), &job_id, Outcome::Failed)
+                                    .await;
+                                return Ok(());
+                            }
+                        },
+                    }
+                } else {
+                    let mut files = vec![(main_filename, output)];
+                    files.extend(
+                        extra_files
+                            .into_iter()
+                            .map(|extra| (extra.name, extra.file)),
+                    );
+                    match bundle_outputs(config, files).await {
+                        Ok((archive, shared_file_paths)) => {
+                            let archive_filename = name_output("zip");
+                            let size = archive.len() as u64;
+                            (
+                                archive_filename,
+                                OutputContents::Memory(archive),
+                                size,
+                                shared_file_paths,
+                            )
+                        }
+                        Err(e) => {
+                            warn!("Failed to bundle outputs: {e:?}");
+                            send_retrying(
+                                &bot.send_message(ChatId(chat_id), messages.output_unavailable),
+                            )
+                            .await?;
+                            record_delivery(false, None);
+                            self.set_history_outcome(ChatId(chat_id), &job_id, Outcome::Failed)
+                                .await;
+                            return Ok(());
+                        }
+                    }
+                };
+
+                let inline_text = match &contents {
+                    OutputContents::Memory(bytes)
+                        if INLINE_TEXT_FILETYPES.contains(&to_filetype.as_str()) =>
+                    {
+                        std::str::from_utf8(bytes)
+                            .ok()
+                            .filter(|output| output.chars().count() <= INLINE_TEXT_MAX_CHARS)
+                            .map(str::to_owned)
+                    }
+                    _ => None,
+                };
+
+                let size_mb = (size + BYTES_PER_MB - 1) / BYTES_PER_MB;
+                if let Some(output_text) = inline_text {
+                    // The file is kept for the button, and for /resend like documents
+                    let output = LastOutput {
+                        file_name,
+                        contents: output_text.clone().into_bytes(),
+                    };
+                    let get_file_button = InlineKeyboardButton::callback(
+                        "Get as file".to_owned(),
+                        format!("{FILE_BUTTON_PREFIX}{job_id}"),
+                    );
+                    send_retrying(
+                        &bot.send_message(
+                            ChatId(chat_id),
+                            format!("{text}\n<pre>{}</pre>", escape_html(&output_text)),
+                        )
+                        .parse_mode(ParseMode::Html)
+                        .reply_markup(InlineKeyboardMarkup::new([[get_file_button]])),
+                    )
+                    .await?;
+                    text_outputs.insert(job_id, output.clone());
+                    last_outputs.insert(chat_id, output);
+                } else if size > TELEGRAM_MAX_UPLOAD_BYTES {
+                    warn!("Output of {size} bytes is too large to send");
+                    let link = match downloads {
+                        Some(downloads) => {
+                            let link = match &contents {
+                                OutputContents::Memory(bytes) => {
+                                    downloads.add(&file_name, bytes).await
+                                }
+                                OutputContents::File(path) => {
+                                    downloads.add_file(&file_name, path).await
+                                }
+                            };
+                            link.map_err(|e| warn!("Failed to serve output for download: {e:?}"))
+                                .ok()
+                                .map(|url| (url, downloads.ttl()))
+                        }
+                        None => None,
+                    };
+                    let text = match link {
+                        Some((url, ttl)) => {
+                            let hours = (ttl.as_secs() + 60 * 60 - 1) / (60 * 60);
+                            (messages.output_link)(size_mb, url.as_str(), hours)
+                        }
+                        None => (messages.output_too_large)(
+                            size_mb,
+                            TELEGRAM_MAX_UPLOAD_BYTES / BYTES_PER_MB,
+                        ),
+                    };
+                    send_retrying(&bot.send_message(ChatId(chat_id), text)).await?;
+                } else {
+                    // Kept for /resend, shared outputs are removed once delivered
+                    let resendable = match &contents {
+                        OutputContents::Memory(bytes) => Some(bytes.clone()),
+                        OutputContents::File(path) => tokio::fs::read(path)
+                            .await
+                            .map_err(|e| warn!("Failed to keep output {path:?} for /resend: {e}"))
+                            .ok(),
+                    };
+                    let document = match contents {
+                        OutputContents::Memory(bytes) => InputFile::memory(bytes),
+                        OutputContents::File(path) => InputFile::file(path),
+                    };
+                    let chat_prefs = prefs
+                        .clone()
+                        .get_dialogue(ChatId(chat_id))
+                        .await
+                        .unwrap_or_else(|e| {
+                            warn!("Failed to load prefs, using the defaults: {e:?}");
+                            None
+                        })
+                        .unwrap_or_default();
+                    let mut req = bot
+                        .send_document(ChatId(chat_id), document.file_name(file_name.clone()))
+                        .caption(truncate_chars(&text, TELEGRAM_MAX_CAPTION_CHARS))
+                        .parse_mode(ParseMode::Html)
+                        .disable_content_type_detection(chat_prefs.forces_download(&to_filetype));
+                    if let Some(swap_button) = swap_button {
+                        req = req.reply_markup(InlineKeyboardMarkup::new([[swap_button]]));
+                    }
+                    send_retrying(&req).await?;
+                    if let Some(contents) = resendable {
+                        last_outputs.insert(
+                            chat_id,
+                            LastOutput {
+                                file_name,
+                                contents,
+                            },
+                        );
+                    }
+                }
+
+                record_delivery(true, Some(&to_filetype));
+                if let Some((cache_key, file)) = cached {
+                    output_cache.insert(cache_key, file);
+                }
+
+                if !warnings.is_empty() {
+                    let details = truncate_chars(&warnings.join("\n"), TELEGRAM_MAX_MESSAGE_CHARS);
+                    let sent = send_retrying(
+                        &bot.send_message(
+                            ChatId(chat_id),
+                            format!("<pre>{}</pre>", escape_html(&details)),
+                        )
+                        .parse_mode(ParseMode::Html),
+                    )
+                    .await;
+                    if let Err(e) = sent {
+                        warn!("Failed to send the warnings of the delivered output: {e}");
+                    }
+                }
+
+                // The worker hands shared outputs over to us, so they're ours to clean up
+                for path in shared_file_paths {
+                    if let Err(e) = tokio::fs::remove_file(&path).await {
+                        warn!("Failed to remove shared output file {path:?}: {e}");
+                    }
+                }
+            }
+            ConvertResponse::Failure {
+                chat_id, error_msg, ..
+            } => {
+                info!("Received failed conversion");
+
+                let failure_text = if dry_run {
+                    messages.validation_failed
+                } else {
+                    messages.conversion_failed
+                };
+                let sent = send_retrying(
+                    &bot.send_message(ChatId(chat_id), failure_text(&escape_html(&error_msg)))
+                        .parse_mode(ParseMode::Html),
+                )
+                .await;
+                if let Err(e) = sent {
+                    warn!("Failed to send failure message, sending it as plain text: {e}");
+                    send_retrying(&bot.send_message(
+                        ChatId(chat_id),
+                        plain_failure_text(failure_text, &error_msg),
+                    ))
+                    .await?;
+                }
+                record_delivery(false, None);
+
+                if let Some(retry) = retry.filter(|_| !dry_run) {
+                    if let Err(e) = remember_failed_job(failed_jobs, ChatId(chat_id), retry).await {
+                        warn!("Failed to remember the failed job for /retry: {e:?}");
+                    }
+                }
+            }
+            ConvertResponse::Validated { chat_id, .. } => {
+                info!("Received passed validation");
+
+                send_retrying(&bot.send_message(ChatId(chat_id), messages.validation_passed))
+                    .await?;
+                record_delivery(true, None);
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Keep `retry` among the most recent failed jobs of `chat_id` for /retry
+async fn remember_failed_job(
+    failed_jobs: &FailedJobsStorage,
+    chat_id: ChatId,
+    retry: RetryableJob,
+) -> Result<()> {
+    let mut failed = failed_jobs
+        .clone()
+        .get_dialogue(chat_id)
+        .await
+        .map_err(|e| anyhow::anyhow!(e))?
+        .unwrap_or_default();
+    failed.push(retry);
+    if failed.len() > MAX_FAILED_JOBS {
+        failed.drain(..failed.len() - MAX_FAILED_JOBS);
+    }
+    failed_jobs
+        .clone()
+        .update_dialogue(chat_id, failed)
+        .await
+        .map_err(|e| anyhow::anyhow!(e))
+}
+
+/// `text` cut to at most `max_chars` characters, ending with an ellipsis if anything was cut
+fn truncate_chars(text: &str, max_chars: usize) -> String {
+    if text.chars().count() <= max_chars {
+        return text.to_owned();
+    }
+    let mut truncated: String = text.chars().take(max_chars.saturating_sub(1)).collect();
+    truncated.push('…');
+    truncated
+}
+
+/// Escape `text` for messages sent with `ParseMode::Html`
+fn escape_html(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+}
+
+/// `failure_text` without its markup, for when Telegram rejects the HTML version
+fn plain_failure_text(failure_text: fn(&str) -> String, error_msg: &str) -> String {
+    const PLACEHOLDER: &str = "\u{0}";
+    failure_text(PLACEHOLDER)
+        .replace("<pre>", "")
+        .replace("</pre>", "")
+        .replace(PLACEHOLDER, error_msg)
+}
+
+/// A converted file ready to be delivered
+enum OutputContents {
+    Memory(Vec<u8>),
+    /// On the shared volume
+    File(PathBuf),
+}
+
+/// Zip `files` into an in-memory archive, returning it with the shared files that went into it
+async fn bundle_outputs(
+    config: &Config,
+    files: Vec<(String, JobFile)>,
+) -> Result<(Vec<u8>, Vec<PathBuf>)> {
+    let mut shared_file_paths = vec![];
+    let mut archive = ZipWriter::new(Cursor::new(vec![]));
+    for (name, file) in files {
+        // Keep entries from escaping the directory the archive is extracted into
+        let is_relative = Path::new(&name)
+            .components()
+            .all(|component| matches!(component, Component::Normal(_)));
+        if name.is_empty() || !is_relative {
+            anyhow::bail!("Invalid output file name {name:?}");
+        }
+
+        let contents = match file {
+            JobFile::Inline { file } => file,
+            JobFile::External { path } => {
+                let path = config
+                    .resolve_shared_file(&path)
+                    .with_context(|| format!("Output outside of the shared volume: {path:?}"))?;
+                let contents = tokio::fs::read(&path)
+                    .await
+                    .with_context(|| format!("Failed to read shared output file {path:?}"))?;
+                shared_file_paths.push(path);
+                contents
+            }
+        };
+
+        archive.start_file(name, FileOptions::default())?;
+        archive.write_all(&contents)?;
+    }
+    Ok((archive.finish()?.into_inner(), shared_file_paths))
+}
+
+/// Arguments the job queue is declared with.
+/// The worker has to declare the queue with the same arguments, or the broker rejects one of them.
+fn job_queue_args(queues: &QueueNames) -> FieldTable {
+    let mut args = FieldTable::default();
+    args.insert(
+        "x-dead-letter-exchange".into(),
+        AMQPValue::LongString("".into()),
+    );
+    args.insert(
+        "x-dead-letter-routing-key".into(),
+        AMQPValue::LongString(queues.dead_letters.as_str().into()),
+    );
+    args
+}
+
+/// Declare the job queues, and the dead-letter queue that expired or rejected jobs go to.
+/// A job queue that already exists without the dead-letter arguments is used as it is,
+/// since the broker refuses to change them, until an operator deletes it to have it recreated.
+async fn declare_job_queues(amqp_conn: &lapin::Connection, queues: &QueueNames) -> Result<()> {
+    let mut channel = amqp_conn.create_channel().await?;
+    let queue = channel
+        .queue_declare(&queues.dead_letters, Default::default(), Default::default())
+        .await?;
+    info!("Declared queue {queue:?}");
+    for job_queue in queues.jobs() {
+        let declared = channel
+            .queue_declare(job_queue, Default::default(), job_queue_args(queues))
+            .await;
+        let queue = match declared {
+            Ok(queue) => queue,
+            Err(e) if is_precondition_failed(&e) => {
+                error!(
+                    "Job queue {job_queue} exists without the dead-letter arguments, \
+                     so expired and rejected jobs are dropped instead of going to {}. \
+                     Delete it while no jobs are waiting to have it recreated: {e}",
+                    queues.dead_letters
+                );
+                // The refused declaration closed the channel
+                channel = amqp_conn.create_channel().await?;
+                channel
+                    .queue_declare(
+                        job_queue,
+                        QueueDeclareOptions {
+                            passive: true,
+                            ..Default::default()
+                        },
+                        Default::default(),
+                    )
+                    .await?
+            }
+            Err(e) => return Err(e.into()),
+        };
+        info!("Declared queue {queue:?}");
+    }
+    Ok(())
+}
+
+/// Whether the broker refused to declare a queue because it exists with other arguments
+fn is_precondition_failed(e: &lapin::Error) -> bool {
+    matches!(
+        e,
+        lapin::Error::ProtocolError(e)
+            if e.kind() == &AMQPErrorKind::Soft(AMQPSoftError::PRECONDITIONFAILED)
+    )
+}
+
+/// Declare the job queue `job_queue` again to check that the broker still accepts it
+async fn job_queue_status(
+    amqp_conn: &lapin::Connection,
+    queues: &QueueNames,
+    job_queue: &str,
+) -> Result<Queue> {
+    let channel = amqp_conn.create_channel().await?;
+    let queue = channel
+        .queue_declare(job_queue, Default::default(), job_queue_args(queues))
+        .await?;
+    channel.close(0, "").await?;
+    Ok(queue)
+}
+
+/// Log the dead-lettered jobs so operators can triage them.
+/// Reconnects and re-subscribes with exponential backoff until `amqp_conn` is closed on shutdown.
+async fn log_dead_letters(amqp_conn: Arc<AmqpConnection>, config: Arc<Config>) {
+    let mut backoff = RECONNECT_INITIAL_BACKOFF;
+    loop {
+        let consumed = consume_dead_letters(&amqp_conn, &config.queues, &mut backoff).await;
+        if amqp_conn.is_closed() {
+            info!("AMQP connection closed, stop logging dead-lettered jobs");
+            return;
+        }
+        match consumed {
+            Ok(()) => warn!("Dead-letter consumer stopped"),
+            Err(e) => warn!("Dead-letter consumer failed: {e:?}"),
+        }
+
+        warn!("Reconnecting to dead-letter queue in {backoff:?}");
+        tokio::time::sleep(backoff).await;
+        backoff = (backoff * 2).min(RECONNECT_MAX_BACKOFF);
+    }
+}
+
+/// Subscribe to the dead-letter queue and log its jobs until the consumer ends.
+/// `backoff` is reset once the subscription succeeds.
+async fn consume_dead_letters(
+    amqp_conn: &AmqpConnection,
+    queues: &QueueNames,
+    backoff: &mut Duration,
+) -> Result<()> {
+    let channel = amqp_conn.get().await?.create_channel().await?;
+    let mut consumer = channel
+        .basic_consume(
+            &queues.dead_letters,
+            "",
+            Default::default(),
+            Default::default(),
+        )
+        .await?;
+    *backoff = RECONNECT_INITIAL_BACKOFF;
+    while let Some(delivery) = consumer.next().await {
+        let delivery = delivery?;
+        match bson::from_slice::<ConvertRequest>(&delivery.data) {
+            Ok(req) => warn!(
+                "Dead-lettered job {} from chat {}: {} -> {}, file id {}",
+                req.job_id, req.chat_id, req.from_filetype, req.to_filetype, req.file_id
+            ),
+            Err(e) => warn!("Dead-lettered message that is not a job: {e}"),
+        }
+        delivery.ack(Default::default()).await?;
+    }
+    Ok(())
+}
+
+/// Load the pending jobs saved at `path`, leaving out the ones older than `timeout`
+/// since the worker's queue has expired them by now
+fn load_pending_jobs(path: &Path, timeout: Duration) -> Result<HashMap<String, PendingJob>> {
+    let contents = match std::fs::read(path) {
+        Ok(contents) => contents,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(HashMap::new()),
+        Err(e) => return Err(e).with_context(|| format!("Failed to read {path:?}")),
+    };
+    let saved: HashMap<String, SavedPendingJob> = serde_json::from_slice(&contents)
+        .with_context(|| format!("Malformed pending jobs file {path:?}"))?;
+
+    let total = saved.len();
+    let pending_jobs: HashMap<_, _> = saved
+        .into_iter()
+        .filter(|(_, job)| job.age() <= timeout)
+        .map(|(job_id, job)| (job_id, job.into_pending_job()))
+        .collect();
+    info!(
+        "Resumed {} pending jobs, pruned {} that timed out",
+        pending_jobs.len(),
+        total - pending_jobs.len()
+    );
+    Ok(pending_jobs)
+}
+
+/// Write the pending jobs to `path`, replacing the previous file in one step
+async fn save_pending_jobs(pending_jobs: &PendingJobs, path: &Path) -> Result<()> {
+    let contents = pending_jobs_json(pending_jobs)?;
+    write_pending_jobs(path, &contents).await
+}
+
+fn pending_jobs_json(pending_jobs: &PendingJobs) -> Result<Vec<u8>> {
+    let saved: HashMap<&str, SavedPendingJob> = pending_jobs
+        .lock()
+        .unwrap()
+        .iter()
+        .map(|(job_id, job)| (job_id.as_str(), job.into()))
+        .collect();
+    Ok(serde_json::to_vec(&saved)?)
+}
+
+async fn write_pending_jobs(path: &Path, contents: &[u8]) -> Result<()> {
+    let tmp_path = path.with_extension("json.tmp");
+    tokio::fs::write(&tmp_path, contents)
+        .await
+        .with_context(|| format!("Failed to write {tmp_path:?}"))?;
+    tokio::fs::rename(&tmp_path, path)
+        .await
+        .with_context(|| format!("Failed to replace {path:?}"))?;
+    Ok(())
+}
+
+/// Save the pending jobs to `path` every second when they changed
+async fn persist_pending_jobs(pending_jobs: PendingJobs, path: PathBuf) {
+    let mut last_saved = None;
+    let mut interval = tokio::time::interval(Duration::from_secs(1));
+    loop {
+        interval.tick().await;
+
+        let contents = match pending_jobs_json(&pending_jobs) {
+            Ok(contents) => contents,
+            Err(e) => {
+                warn!("Failed to serialize pending jobs: {e:?}");
+                continue;
+            }
+        };
+        if last_saved.as_ref() == Some(&contents) {
+            continue;
+        }
+        match write_pending_jobs(&path, &contents).await {
+            Ok(()) => last_saved = Some(contents),
+            Err(e) => warn!("Failed to save pending jobs: {e:?}"),
+        }
+    }
+}
+
+/// Wait until every pending job got a response or `grace` ran out.
+/// Returns the number of jobs still pending.
+async fn wait_for_pending_jobs(pending_jobs: &PendingJobs, grace: Duration) -> usize {
+    let deadline = Instant::now() + grace;
+    loop {
+        let pending = pending_jobs.lock().unwrap().len();
+        if pending == 0 || Instant::now() >= deadline {
+            return pending;
+        }
+        tokio::time::sleep(Duration::from_millis(500)).await;
+    }
+}
+
+/// Periodically notify users of pending jobs older than `timeout` and stop tracking them
+async fn watch_job_timeouts(
+    bot: Bot,
+    pending_jobs: PendingJobs,
+    history: Arc<History>,
+    timeout: Duration,
+) {
+    let mut interval = tokio::time::interval(Duration::from_secs(1));
+    loop {
+        interval.tick().await;
+
+        let timed_out = {
+            let mut pending_jobs = pending_jobs.lock().unwrap();
+            let timed_out: Vec<_> = pending_jobs
+                .iter()
+                .filter(|(_, job)| job.published_at.elapsed() > timeout)
+                .map(|(job_id, job)| (job_id.clone(), job.chat_id, job.messages))
+                .collect();
+            for (job_id, _, _) in &timed_out {
+                pending_jobs.remove(job_id);
+            }
+            timed_out
+        };
+
+        for (job_id, chat_id, messages) in timed_out {
+            info!("Job {job_id} in chat {chat_id} timed out");
+            if let Err(e) = history
+                .set_outcome(ChatId(chat_id), &job_id, Outcome::Failed)
+                .await
+            {
+                warn!("Failed to record the outcome in the history: {e}");
+            }
+
+            if let Err(e) = bot
+                .send_message(ChatId(chat_id), messages.timed_out)
+                .send()
+                .await
+            {
+                warn!("Failed to notify chat {chat_id} of timed out job: {e}");
+            }
+        }
+    }
+}
+
+/// Keep the "sending a file" indicator up in every chat with a pending job, or "typing" for dry runs.
+/// Telegram clears the indicator after 5 seconds, and it stops being renewed
+/// once the job leaves `pending_jobs` through a response or a timeout.
+async fn send_pending_chat_actions(bot: Bot, pending_jobs: PendingJobs) {
+    let mut interval = tokio::time::interval(Duration::from_secs(4));
+    loop {
+        interval.tick().await;
+
+        let mut actions: HashMap<i64, ChatAction> = HashMap::new();
+        for job in pending_jobs.lock().unwrap().values() {
+            let action = actions.entry(job.chat_id).or_insert(ChatAction::Typing);
+            if !job.dry_run {
+                *action = ChatAction::UploadDocument;
+            }
+        }
+
+        for (chat_id, action) in actions {
+            if let Err(e) = bot.send_chat_action(ChatId(chat_id), action).send().await {
+                warn!("Failed to send chat action to chat {chat_id}: {e}");
+            }
+        }
+    }
+}
+
+/* Bot handlers */
+
+/// Explain what the bot does before starting a conversion
+async fn welcome(
+    bot: Bot,
+    msg: Message,
+    dialogue: MyDialogue,
+    config: Arc<Config>,
+) -> HandlerResult {
+    let text = format!(
+        "Hi! I convert documents with Pandoc.\n\n\
+         Input formats: {}\n\
+         Output formats: {}",
+        FROM_FILETYPES.join(", "),
+        TO_FILETYPES.join(", "),
+    );
+    bot.send_message(msg.chat.id, text).send().await?;
+
+    start(bot, msg, dialogue, config).await
+}
+
+/// Point users who chat with the bot in `State::Start` to /start
+async fn nudge_start(bot: Bot, msg: Message) -> HandlerResult {
+    bot.send_message(msg.chat.id, "Send /start to begin a conversion.")
+        .send()
+        .await?;
+    Ok(())
+}
+
+async fn start(bot: Bot, msg: Message, dialogue: MyDialogue, config: Arc<Config>) -> HandlerResult {
+    let messages = user_messages(msg.from());
+    let upload = msg.document().map(PendingUpload::from_document);
+    if upload
+        .as_ref()
+        .map_or(false, |upload| is_empty_upload(upload.file_size))
+    {
+        bot.send_message(msg.chat.id, EMPTY_FILE_TEXT)
+            .send()
+            .await?;
+        return Ok(());
+    }
+
+    let text = match &upload {
+        Some(_) => format!(
+            "{} {}",
+            messages.upload_received, messages.ask_from_filetype
+        ),
+        None => messages.start.to_owned(),
+    };
+    let keyboard = make_from_keyboard(&config);
+    bot.send_message(msg.chat.id, text)
+        .reply_markup(keyboard)
+        .send()
+        .await?;
+
+    dialogue
+        .update(State::ReceiveFromFiletype { upload })
+        .await?;
+    Ok(())
+}
+
+/// Start a dry run that only checks whether the input parses
+async fn validate(
+    bot: Bot,
+    msg: Message,
+    dialogue: MyDialogue,
+    config: Arc<Config>,
+) -> HandlerResult {
+    bot.send_message(
+        msg.chat.id,
+        "Tell me the type of the document you want to validate.",
+    )
+    .reply_markup(make_from_keyboard(&config))
+    .send()
+    .await?;
+
+    dialogue.update(State::ReceiveValidationFiletype).await?;
+    Ok(())
+}
+
+async fn receive_validation_filetype(
+    bot: Bot,
+    q: CallbackQuery,
+    dialogue: MyDialogue,
+) -> HandlerResult {
+    let from_filetype = match q
+        .data
+        .clone()
+        .filter(|data| FROM_FILETYPES.contains(&data.as_str()))
+    {
+        Some(from_filetype) => from_filetype,
+        None => return answer_expired_button(&bot, &q, user_messages(Some(&q.from))).await,
+    };
+
+    bot.answer_callback_query(q.id.clone()).send().await?;
+    let chat_id = q.chat_id().context("No chat id found")?;
+    remove_keyboard_from(&bot, &q).await?;
+
+    bot.send_message(
+        chat_id,
+        format!("Send me the {from_filetype} document to validate, or paste its contents."),
+    )
+    .send()
+    .await?;
+    // The worker parses into pandoc's native format and throws the result away
+    dialogue
+        .update(State::ReceiveInputFile {
+            from_filetype,
+            to_filetypes: vec![VALIDATION_TO_FILETYPE.to_owned()],
+            options: JobOptions {
+                dry_run: true,
+                ..Default::default()
+            },
+        })
+        .await?;
+    Ok(())
+}
+
+const VALIDATION_TO_FILETYPE: &str = "native";
+
+/// List the supported formats without touching the dialogue
+async fn formats(bot: Bot, msg: Message) -> HandlerResult {
+    let list = |filetypes: &[&str]| {
+        filetypes
+            .iter()
+            .map(|filetype| format!("• {filetype}"))
+            .collect::<Vec<_>>()
+            .join("\n")
+    };
+    let text = format!(
+        "<b>Input formats</b>\n{}\n\n<b>Output formats</b>\n{}",
+        list(FROM_FILETYPES),
+        list(TO_FILETYPES),
+    );
+    bot.send_message(msg.chat.id, text)
+        .parse_mode(ParseMode::Html)
+        .send()
+        .await?;
+    Ok(())
+}
+
+/// Send a table of the supported conversions, one row per input format
+async fn matrix(bot: Bot, msg: Message) -> HandlerResult {
+    let row_header_width = FROM_FILETYPES
+        .iter()
+        .map(|from| from.len())
+        .max()
+        .unwrap_or(0);
+    let mut table = format!("{:row_header_width$}", "");
+    for to in TO_FILETYPES {
+        table.push_str(&format!(" {to}"));
+    }
+    for from in FROM_FILETYPES {
+        table.push_str(&format!("\n{from:row_header_width$}"));
+        for to in TO_FILETYPES {
+            let cell = if is_supported_conversion(from, to) {
+                "✓"
+            } else {
+                "·"
+            };
+            table.push_str(&format!(" {cell:^width$}", width = to.len()));
+        }
+    }
+    bot.send_message(
+        msg.chat.id,
+        format!("<b>Supported conversions</b> (input → output)\n<pre>{table}</pre>"),
+    )
+    .parse_mode(ParseMode::Html)
+    .send()
+    .await?;
+    Ok(())
+}
+
+/// Skip the source format keyboard when the uploaded document's extension tells us the type
+async fn start_with_filetype(
+    bot: Bot,
+    msg: Message,
+    dialogue: MyDialogue,
+    config: Arc<Config>,
+    from_filetype: String,
+) -> HandlerResult {
+    let messages = user_messages(msg.from());
+    let upload = msg.document().map(PendingUpload::from_document);
+    if upload
+        .as_ref()
+        .map_or(false, |upload| is_empty_upload(upload.file_size))
+    {
+        bot.send_message(msg.chat.id, EMPTY_FILE_TEXT)
+            .send()
+            .await?;
+        return Ok(());
+    }
+
+    let keyboard = make_to_keyboard(&config, &[]);
+    let text = format!(
+        "{} {} {}",
+        (messages.from_filetype_detected)(&from_filetype),
+        messages.upload_received,
+        messages.ask_to_filetypes
+    );
+    bot.send_message(msg.chat.id, text)
+        .parse_mode(ParseMode::Html)
+        .reply_markup(keyboard)
+        .send()
+        .await?;
+
+    dialogue
+        .update(State::ReceiveToFiletype {
+            reused_input: upload.map(|upload| upload.with_filetype(from_filetype.clone())),
+            from_filetype,
+            to_filetypes: vec![],
+        })
+        .await?;
+    Ok(())
+}
+
+/// Reset the dialogue from any state
+async fn cancel(bot: Bot, msg: Message, dialogue: MyDialogue) -> HandlerResult {
+    dialogue.update(State::Start).await?;
+    bot.send_message(msg.chat.id, "Cancelled, send /start to begin again.")
+        .send()
+        .await?;
+    Ok(())
+}
+
+/// Start over with the last uploaded document, skipping straight to the output format
+async fn again(
+    bot: Bot,
+    msg: Message,
+    dialogue: MyDialogue,
+    last_inputs: LastInputStorage,
+    config: Arc<Config>,
+) -> HandlerResult {
+    let input = match last_inputs.get_dialogue(msg.chat.id).await? {
+        Some(input) => input,
+        None => {
+            bot.send_message(msg.chat.id, "No previous file to reconvert.")
+                .send()
+                .await?;
+            return Ok(());
+        }
+    };
+
+    let keyboard = make_to_keyboard(&config, &[]);
+    let text = format!(
+        "Converting your last <b>{}</b> document again. \
+         What formats do you want for the output? Tap Done when finished.",
+        input.from_filetype
+    );
+    bot.send_message(msg.chat.id, text)
+        .parse_mode(ParseMode::Html)
+        .reply_markup(keyboard)
+        .send()
+        .await?;
+
+    dialogue
+        .update(State::ReceiveToFiletype {
+            from_filetype: input.from_filetype.clone(),
+            reused_input: Some(input),
+            to_filetypes: vec![],
+        })
+        .await?;
+    Ok(())
+}
+
+/// Show the chat's default options, or forget them if `arg` is `reset`
+async fn show_prefs(bot: Bot, msg: Message, prefs: PrefsStorage, arg: String) -> HandlerResult {
+    if let Some(choice) = arg.trim().strip_prefix("download") {
+        let force_download = match choice.trim() {
+            "on" => Some(true),
+            "off" => Some(false),
+            "auto" => None,
+            _ => {
+                bot.send_message(msg.chat.id, "Usage: /prefs download on|off|auto")
+                    .send()
+                    .await?;
+                return Ok(());
+            }
+        };
+        let mut saved = prefs
+            .clone()
+            .get_dialogue(msg.chat.id)
+            .await?
+            .unwrap_or_default();
+        saved.force_download = force_download;
+        prefs.update_dialogue(msg.chat.id, saved).await?;
+        let text = match force_download {
+            Some(true) => "Outputs will be sent as plain downloads.",
+            Some(false) => "Outputs will be sent so Telegram can preview them.",
+            None => "Outputs will be sent as plain downloads for HTML only.",
+        };
+        bot.send_message(msg.chat.id, text).send().await?;
+        return Ok(());
+    }
+    if arg.trim() == "reset" {
+        prefs.remove_dialogue(msg.chat.id).await?;
+        bot.send_message(msg.chat.id, "Your default options were reset.")
+            .send()
+            .await?;
+        return Ok(());
+    }
+
+    let saved = prefs.get_dialogue(msg.chat.id).await?.unwrap_or_default();
+    let text = if saved.is_empty() {
+        "No default options yet, they're saved from your next conversion.".to_owned()
+    } else {
+        let on_off = |value| if value { "on" } else { "off" };
+        let mut lines = vec!["Your default options:".to_owned()];
+        if let Some(pdf_engine) = &saved.pdf_engine {
+            lines.push(format!("PDF engine: <b>{pdf_engine}</b>"));
+        }
+        if let Some(toc) = saved.toc {
+            lines.push(format!("Include TOC: <b>{}</b>", on_off(toc)));
+        }
+        if let Some(standalone) = saved.standalone {
+            lines.push(format!("Standalone HTML: <b>{}</b>", on_off(standalone)));
+        }
+        if let Some(force_download) = saved.force_download {
+            lines.push(format!(
+                "Send as plain downloads: <b>{}</b>",
+                on_off(force_download)
+            ));
+        }
+        lines.push("Send /prefs reset to forget them.".to_owned());
+        lines.join("\n")
+    };
+    bot.send_message(msg.chat.id, text)
+        .parse_mode(ParseMode::Html)
+        .send()
+        .await?;
+    Ok(())
+}
+
+/// Submit one of the chat's recent failed conversions again, the most recent unless `arg` picks another.
+/// `/retry list` numbers them from the most recent.
+async fn retry(
+    bot: Bot,
+    msg: Message,
+    dialogue: MyDialogue,
+    failed_jobs: FailedJobsStorage,
+    submitter: Arc<JobSubmitter>,
+    arg: String,
+) -> HandlerResult {
+    let mut failed = failed_jobs
+        .clone()
+        .get_dialogue(msg.chat.id)
+        .await?
+        .unwrap_or_default();
+    if failed.is_empty() {
+        bot.send_message(msg.chat.id, "No failed conversions to retry.")
+            .send()
+            .await?;
+        return Ok(());
+    }
+
+    let arg = arg.trim();
+    if arg == "list" {
+        let mut lines = vec!["Your recent failed conversions:".to_owned()];
+        for (number, job) in failed.iter().rev().enumerate() {
+            lines.push(format!(
+                "{}. {} ({} → {})",
+                number + 1,
+                job.input.original_filename.as_deref().unwrap_or("document"),
+                job.input.from_filetype,
+                job.to_filetype,
+            ));
+        }
+        lines.push("Send /retry <number> to retry one of them.".to_owned());
+        bot.send_message(msg.chat.id, lines.join("\n"))
+            .send()
+            .await?;
+        return Ok(());
+    }
+
+    let number = if arg.is_empty() {
+        1
+    } else {
+        match arg.parse::<usize>() {
+            Ok(number) if (1..=failed.len()).contains(&number) => number,
+            _ => {
+                let text = format!(
+                    "Send /retry, /retry list or /retry <number> with a number from 1 to {}.",
+                    failed.len()
+                );
+                bot.send_message(msg.chat.id, text).send().await?;
+                return Ok(());
+            }
+        }
+    };
+    let job = failed.remove(failed.len() - number);
+    // A retry that fails again is recorded anew once its response arrives
+    failed_jobs
+        .clone()
+        .update_dialogue(msg.chat.id, failed)
+        .await?;
+
+    info!(
+        "Retrying conversion of document with id {} to {}",
+        job.input.file_id, job.to_filetype
+    );
+    submitter
+        .submit_document(
+            &ChatContext::of(&bot, &dialogue, &msg),
+            job.input,
+            vec![job.to_filetype],
+            job.options,
+        )
+        .await?;
+    Ok(())
+}
+
+/// Report how many of the chat's jobs haven't got a response yet, without touching the dialogue
+async fn status(bot: Bot, msg: Message, submitter: Arc<JobSubmitter>) -> HandlerResult {
+    let text = match submitter.jobs_in_progress(msg.chat.id.0) {
+        0 => "No active conversions.".to_owned(),
+        1 => "You have 1 conversion in progress.".to_owned(),
+        count => format!("You have {count} conversions in progress."),
+    };
+    bot.send_message(msg.chat.id, text).send().await?;
+    Ok(())
+}
+
+/// Whether the command is one of the admin only ones, which are hidden from everyone else
+fn is_admin_command(cmd: Command) -> bool {
+    matches!(
+        cmd,
+        Command::Broadcast(_) | Command::Health | Command::Maintenance(_)
+    )
+}
+
+/// Whether the message comes from one of the chats in `ADMIN_CHAT_IDS`
+fn is_admin(msg: Message, config: Arc<Config>) -> bool {
+    config.admin_chat_ids.contains(&msg.chat.id.0)
+}
+
+/// Answer admin commands from other chats as if they didn't exist
+async fn unknown_command(bot: Bot, msg: Message) -> HandlerResult {
+    bot.send_message(msg.chat.id, "Unknown command.")
+        .send()
+        .await?;
+    Ok(())
+}
+
+/// Report whether the AMQP connection, the job queue and the workers are up,
+/// for chats in `ADMIN_CHAT_IDS` only
+async fn health(
+    bot: Bot,
+    msg: Message,
+    submitter: Arc<JobSubmitter>,
+    amqp_conn: Arc<AmqpConnection>,
+    config: Arc<Config>,
+) -> HandlerResult {
+    let mark = |ok: bool| if ok { "✅" } else { "❌" };
+
+    let conn = amqp_conn.get().await;
+    let connected = matches!(&conn, Ok(conn) if conn.status().connected());
+    let mut queues = vec![];
+    for job_queue in config.queues.jobs() {
+        let status = match &conn {
+            Ok(conn) => job_queue_status(conn, &config.queues, job_queue).await,
+            Err(e) => Err(anyhow::anyhow!("{e}")),
+        };
+        queues.push(match status {
+            Ok(queue) => format!(
+                "{} {job_queue}: {} waiting, {} consumers",
+                mark(true),
+                queue.message_count(),
+                queue.consumer_count()
+            ),
+            Err(e) => format!("{} {job_queue}: {e}", mark(false)),
+        });
+    }
+    let queue = queues.join("\n");
+    let since_heartbeat = submitter.heartbeat.since_last_seen().as_secs();
+    let text = format!(
+        "{} AMQP connection\n{queue}\n{} Worker heartbeat: {since_heartbeat}s ago\n\
+         Pending jobs: {}",
+        mark(connected),
+        mark(submitter.heartbeat.is_alive()),
+        submitter.pending_jobs.lock().unwrap().len()
+    );
+    bot.send_message(msg.chat.id, text).send().await?;
+    Ok(())
+}
+
+/// Delay between broadcast messages, keeping well under Telegram's limit of 30 per second
+const BROADCAST_INTERVAL: Duration = Duration::from_millis(50);
+
+/// Send `text` to every known chat, for chats in `ADMIN_CHAT_IDS` only
+async fn broadcast(
+    bot: Bot,
+    msg: Message,
+    text: String,
+    known_chats: Arc<KnownChats>,
+) -> HandlerResult {
+    if text.trim().is_empty() {
+        bot.send_message(msg.chat.id, "Usage: /broadcast <message>")
+            .send()
+            .await?;
+        return Ok(());
+    }
+
+    let chats = known_chats.all();
+    let mut delivered = 0;
+    for &chat_id in &chats {
+        match bot.send_message(ChatId(chat_id), &text).send().await {
+            Ok(_) => delivered += 1,
+            Err(e) => warn!("Failed to deliver broadcast to chat {chat_id}: {e}"),
+        }
+        tokio::time::sleep(BROADCAST_INTERVAL).await;
+    }
+
+    info!(
+        "Delivered broadcast to {delivered} of {} chats",
+        chats.len()
+    );
+    bot.send_message(
+        msg.chat.id,
+        format!(
+            "Broadcast delivered to {delivered} of {} chats.",
+            chats.len()
+        ),
+    )
+    .send()
+    .await?;
+    Ok(())
+}
+
+const MAINTENANCE_TEXT: &str = "Bot is under maintenance, please try again shortly.";
+
+/// Turn maintenance mode on or off with /maintenance on|off, or show it without an argument
+async fn maintenance(
+    bot: Bot,
+    msg: Message,
+    arg: String,
+    maintenance_mode: Arc<MaintenanceMode>,
+) -> HandlerResult {
+    let enabled = match arg.trim() {
+        "on" => true,
+        "off" => false,
+        "" => {
+            let state = if maintenance_mode.is_enabled() {
+                "on"
+            } else {
+                "off"
+            };
+            bot.send_message(msg.chat.id, format!("Maintenance mode is {state}."))
+                .send()
+                .await?;
+            return Ok(());
+        }
+        _ => {
+            bot.send_message(msg.chat.id, "Usage: /maintenance on|off")
+                .send()
+                .await?;
+            return Ok(());
+        }
+    };
+    maintenance_mode.set(enabled)?;
+    info!(
+        "Maintenance mode turned {}",
+        if enabled { "on" } else { "off" }
+    );
+
+    let text = if enabled {
+        "Maintenance mode is on. New conversions are refused, queued ones are still delivered."
+    } else {
+        "Maintenance mode is off."
+    };
+    bot.send_message(msg.chat.id, text).send().await?;
+    Ok(())
+}
+
+/// Send the chat's last output again without converting anything, leaving the dialogue as it is
+async fn resend(bot: Bot, msg: Message, last_outputs: Arc<LastOutputs<i64>>) -> HandlerResult {
+    match last_outputs.get(&msg.chat.id.0) {
+        Some(output) => {
+            bot.send_document(
+                msg.chat.id,
+                InputFile::memory(output.contents).file_name(output.file_name),
+            )
+            .send()
+            .await?;
+        }
+        None => {
+            bot.send_message(msg.chat.id, "No recent output to resend.")
+                .send()
+                .await?;
+        }
+    }
+    Ok(())
+}
+
+/// List the chat's recent conversions, most recent first
+async fn show_history(
+    bot: Bot,
+    msg: Message,
+    history: Arc<History>,
+    config: Arc<Config>,
+) -> HandlerResult {
+    let entries = history.recent(msg.chat.id).await?;
+    if entries.is_empty() {
+        bot.send_message(msg.chat.id, "No conversions yet.")
+            .send()
+            .await?;
+        return Ok(());
+    }
+
+    let now = unix_now().as_secs();
+    let mut lines = vec!["Your recent conversions:".to_owned()];
+    for entry in entries.iter().rev() {
+        let age = Duration::from_secs(now.saturating_sub(entry.submitted_at));
+        let outcome = match entry.outcome {
+            Outcome::Succeeded => "✅",
+            Outcome::Failed => "❌",
+            // Its response never came, or came while the bot was down
+            Outcome::Pending if age > config.job_timeout => "⌛",
+            Outcome::Pending => "⏳",
+        };
+        lines.push(format!(
+            "{outcome} {} → {}, {} ago",
+            entry.from_filetype,
+            entry.to_filetype,
+            format_age(age)
+        ));
+    }
+    bot.send_message(msg.chat.id, lines.join("\n"))
+        .send()
+        .await?;
+    Ok(())
+}
+
+/// Round `age` to minutes, hours or days
+fn format_age(age: Duration) -> String {
+    let mins = age.as_secs() / 60;
+    if mins < 60 {
+        format!("{} min", mins.max(1))
+    } else if mins < 24 * 60 {
+        format!("{} h", mins / 60)
+    } else {
+        format!("{} d", mins / (24 * 60))
+    }
+}
+
+/// Messages each chat may send with /feedback per hour
+const MAX_FEEDBACK_PER_HOUR: usize = 3;
+
+/// Forward `text` to the admins, leaving the dialogue as it is
+async fn feedback(
+    bot: Bot,
+    msg: Message,
+    text: String,
+    feedback_limiter: Arc<RateLimiter>,
+    config: Arc<Config>,
+) -> HandlerResult {
+    let text = text.trim();
+    if text.is_empty() {
+        bot.send_message(msg.chat.id, "Usage: /feedback <message>")
+            .send()
+            .await?;
+        return Ok(());
+    }
+
+    if let Err(wait) = feedback_limiter.try_submit(msg.chat.id.0, 1) {
+        let minutes = (wait.as_secs() + 59) / 60;
+        bot.send_message(
+            msg.chat.id,
+            format!("You've sent a lot of feedback, try again in {minutes} minutes."),
+        )
+        .send()
+        .await?;
+        return Ok(());
+    }
+
+    let sender = match msg.from().and_then(|user| user.username.as_deref()) {
+        Some(username) => format!("@{username} in chat {}", msg.chat.id),
+        None => format!("chat {}", msg.chat.id),
+    };
+    let forwarded = format!("Feedback from {sender}:\n\n{text}");
+    let mut delivered = false;
+    for &admin_chat_id in &config.admin_chat_ids {
+        match bot
+            .send_message(ChatId(admin_chat_id), &forwarded)
+            .send()
+            .await
+        {
+            Ok(_) => delivered = true,
+            Err(e) => warn!("Failed to forward feedback to admin chat {admin_chat_id}: {e}"),
+        }
+    }
+
+    let reply = if delivered {
+        "Thanks, your feedback was sent."
+    } else {
+        warn!("Dropped feedback from {sender}, no admin chat received it");
+        "Sorry, feedback can't be delivered right now."
+    };
+    bot.send_message(msg.chat.id, reply).send().await?;
+    Ok(())
+}
+
+async fn receive_from_filetype(
+    bot: Bot,
+    q: CallbackQuery,
+    dialogue: MyDialogue,
+    config: Arc<Config>,
+    upload: Option<PendingUpload>,
+) -> HandlerResult {
+    let messages = user_messages(Some(&q.from));
+
+    let from_filetype = match q
+        .data
+        .clone()
+        .filter(|data| FROM_FILETYPES.contains(&data.as_str()))
+    {
+        Some(from_filetype) => from_filetype,
+        None => return answer_expired_button(&bot, &q, messages).await,
+    };
+
+    bot.answer_callback_query(q.id.clone()).send().await?;
+    let chat_id = q.chat_id().context("No chat id found")?;
+
+    let make_success_msg = |from_filetype: &str| {
+        let keyboard = make_to_keyboard(&config, &[]);
+
+        let text = format!(
+            "{} {}",
+            (messages.from_filetype_set)(from_filetype),
+            messages.ask_to_filetypes
+        );
+        bot.send_message(chat_id, text)
+            .parse_mode(ParseMode::Html)
+            .reply_markup(keyboard)
+    };
+
+    remove_keyboard_from(&bot, &q).await?;
+
+    make_success_msg(&from_filetype).send().await?;
+    dialogue
+        .update(State::ReceiveToFiletype {
+            reused_input: upload.map(|upload| upload.with_filetype(from_filetype.clone())),
+            from_filetype,
+            to_filetypes: vec![],
+        })
+        .await?;
+
+    Ok(())
+}
+
+/// Prefix of the callback data of the button converting an output back,
+/// followed by `<output filetype>:<input filetype>`
+const SWAP_BUTTON_PREFIX: &str = "__swap__:";
+
+/// The output and input filetypes of a tapped swap button
+fn parse_swap_button(q: CallbackQuery) -> Option<(String, String)> {
+    let (output_filetype, input_filetype) = q
+        .data?
+        .strip_prefix(SWAP_BUTTON_PREFIX)?
+        .split_once(':')
+        .map(|(output, input)| (output.to_owned(), input.to_owned()))?;
+    is_supported_conversion(&output_filetype, &input_filetype)
+        .then(|| (output_filetype, input_filetype))
+}
+
+/// Start converting the delivered output the button is attached to back into the input's format.
+/// The output is reused through its Telegram file id like with /again, so nothing is kept here.
+async fn swap_formats(
+    bot: Bot,
+    q: CallbackQuery,
+    dialogue: MyDialogue,
+    config: Arc<Config>,
+    (output_filetype, input_filetype): (String, String),
+) -> HandlerResult {
+    let messages = user_messages(Some(&q.from));
+    let doc = match q.message.as_ref().and_then(|message| message.document()) {
+        Some(doc) => doc,
+        None => return answer_expired_button(&bot, &q, messages).await,
+    };
+    bot.answer_callback_query(q.id.clone()).send().await?;
+    let chat_id = q.chat_id().context("No chat id found")?;
+
+    let input = PendingUpload::from_document(doc).with_filetype(output_filetype.clone());
+    let to_filetypes = vec![input_filetype];
+    let text = format!(
+        "{} {}",
+        (messages.from_filetype_set)(&output_filetype),
+        messages.ask_to_filetypes
+    );
+    bot.send_message(chat_id, text)
+        .parse_mode(ParseMode::Html)
+        .reply_markup(make_to_keyboard(&config, &to_filetypes))
+        .send()
+        .await?;
+
+    dialogue
+        .update(State::ReceiveToFiletype {
+            from_filetype: output_filetype,
+            reused_input: Some(input),
+            to_filetypes,
+        })
+        .await?;
+    Ok(())
+}
+
+/// Prefix of the callback data of the button under an output shown as text, followed by the job id
+const FILE_BUTTON_PREFIX: &str = "__file__:";
+
+/// The job id of a tapped "Get as file" button
+fn parse_file_button(q: CallbackQuery) -> Option<String> {
+    q.data?.strip_prefix(FILE_BUTTON_PREFIX).map(str::to_owned)
+}
+
+/// Send the output shown as text above the tapped button as a file
+async fn send_text_output(
+    bot: Bot,
+    q: CallbackQuery,
+    text_outputs: Arc<LastOutputs<String>>,
+    job_id: String,
+) -> HandlerResult {
+    let output = match text_outputs.get(&job_id) {
+        Some(output) => output,
+        None => return answer_expired_button(&bot, &q, user_messages(Some(&q.from))).await,
+    };
+    bot.answer_callback_query(q.id.clone()).send().await?;
+    let chat_id = q.chat_id().context("No chat id found")?;
+    bot.send_document(
+        chat_id,
+        InputFile::memory(output.contents).file_name(output.file_name),
+    )
+    .send()
+    .await?;
+    Ok(())
+}
+
+/// Tell the user that the tapped button belongs to an earlier step or conversion
+async fn answer_expired_button(bot: &Bot, q: &CallbackQuery, messages: &Messages) -> HandlerResult {
+    info!("Received expired callback data {:?}", q.data);
+    bot.answer_callback_query(q.id.clone())
+        .text(messages.button_expired)
+        .show_alert(true)
+        .send()
+        .await?;
+    Ok(())
+}
+
+/// Acknowledge a tap on a keyboard that doesn't belong to the current step
+async fn answer_stale_button(bot: Bot, q: CallbackQuery) -> HandlerResult {
+    answer_expired_button(&bot, &q, user_messages(Some(&q.from))).await
+}
+
+async fn receive_to_filetype(
+    bot: Bot,
+    q: CallbackQuery,
+    dialogue: MyDialogue,
+    config: Arc<Config>,
+    prefs: PrefsStorage,
+    (from_filetype, reused_input, mut to_filetypes): (String, Option<StoredInput>, Vec<String>),
+) -> HandlerResult {
+    let messages = user_messages(Some(&q.from));
+
+    let data = match q.data.clone().filter(|data| {
+        data == BACK_BUTTON_DATA
+            || data == DONE_BUTTON_DATA
+            || TO_FILETYPES.contains(&data.as_str())
+    }) {
+        Some(data) => data,
+        None => return answer_expired_button(&bot, &q, messages).await,
+    };
+
+    bot.answer_callback_query(q.id.clone()).send().await?;
+    let chat_id = q.chat_id().context("No chat id found")?;
+
+    let make_unsupported_msg = |from_filetype: &str, to_filetype: &str, to_filetypes: &[String]| {
+        let keyboard = make_to_keyboard(&config, to_filetypes);
+
+        let text = (messages.unsupported_conversion)(from_filetype, to_filetype);
+        bot.send_message(chat_id, text).reply_markup(keyboard)
+    };
+
+    let make_pdf_engine_msg = |to_filetypes: &[String], preferred: Option<&str>| {
+        let keyboard = make_pdf_engine_keyboard(preferred);
+        let text = format!(
+            "{} {}",
+            (messages.to_filetypes_set)(&to_filetypes.join(", ")),
+            messages.ask_pdf_engine
+        );
+        bot.send_message(chat_id, text)
+            .parse_mode(ParseMode::Html)
+            .reply_markup(keyboard)
+    };
+
+    let make_back_msg = || {
+        let keyboard = make_from_keyboard(&config);
+        bot.send_message(chat_id, messages.ask_from_filetype)
+            .reply_markup(keyboard)
+    };
+
+    if data == BACK_BUTTON_DATA {
+        remove_keyboard_from(&bot, &q).await?;
+        make_back_msg().send().await?;
+        // Keep the uploaded document, only its type is chosen again
+        dialogue
+            .update(State::ReceiveFromFiletype {
+                upload: reused_input.map(PendingUpload::from),
+            })
+            .await?;
+    } else if data == DONE_BUTTON_DATA {
+        if to_filetypes.is_empty() {
+            return Ok(());
+        }
+
+        remove_keyboard_from(&bot, &q).await?;
+        let options = prefs
+            .get_dialogue(chat_id)
+            .await?
+            .unwrap_or_default()
+            .initial_options(&to_filetypes);
+        if to_filetypes.iter().any(|to_filetype| to_filetype == "pdf") {
+            make_pdf_engine_msg(&to_filetypes, options.pdf_engine.as_deref())
+                .send()
+                .await?;
+            dialogue
+                .update(State::ReceivePdfEngine {
+                    from_filetype,
+                    to_filetypes,
+                    reused_input,
+                })
+                .await?;
+        } else {
+            let chosen = (messages.to_filetypes_set)(&to_filetypes.join(", "));
+            ask_for_options(
+                &bot,
+                chat_id,
+                &dialogue,
+                &chosen,
+                JobDraft {
+                    from_filetype,
+                    to_filetypes,
+                    options,
+                    reused_input,
+                },
+            )
+            .await?;
+        }
+    } else {
+        if !is_supported_conversion(&from_filetype, &data) {
+            remove_keyboard_from(&bot, &q).await?;
+            make_unsupported_msg(&from_filetype, &data, &to_filetypes)
+                .send()
+                .await?;
+            return Ok(());
+        }
+
+        // Toggle the format and redraw the checkmarks in place
+        match to_filetypes
+            .iter()
+            .position(|to_filetype| *to_filetype == data)
+        {
+            Some(index) => {
+                to_filetypes.remove(index);
+            }
+            None => to_filetypes.push(data),
+        }
+        if let Some(message) = &q.message {
+            bot.edit_message_reply_markup(message.chat.id, message.id)
+                .reply_markup(make_to_keyboard(&config, &to_filetypes))
+                .send()
+                .await?;
+        }
+        dialogue
+            .update(State::ReceiveToFiletype {
+                from_filetype,
+                reused_input,
+                to_filetypes,
+            })
+            .await?;
+    }
+
+    Ok(())
+}
+
+async fn receive_pdf_engine(
+    bot: Bot,
+    q: CallbackQuery,
+    dialogue: MyDialogue,
+    prefs: PrefsStorage,
+    (from_filetype, to_filetypes, reused_input): (String, Vec<String>, Option<StoredInput>),
+) -> HandlerResult {
+    bot.answer_callback_query(q.id.clone()).send().await?;
+    let chat_id = q.chat_id().context("No chat id found")?;
+    let options = prefs
+        .get_dialogue(chat_id)
+        .await?
+        .unwrap_or_default()
+        .initial_options(&to_filetypes);
+
+    let make_fail_msg = || {
+        let keyboard = make_pdf_engine_keyboard(options.pdf_engine.as_deref());
+        bot.send_message(chat_id, "Which PDF engine should be used?")
+            .reply_markup(keyboard)
+    };
+
+    remove_keyboard_from(&bot, &q).await?;
+
+    if let Some(pdf_engine) = q.data {
+        if PDF_ENGINES.contains(&pdf_engine.as_str()) {
+            let chosen = format!("The PDF engine is set to <b>{pdf_engine}</b>.");
+            if pdf_engine == "wkhtmltopdf" {
+                let options = JobOptions {
+                    pdf_engine: Some(pdf_engine),
+                    page_size: Some(DEFAULT_PAGE_SIZE.to_owned()),
+                    margin_mm: Some(DEFAULT_MARGIN_MM),
+                    ..options.clone()
+                };
+                bot.send_message(chat_id, format!("{chosen}\n\n{PAGE_LAYOUT_PROMPT}"))
+                    .parse_mode(ParseMode::Html)
+                    .reply_markup(make_page_layout_keyboard(&options))
+                    .send()
+                    .await?;
+                dialogue
+                    .update(State::ReceivePageLayout {
+                        from_filetype,
+                        to_filetypes,
+                        options,
+                        reused_input,
+                    })
+                    .await?;
+                return Ok(());
+            }
+
+            let options = JobOptions {
+                pdf_engine: Some(pdf_engine),
+                ..options.clone()
+            };
+            ask_for_options(
+                &bot,
+                chat_id,
+                &dialogue,
+                &chosen,
+                JobDraft {
+                    from_filetype,
+                    to_filetypes,
+                    options,
+                    reused_input,
+                },
+            )
+            .await?;
+        } else {
+            make_fail_msg().send().await?;
+        }
+    } else {
+        make_fail_msg().send().await?;
+    }
+
+    Ok(())
+}
+
+const PAGE_LAYOUT_PROMPT: &str = "Pick the page size and margins, then tap Done. \
+     Send /skip to keep the checked ones.";
+
+const PAGE_SIZES: &[&str] = &["A4", "Letter"];
+const DEFAULT_PAGE_SIZE: &str = "A4";
+
+/// Margins offered for wkhtmltopdf, in millimeters
+const MARGINS_MM: &[u32] = &[10, 20, 30];
+const DEFAULT_MARGIN_MM: u32 = 20;
+
+/// Prefix of the callback data of the page size buttons, followed by the size
+const PAGE_SIZE_BUTTON_PREFIX: &str = "__page_size__:";
+
+/// Prefix of the callback data of the margin buttons, followed by the millimeters
+const MARGIN_BUTTON_PREFIX: &str = "__margin__:";
+
+/// Page sizes and margins with the chosen ones checked, followed by Done
+fn make_page_layout_keyboard(options: &JobOptions) -> InlineKeyboardMarkup {
+    let check = |label: String, checked: bool| {
+        if checked {
+            format!("✓ {label}")
+        } else {
+            label
+        }
+    };
+    let page_sizes = PAGE_SIZES
+        .iter()
+        .map(|&page_size| {
+            InlineKeyboardButton::callback(
+                check(
+                    page_size.to_owned(),
+                    options.page_size.as_deref() == Some(page_size),
+                ),
+                format!("{PAGE_SIZE_BUTTON_PREFIX}{page_size}"),
+            )
+        })
+        .collect::<Vec<_>>();
+    let margins = MARGINS_MM
+        .iter()
+        .map(|&margin_mm| {
+            InlineKeyboardButton::callback(
+                check(
+                    format!("{margin_mm} mm"),
+                    options.margin_mm == Some(margin_mm),
+                ),
+                format!("{MARGIN_BUTTON_PREFIX}{margin_mm}"),
+            )
+        })
+        .collect::<Vec<_>>();
+
+    InlineKeyboardMarkup::new(vec![page_sizes, margins]).append_row(vec![
+        InlineKeyboardButton::callback("Done ✔".to_owned(), DONE_BUTTON_DATA.to_owned()),
+    ])
+}
+
+/// Confirmation of the page layout in `options`
+fn page_layout_text(options: &JobOptions) -> String {
+    format!(
+        "The page size is set to <b>{}</b> with <b>{} mm</b> margins.",
+        options.page_size.as_deref().unwrap_or(DEFAULT_PAGE_SIZE),
+        options.margin_mm.unwrap_or(DEFAULT_MARGIN_MM)
+    )
+}
+
+/// Check a page size or margin, or continue with [`ask_for_options`] on Done
+async fn receive_page_layout(
+    bot: Bot,
+    q: CallbackQuery,
+    dialogue: MyDialogue,
+    (from_filetype, to_filetypes, mut options, reused_input): (
+        String,
+        Vec<String>,
+        JobOptions,
+        Option<StoredInput>,
+    ),
+) -> HandlerResult {
+    let data = q.data.clone().unwrap_or_default();
+    if let Some(page_size) = data
+        .strip_prefix(PAGE_SIZE_BUTTON_PREFIX)
+        .filter(|page_size| PAGE_SIZES.contains(page_size))
+    {
+        options.page_size = Some(page_size.to_owned());
+    } else if let Some(margin_mm) = data
+        .strip_prefix(MARGIN_BUTTON_PREFIX)
+        .and_then(|margin_mm| margin_mm.parse().ok())
+        .filter(|margin_mm| MARGINS_MM.contains(margin_mm))
+    {
+        options.margin_mm = Some(margin_mm);
+    } else if data == DONE_BUTTON_DATA {
+        bot.answer_callback_query(q.id.clone()).send().await?;
+        let chat_id = q.chat_id().context("No chat id found")?;
+        remove_keyboard_from(&bot, &q).await?;
+        return ask_for_options(
+            &bot,
+            chat_id,
+            &dialogue,
+            &page_layout_text(&options),
+            JobDraft {
+                from_filetype,
+                to_filetypes,
+                options,
+                reused_input,
+            },
+        )
+        .await;
+    } else {
+        return answer_expired_button(&bot, &q, user_messages(Some(&q.from))).await;
+    }
+
+    bot.answer_callback_query(q.id.clone()).send().await?;
+    if let Some(message) = &q.message {
+        bot.edit_message_reply_markup(message.chat.id, message.id)
+            .reply_markup(make_page_layout_keyboard(&options))
+            .send()
+            .await?;
+    }
+    dialogue
+        .update(State::ReceivePageLayout {
+            from_filetype,
+            to_filetypes,
+            options,
+            reused_input,
+        })
+        .await?;
+    Ok(())
+}
+
+/// Repeat the page layout prompt for messages other than /skip
+async fn nudge_page_layout(
+    bot: Bot,
+    msg: Message,
+    (_, _, options, _): (String, Vec<String>, JobOptions, Option<StoredInput>),
+) -> HandlerResult {
+    bot.send_message(msg.chat.id, PAGE_LAYOUT_PROMPT)
+        .reply_markup(make_page_layout_keyboard(&options))
+        .send()
+        .await?;
+    Ok(())
+}
+
+async fn skip_page_layout(
+    bot: Bot,
+    msg: Message,
+    dialogue: MyDialogue,
+    (from_filetype, to_filetypes, options, reused_input): (
+        String,
+        Vec<String>,
+        JobOptions,
+        Option<StoredInput>,
+    ),
+) -> HandlerResult {
+    ask_for_options(
+        &bot,
+        msg.chat.id,
+        &dialogue,
+        &page_layout_text(&options),
+        JobDraft {
+            from_filetype,
+            to_filetypes,
+            options,
+            reused_input,
+        },
+    )
+    .await
+}
+
+/// The conversion being set up while the options are asked for one by one
+struct JobDraft {
+    from_filetype: String,
+    to_filetypes: Vec<String>,
+    options: JobOptions,
+    /// Set by /again to convert a previous upload instead of asking for one
+    reused_input: Option<StoredInput>,
+}
+
+/// Ask for an EPUB cover if one of the outputs is EPUB, then continue with [`ask_for_reference_doc`].
+/// `chosen` confirms the choice that led here.
+async fn ask_for_options(
+    bot: &Bot,
+    chat_id: ChatId,
+    dialogue: &MyDialogue,
+    chosen: &str,
+    draft: JobDraft,
+) -> HandlerResult {
+    let JobDraft {
+        from_filetype,
+        to_filetypes,
+        options,
+        reused_input,
+    } = draft;
+    if to_filetypes.iter().any(|to_filetype| to_filetype == "epub") {
+        bot.send_message(chat_id, format!("{chosen}\n\n{EPUB_COVER_PROMPT}"))
+            .parse_mode(ParseMode::Html)
+            .send()
+            .await?;
+        dialogue
+            .update(State::ReceiveEpubCover {
+                from_filetype,
+                to_filetypes,
+                options,
+                reused_input,
+            })
+            .await?;
+        Ok(())
+    } else {
+        ask_for_reference_doc(
+            bot,
+            chat_id,
+            dialogue,
+            chosen,
+            JobDraft {
+                from_filetype,
+                to_filetypes,
+                options,
+                reused_input,
+            },
+        )
+        .await
+    }
+}
+
+const EPUB_COVER_PROMPT: &str =
+    "Send an image to use as the EPUB cover, or /skip to go without one. \
+     The title and author can be set in the next steps.";
+
+/// Largest accepted EPUB cover image, in bytes
+const MAX_COVER_SIZE_BYTES: u32 = 5 * 1024 * 1024;
+
+async fn receive_epub_cover(
+    bot: Bot,
+    msg: Message,
+    dialogue: MyDialogue,
+    (from_filetype, to_filetypes, options, reused_input): (
+        String,
+        Vec<String>,
+        JobOptions,
+        Option<StoredInput>,
+    ),
+) -> HandlerResult {
+    // Photos come in several sizes, the last one being the largest
+    let cover = if let Some(photo) = msg.photo().and_then(|sizes| sizes.last()) {
+        Some((photo.file_id.clone(), photo.file_size))
+    } else {
+        msg.document()
+            .filter(|doc| {
+                doc.mime_type
+                    .as_ref()
+                    .map_or(false, |mime| mime.type_() == "image")
+            })
+            .map(|doc| (doc.file_id.clone(), doc.file_size))
+    };
+
+    match cover {
+        Some((_, file_size)) if file_size > MAX_COVER_SIZE_BYTES => {
+            let text = format!(
+                "The cover image is too large, the limit is {} MB. Send a smaller one or /skip.",
+                MAX_COVER_SIZE_BYTES / BYTES_PER_MB as u32
+            );
+            bot.send_message(msg.chat.id, text).send().await?;
+        }
+        Some((file_id, _)) => {
+            ask_for_reference_doc(
+                &bot,
+                msg.chat.id,
+                &dialogue,
+                "The cover image is set.",
+                JobDraft {
+                    from_filetype,
+                    to_filetypes,
+                    options: JobOptions {
+                        cover_file_id: Some(file_id),
+                        ..options
+                    },
+                    reused_input,
+                },
+            )
+            .await?;
+        }
+        None => {
+            bot.send_message(msg.chat.id, EPUB_COVER_PROMPT)
+                .parse_mode(ParseMode::Html)
+                .send()
+                .await?;
+        }
+    }
+
+    Ok(())
+}
+
+async fn skip_epub_cover(
+    bot: Bot,
+    msg: Message,
+    dialogue: MyDialogue,
+    (from_filetype, to_filetypes, options, reused_input): (
+        String,
+        Vec<String>,
+        JobOptions,
+        Option<StoredInput>,
+    ),
+) -> HandlerResult {
+    ask_for_reference_doc(
+        &bot,
+        msg.chat.id,
+        &dialogue,
+        "The EPUB will have no cover.",
+        JobDraft {
+            from_filetype,
+            to_filetypes,
+            options,
+            reused_input,
+        },
+    )
+    .await
+}
+
+/// Offer to take a reference DOCX if one of the outputs is DOCX,
+/// then continue with [`ask_for_bibliography`]
+async fn ask_for_reference_doc(
+    bot: &Bot,
+    chat_id: ChatId,
+    dialogue: &MyDialogue,
+    chosen: &str,
+    draft: JobDraft,
+) -> HandlerResult {
+    let JobDraft {
+        from_filetype,
+        to_filetypes,
+        options,
+        reused_input,
+    } = draft;
+    if to_filetypes.iter().any(|to_filetype| to_filetype == "docx") {
+        bot.send_message(chat_id, format!("{chosen}\n\n{REFERENCE_DOC_PROMPT}"))
+            .parse_mode(ParseMode::Html)
+            .send()
+            .await?;
+        dialogue
+            .update(State::ReceiveReferenceDoc {
+                from_filetype,
+                to_filetypes,
+                options,
+                reused_input,
+            })
+            .await?;
+        Ok(())
+    } else {
+        ask_for_bibliography(
+            bot,
+            chat_id,
+            dialogue,
+            chosen,
+            JobDraft {
+                from_filetype,
+                to_filetypes,
+                options,
+                reused_input,
+            },
+        )
+        .await
+    }
+}
+
+const REFERENCE_DOC_PROMPT: &str = "Send a <b>.docx</b> whose styles the DOCX output should use, \
+     or /skip for pandoc's default styling.";
+
+/// Largest accepted reference DOCX, in bytes
+const MAX_REFERENCE_DOC_SIZE_BYTES: u32 = 5 * 1024 * 1024;
+
+async fn receive_reference_doc(
+    bot: Bot,
+    msg: Message,
+    dialogue: MyDialogue,
+    (from_filetype, to_filetypes, options, reused_input): (
+        String,
+        Vec<String>,
+        JobOptions,
+        Option<StoredInput>,
+    ),
+) -> HandlerResult {
+    let doc = match msg.document() {
+        Some(doc) => doc,
+        None => {
+            bot.send_message(msg.chat.id, REFERENCE_DOC_PROMPT)
+                .parse_mode(ParseMode::Html)
+                .send()
+                .await?;
+            return Ok(());
+        }
+    };
+
+    if doc.file_size > MAX_REFERENCE_DOC_SIZE_BYTES {
+        let text = format!(
+            "The reference document is too large, the limit is {} MB. \
+             Send a smaller one or /skip.",
+            MAX_REFERENCE_DOC_SIZE_BYTES / BYTES_PER_MB as u32
+        );
+        bot.send_message(msg.chat.id, text).send().await?;
+        return Ok(());
+    }
+
+    // pandoc fails on anything else only once the job runs, so check the contents up front
+    let is_docx = match download_to_memory(&bot, &doc.file_id).await {
+        Ok(contents) => infer::get(&contents).map_or(false, |kind| matches_filetype(kind, "docx")),
+        Err(e) => {
+            warn!(
+                "Failed to download reference doc with id {}: {e:?}",
+                doc.file_id
+            );
+            bot.send_message(
+                msg.chat.id,
+                "Couldn't download the reference document, please send it again or /skip.",
+            )
+            .send()
+            .await?;
+            return Ok(());
+        }
+    };
+    if !is_docx {
+        bot.send_message(
+            msg.chat.id,
+            "That doesn't look like a DOCX file. Send a .docx or /skip.",
+        )
+        .send()
+        .await?;
+        return Ok(());
+    }
+
+    ask_for_bibliography(
+        &bot,
+        msg.chat.id,
+        &dialogue,
+        "The reference document is set.",
+        JobDraft {
+            from_filetype,
+            to_filetypes,
+            options: JobOptions {
+                reference_doc_file_id: Some(doc.file_id.clone()),
+                ..options
+            },
+            reused_input,
+        },
+    )
+    .await
+}
+
+async fn skip_reference_doc(
+    bot: Bot,
+    msg: Message,
+    dialogue: MyDialogue,
+    (from_filetype, to_filetypes, options, reused_input): (
+        String,
+        Vec<String>,
+        JobOptions,
+        Option<StoredInput>,
+    ),
+) -> HandlerResult {
+    ask_for_bibliography(
+        &bot,
+        msg.chat.id,
+        &dialogue,
+        "The DOCX will use the default styling.",
+        JobDraft {
+            from_filetype,
+            to_filetypes,
+            options,
+            reused_input,
+        },
+    )
+    .await
+}
+
+/// Offer to take a BibTeX file for citations if Markdown is converted to PDF or DOCX,
+/// then continue with [`ask_for_highlight_style`]
+async fn ask_for_bibliography(
+    bot: &Bot,
+    chat_id: ChatId,
+    dialogue: &MyDialogue,
+    chosen: &str,
+    draft: JobDraft,
+) -> HandlerResult {
+    let JobDraft {
+        from_filetype,
+        to_filetypes,
+        options,
+        reused_input,
+    } = draft;
+    if from_filetype == "markdown"
+        && to_filetypes
+            .iter()
+            .any(|to_filetype| BIBLIOGRAPHY_FILETYPES.contains(&to_filetype.as_str()))
+    {
+        bot.send_message(chat_id, format!("{chosen}\n\n{BIBLIOGRAPHY_PROMPT}"))
+            .parse_mode(ParseMode::Html)
+            .send()
+            .await?;
+        dialogue
+            .update(State::ReceiveBibliography {
+                from_filetype,
+                to_filetypes,
+                options,
+                reused_input,
+            })
+            .await?;
+        Ok(())
+    } else {
+        ask_for_highlight_style(
+            bot,
+            chat_id,
+            dialogue,
+            chosen,
+            JobDraft {
+                from_filetype,
+                to_filetypes,
+                options,
+                reused_input,
+            },
+        )
+        .await
+    }
+}
+
+const BIBLIOGRAPHY_PROMPT: &str = "Send a <b>.bib</b> file to resolve the citations in the \
+     document, or /skip if it has none.";
+
+/// Largest accepted bibliography, in bytes
+const MAX_BIBLIOGRAPHY_SIZE_BYTES: u32 = 5 * 1024 * 1024;
+
+async fn receive_bibliography(
+    bot: Bot,
+    msg: Message,
+    dialogue: MyDialogue,
+    (from_filetype, to_filetypes, options, reused_input): (
+        String,
+        Vec<String>,
+        JobOptions,
+        Option<StoredInput>,
+    ),
+) -> HandlerResult {
+    let doc = match msg.document() {
+        Some(doc) => doc,
+        None => {
+            bot.send_message(msg.chat.id, BIBLIOGRAPHY_PROMPT)
+                .parse_mode(ParseMode::Html)
+                .send()
+                .await?;
+            return Ok(());
+        }
+    };
+
+    if doc.file_size > MAX_BIBLIOGRAPHY_SIZE_BYTES {
+        let text = format!(
+            "The bibliography is too large, the limit is {} MB. \
+             Send a smaller one or /skip.",
+            MAX_BIBLIOGRAPHY_SIZE_BYTES / BYTES_PER_MB as u32
+        );
+        bot.send_message(msg.chat.id, text).send().await?;
+        return Ok(());
+    }
+
+    // pandoc fails on anything else only once the job runs, so check the contents up front
+    let is_bibtex = match download_to_memory(&bot, &doc.file_id).await {
+        Ok(contents) => looks_like_bibtex(&contents),
+        Err(e) => {
+            warn!(
+                "Failed to download bibliography with id {}: {e:?}",
+                doc.file_id
+            );
+            bot.send_message(
+                msg.chat.id,
+                "Couldn't download the bibliography, please send it again or /skip.",
+            )
+            .send()
+            .await?;
+            return Ok(());
+        }
+    };
+    if !is_bibtex {
+        bot.send_message(
+            msg.chat.id,
+            "That doesn't look like a BibTeX file. Send a .bib or /skip.",
+        )
+        .send()
+        .await?;
+        return Ok(());
+    }
+
+    ask_for_highlight_style(
+        &bot,
+        msg.chat.id,
+        &dialogue,
+        "The bibliography is set, citations will be resolved.",
+        JobDraft {
+            from_filetype,
+            to_filetypes,
+            options: JobOptions {
+                bibliography_file_id: Some(doc.file_id.clone()),
+                ..options
+            },
+            reused_input,
+        },
+    )
+    .await
+}
+
+async fn skip_bibliography(
+    bot: Bot,
+    msg: Message,
+    dialogue: MyDialogue,
+    (from_filetype, to_filetypes, options, reused_input): (
+        String,
+        Vec<String>,
+        JobOptions,
+        Option<StoredInput>,
+    ),
+) -> HandlerResult {
+    ask_for_highlight_style(
+        &bot,
+        msg.chat.id,
+        &dialogue,
+        "Citations will be left as they are.",
+        JobDraft {
+            from_filetype,
+            to_filetypes,
+            options,
+            reused_input,
+        },
+    )
+    .await
+}
+
+/// Whether `contents` is text with at least one BibTeX entry such as `@article{`
+fn looks_like_bibtex(contents: &[u8]) -> bool {
+    let text = match std::str::from_utf8(contents) {
+        Ok(text) => text,
+        Err(_) => return false,
+    };
+    text.lines().any(|line| {
+        line.trim_start()
+            .strip_prefix('@')
+            .map(|rest| {
+                let kind_len = rest
+                    .find(|c: char| !c.is_ascii_alphabetic())
+                    .unwrap_or(rest.len());
+                kind_len > 0 && rest[kind_len..].trim_start().starts_with(['{', '('])
+            })
+            .unwrap_or(false)
+    })
+}
+
+/// Ask for a syntax highlighting style if one of the outputs highlights code,
+/// then continue with [`ask_for_revealjs_theme`]
+async fn ask_for_highlight_style(
+    bot: &Bot,
+    chat_id: ChatId,
+    dialogue: &MyDialogue,
+    chosen: &str,
+    draft: JobDraft,
+) -> HandlerResult {
+    let JobDraft {
+        from_filetype,
+        to_filetypes,
+        options,
+        reused_input,
+    } = draft;
+    if to_filetypes
+        .iter()
+        .any(|to_filetype| HIGHLIGHT_FILETYPES.contains(&to_filetype.as_str()))
+    {
+        bot.send_message(chat_id, format!("{chosen}\n\n{HIGHLIGHT_STYLE_PROMPT}"))
+            .parse_mode(ParseMode::Html)
+            .reply_markup(make_highlight_style_keyboard())
+            .send()
+            .await?;
+        dialogue
+            .update(State::ReceiveHighlightStyle {
+                from_filetype,
+                to_filetypes,
+                options,
+                reused_input,
+            })
+            .await?;
+        Ok(())
+    } else {
+        ask_for_revealjs_theme(
+            bot,
+            chat_id,
+            dialogue,
+            chosen,
+            JobDraft {
+                from_filetype,
+                to_filetypes,
+                options,
+                reused_input,
+            },
+        )
+        .await
+    }
+}
+
+const HIGHLIGHT_STYLE_PROMPT: &str = "Which style should code blocks be highlighted with? \
+     Send /skip for pandoc's default.";
+
+async fn receive_highlight_style(
+    bot: Bot,
+    q: CallbackQuery,
+    dialogue: MyDialogue,
+    (from_filetype, to_filetypes, options, reused_input): (
+        String,
+        Vec<String>,
+        JobOptions,
+        Option<StoredInput>,
+    ),
+) -> HandlerResult {
+    bot.answer_callback_query(q.id.clone()).send().await?;
+    let chat_id = q.chat_id().context("No chat id found")?;
+
+    remove_keyboard_from(&bot, &q).await?;
+
+    match q
+        .data
+        .filter(|style| HIGHLIGHT_STYLES.contains(&style.as_str()))
+    {
+        Some(style) => {
+            ask_for_revealjs_theme(
+                &bot,
+                chat_id,
+                &dialogue,
+                &format!("The highlight style is set to <b>{style}</b>."),
+                JobDraft {
+                    from_filetype,
+                    to_filetypes,
+                    options: JobOptions {
+                        highlight_style: Some(style),
+                        ..options
+                    },
+                    reused_input,
+                },
+            )
+            .await?;
+        }
+        None => {
+            bot.send_message(chat_id, HIGHLIGHT_STYLE_PROMPT)
+                .reply_markup(make_highlight_style_keyboard())
+                .send()
+                .await?;
+        }
+    }
+
+    Ok(())
+}
+
+/// Repeat the highlight style prompt for messages other than /skip
+async fn nudge_highlight_style(bot: Bot, msg: Message) -> HandlerResult {
+    bot.send_message(msg.chat.id, HIGHLIGHT_STYLE_PROMPT)
+        .reply_markup(make_highlight_style_keyboard())
+        .send()
+        .await?;
+    Ok(())
+}
+
+async fn skip_highlight_style(
+    bot: Bot,
+    msg: Message,
+    dialogue: MyDialogue,
+    (from_filetype, to_filetypes, options, reused_input): (
+        String,
+        Vec<String>,
+        JobOptions,
+        Option<StoredInput>,
+    ),
+) -> HandlerResult {
+    ask_for_revealjs_theme(
+        &bot,
+        msg.chat.id,
+        &dialogue,
+        "Code blocks will use pandoc's default highlighting.",
+        JobDraft {
+            from_filetype,
+            to_filetypes,
+            options,
+            reused_input,
+        },
+    )
+    .await
+}
+
+/// Ask for a reveal.js theme if slides are among the outputs, then continue with [`ask_for_source_options`]
+async fn ask_for_revealjs_theme(
+    bot: &Bot,
+    chat_id: ChatId,
+    dialogue: &MyDialogue,
+    chosen: &str,
+    draft: JobDraft,
+) -> HandlerResult {
+    let JobDraft {
+        from_filetype,
+        to_filetypes,
+        options,
+        reused_input,
+    } = draft;
+    if to_filetypes
+        .iter()
+        .any(|to_filetype| to_filetype == "revealjs")
+    {
+        bot.send_message(chat_id, format!("{chosen}\n\n{REVEALJS_THEME_PROMPT}"))
+            .parse_mode(ParseMode::Html)
+            .reply_markup(make_revealjs_theme_keyboard())
+            .send()
+            .await?;
+        dialogue
+            .update(State::ReceiveRevealjsTheme {
+                from_filetype,
+                to_filetypes,
+                options,
+                reused_input,
+            })
+            .await?;
+        Ok(())
+    } else {
+        ask_for_source_options(
+            bot,
+            chat_id,
+            dialogue,
+            chosen,
+            JobDraft {
+                from_filetype,
+                to_filetypes,
+                options,
+                reused_input,
+            },
+        )
+        .await
+    }
+}
+
+const REVEALJS_THEME_PROMPT: &str =
+    "Which theme should the slides use? Send /skip for the default.";
+
+async fn receive_revealjs_theme(
+    bot: Bot,
+    q: CallbackQuery,
+    dialogue: MyDialogue,
+    (from_filetype, to_filetypes, options, reused_input): (
+        String,
+        Vec<String>,
+        JobOptions,
+        Option<StoredInput>,
+    ),
+) -> HandlerResult {
+    bot.answer_callback_query(q.id.clone()).send().await?;
+    let chat_id = q.chat_id().context("No chat id found")?;
+
+    remove_keyboard_from(&bot, &q).await?;
+
+    match q
+        .data
+        .filter(|theme| REVEALJS_THEMES.contains(&theme.as_str()))
+    {
+        Some(theme) => {
+            ask_for_source_options(
+                &bot,
+                chat_id,
+                &dialogue,
+                &format!("The slide theme is set to <b>{theme}</b>."),
+                JobDraft {
+                    from_filetype,
+                    to_filetypes,
+                    options: JobOptions {
+                        revealjs_theme: Some(theme),
+                        ..options
+                    },
+                    reused_input,
+                },
+            )
+            .await?;
+        }
+        None => {
+            bot.send_message(chat_id, REVEALJS_THEME_PROMPT)
+                .reply_markup(make_revealjs_theme_keyboard())
+                .send()
+                .await?;
+        }
+    }
+
+    Ok(())
+}
+
+/// Repeat the reveal.js theme prompt for messages other than /skip
+async fn nudge_revealjs_theme(bot: Bot, msg: Message) -> HandlerResult {
+    bot.send_message(msg.chat.id, REVEALJS_THEME_PROMPT)
+        .reply_markup(make_revealjs_theme_keyboard())
+        .send()
+        .await?;
+    Ok(())
+}
+
+async fn skip_revealjs_theme(
+    bot: Bot,
+    msg: Message,
+    dialogue: MyDialogue,
+    (from_filetype, to_filetypes, options, reused_input): (
+        String,
+        Vec<String>,
+        JobOptions,
+        Option<StoredInput>,
+    ),
+) -> HandlerResult {
+    ask_for_source_options(
+        &bot,
+        msg.chat.id,
+        &dialogue,
+        "The slides will use the default theme.",
+        JobDraft {
+            from_filetype,
+            to_filetypes,
+            options,
+            reused_input,
+        },
+    )
+    .await
+}
+
+/// Ask which Markdown flavor the input is written in and its extensions if that's ambiguous,
+/// then for the metadata.
+/// `chosen` confirms the choice that led here.
+async fn ask_for_source_options(
+    bot: &Bot,
+    chat_id: ChatId,
+    dialogue: &MyDialogue,
+    chosen: &str,
+    draft: JobDraft,
+) -> HandlerResult {
+    let JobDraft {
+        from_filetype,
+        to_filetypes,
+        options,
+        reused_input,
+    } = draft;
+    if from_filetype == "markdown" {
+        bot.send_message(chat_id, format!("{chosen}\n\n{MARKDOWN_FLAVOR_PROMPT}"))
+            .parse_mode(ParseMode::Html)
+            .reply_markup(make_markdown_flavor_keyboard())
+            .send()
+            .await?;
+        dialogue
+            .update(State::ReceiveMarkdownFlavor {
+                from_filetype,
+                to_filetypes,
+                options,
+                reused_input,
+            })
+            .await?;
+        Ok(())
+    } else {
+        ask_for_metadata(
+            bot,
+            chat_id,
+            dialogue,
+            chosen,
+            JobDraft {
+                from_filetype,
+                to_filetypes,
+                options,
+                reused_input,
+            },
+        )
+        .await
+    }
+}
+
+/// Ask for the metadata, offering the toggles of the remaining options.
+/// `chosen` confirms the choice that led here.
+async fn ask_for_metadata(
+    bot: &Bot,
+    chat_id: ChatId,
+    dialogue: &MyDialogue,
+    chosen: &str,
+    draft: JobDraft,
+) -> HandlerResult {
+    let JobDraft {
+        from_filetype,
+        to_filetypes,
+        options,
+        reused_input,
+    } = draft;
+    bot.send_message(chat_id, format!("{chosen}\n\n{METADATA_PROMPT}"))
+        .parse_mode(ParseMode::Html)
+        .reply_markup(make_options_keyboard(&options, &to_filetypes))
+        .send()
+        .await?;
+    dialogue
+        .update(State::ReceiveMetadata {
+            from_filetype,
+            to_filetypes,
+            options,
+            reused_input,
+        })
+        .await?;
+    Ok(())
+}
+
+const MARKDOWN_FLAVOR_PROMPT: &str = "Which Markdown flavor is the document written in? \
+     Send /skip if unsure to use pandoc's own <b>markdown</b>.";
+
+async fn receive_markdown_flavor(
+    bot: Bot,
+    q: CallbackQuery,
+    dialogue: MyDialogue,
+    (from_filetype, to_filetypes, options, reused_input): (
+        String,
+        Vec<String>,
+        JobOptions,
+        Option<StoredInput>,
+    ),
+) -> HandlerResult {
+    bot.answer_callback_query(q.id.clone()).send().await?;
+    let chat_id = q.chat_id().context("No chat id found")?;
+
+    remove_keyboard_from(&bot, &q).await?;
+
+    match q
+        .data
+        .filter(|flavor| MARKDOWN_FLAVORS.contains(&flavor.as_str()))
+    {
+        Some(flavor) => {
+            ask_for_markdown_extensions(
+                &bot,
+                chat_id,
+                &dialogue,
+                &format!("The Markdown flavor is set to <b>{flavor}</b>."),
+                JobDraft {
+                    from_filetype,
+                    to_filetypes,
+                    options: JobOptions {
+                        source_format_variant: Some(flavor),
+                        ..options
+                    },
+                    reused_input,
+                },
+            )
+            .await?;
+        }
+        None => {
+            bot.send_message(chat_id, MARKDOWN_FLAVOR_PROMPT)
+                .parse_mode(ParseMode::Html)
+                .reply_markup(make_markdown_flavor_keyboard())
+                .send()
+                .await?;
+        }
+    }
+
+    Ok(())
+}
+
+/// Repeat the flavor prompt for messages other than /skip
+async fn nudge_markdown_flavor(bot: Bot, msg: Message) -> HandlerResult {
+    bot.send_message(msg.chat.id, MARKDOWN_FLAVOR_PROMPT)
+        .parse_mode(ParseMode::Html)
+        .reply_markup(make_markdown_flavor_keyboard())
+        .send()
+        .await?;
+    Ok(())
+}
+
+async fn skip_markdown_flavor(
+    bot: Bot,
+    msg: Message,
+    dialogue: MyDialogue,
+    (from_filetype, to_filetypes, options, reused_input): (
+        String,
+        Vec<String>,
+        JobOptions,
+        Option<StoredInput>,
+    ),
+) -> HandlerResult {
+    ask_for_markdown_extensions(
+        &bot,
+        msg.chat.id,
+        &dialogue,
+        "Using pandoc's own <b>markdown</b>.",
+        JobDraft {
+            from_filetype,
+            to_filetypes,
+            options: JobOptions {
+                source_format_variant: Some("markdown".to_owned()),
+                ..options
+            },
+            reused_input,
+        },
+    )
+    .await
+}
+
+/// Offer toggles for the pandoc extensions of the flavor in `options.source_format_variant`
+async fn ask_for_markdown_extensions(
+    bot: &Bot,
+    chat_id: ChatId,
+    dialogue: &MyDialogue,
+    chosen: &str,
+    draft: JobDraft,
+) -> HandlerResult {
+    let JobDraft {
+        from_filetype,
+        to_filetypes,
+        options,
+        reused_input,
+    } = draft;
+    let variant = options.source_format_variant.as_deref().unwrap_or_default();
+    bot.send_message(chat_id, format!("{chosen}\n\n{MARKDOWN_EXTENSIONS_PROMPT}"))
+        .parse_mode(ParseMode::Html)
+        .reply_markup(make_markdown_extensions_keyboard(variant))
+        .send()
+        .await?;
+    dialogue
+        .update(State::ReceiveMarkdownExtensions {
+            from_filetype,
+            to_filetypes,
+            options,
+            reused_input,
+        })
+        .await?;
+    Ok(())
+}
+
+const MARKDOWN_EXTENSIONS_PROMPT: &str =
+    "Tap an extension to turn it on, off, or back to the flavor's default, then press Done. \
+     Send /skip to keep the defaults.";
+
+/// Cycle the tapped extension, or continue with the metadata on Done
+async fn receive_markdown_extension(
+    bot: Bot,
+    q: CallbackQuery,
+    dialogue: MyDialogue,
+    (from_filetype, to_filetypes, options, reused_input): (
+        String,
+        Vec<String>,
+        JobOptions,
+        Option<StoredInput>,
+    ),
+) -> HandlerResult {
+    bot.answer_callback_query(q.id.clone()).send().await?;
+    let chat_id = q.chat_id().context("No chat id found")?;
+    let variant = options.source_format_variant.clone().unwrap_or_default();
+
+    match q.data.as_deref() {
+        Some(DONE_BUTTON_DATA) => {
+            remove_keyboard_from(&bot, &q).await?;
+            ask_for_metadata(
+                &bot,
+                chat_id,
+                &dialogue,
+                &format!("The document will be read as <b>{variant}</b>."),
+                JobDraft {
+                    from_filetype,
+                    to_filetypes,
+                    options,
+                    reused_input,
+                },
+            )
+            .await?;
+        }
+        Some(extension) if MARKDOWN_EXTENSIONS.contains(&extension) => {
+            let enabled = match extension_setting(&variant, extension) {
+                None => Some(true),
+                Some(true) => Some(false),
+                Some(false) => None,
+            };
+            let variant = with_extension_setting(&variant, extension, enabled);
+            if let Some(message) = &q.message {
+                bot.edit_message_reply_markup(message.chat.id, message.id)
+                    .reply_markup(make_markdown_extensions_keyboard(&variant))
+                    .send()
+                    .await?;
+            }
+            dialogue
+                .update(State::ReceiveMarkdownExtensions {
+                    from_filetype,
+                    to_filetypes,
+                    options: JobOptions {
+                        source_format_variant: Some(variant),
+                        ..options
+                    },
+                    reused_input,
+                })
+                .await?;
+        }
+        _ => {}
+    }
+
+    Ok(())
+}
+
+/// Repeat the extensions prompt for messages other than /skip
+async fn nudge_markdown_extensions(
+    bot: Bot,
+    msg: Message,
+    (_, _, options, _): (String, Vec<String>, JobOptions, Option<StoredInput>),
+) -> HandlerResult {
+    let variant = options.source_format_variant.as_deref().unwrap_or_default();
+    bot.send_message(msg.chat.id, MARKDOWN_EXTENSIONS_PROMPT)
+        .reply_markup(make_markdown_extensions_keyboard(variant))
+        .send()
+        .await?;
+    Ok(())
+}
+
+async fn skip_markdown_extensions(
+    bot: Bot,
+    msg: Message,
+    dialogue: MyDialogue,
+    (from_filetype, to_filetypes, options, reused_input): (
+        String,
+        Vec<String>,
+        JobOptions,
+        Option<StoredInput>,
+    ),
+) -> HandlerResult {
+    // Drop any toggles, keeping only the flavor
+    let flavor = options
+        .source_format_variant
+        .as_deref()
+        .map(|variant| split_reader_spec(variant).0.to_owned());
+    ask_for_metadata(
+        &bot,
+        msg.chat.id,
+        &dialogue,
+        "Using the flavor's default extensions.",
+        JobDraft {
+            from_filetype,
+            to_filetypes,
+            options: JobOptions {
+                source_format_variant: flavor,
+                ..options
+            },
+            reused_input,
+        },
+    )
+    .await
+}
+
+/// Split a pandoc reader spec like `gfm+footnotes-raw_html` into the reader
+/// and its extensions, each `true` if turned on
+fn split_reader_spec(spec: &str) -> (&str, Vec<(&str, bool)>) {
+    let reader_end = spec.find(['+', '-']).unwrap_or(spec.len());
+    let mut extensions = vec![];
+    let mut rest = &spec[reader_end..];
+    while let Some(sign) = rest.chars().next() {
+        let name_end = rest[1..].find(['+', '-']).map_or(rest.len(), |end| end + 1);
+        extensions.push((&rest[1..name_end], sign == '+'));
+        rest = &rest[name_end..];
+    }
+    (&spec[..reader_end], extensions)
+}
+
+/// Whether `extension` is turned on or off in `spec`, or `None` if left at the reader's default
+fn extension_setting(spec: &str, extension: &str) -> Option<bool> {
+    split_reader_spec(spec)
+        .1
+        .into_iter()
+        .find(|&(name, _)| name == extension)
+        .map(|(_, enabled)| enabled)
+}
+
+/// `spec` with `extension` turned on, off, or left at the reader's default
+fn with_extension_setting(spec: &str, extension: &str, enabled: Option<bool>) -> String {
+    let (reader, extensions) = split_reader_spec(spec);
+    let mut spec = reader.to_owned();
+    for (name, on) in extensions {
+        if name != extension {
+            spec.push(if on { '+' } else { '-' });
+            spec.push_str(name);
+        }
+    }
+    if let Some(on) = enabled {
+        spec.push(if on { '+' } else { '-' });
+        spec.push_str(extension);
+    }
+    spec
+}
+
+const METADATA_PROMPT: &str =
+    "Optionally send document metadata as <code>key: value</code> lines, e.g.\n\
+     <code>title: My Document</code>\n\
+     <code>author: Jane Doe</code>\n\
+     or /skip to leave it empty. The buttons below toggle further options, \
+     and /name followed by a name renames the output. \
+     Sending the file right away skips the remaining steps.";
+
+/// Callback data of the table of contents toggle
+const TOC_BUTTON_DATA: &str = "__toc__";
+
+/// Callback data of the standalone HTML toggle
+const STANDALONE_BUTTON_DATA: &str = "__standalone__";
+
+/// Flip one of the boolean options while waiting for the metadata
+async fn toggle_option(
+    bot: Bot,
+    q: CallbackQuery,
+    dialogue: MyDialogue,
+    (from_filetype, to_filetypes, options, reused_input): (
+        String,
+        Vec<String>,
+        JobOptions,
+        Option<StoredInput>,
+    ),
+) -> HandlerResult {
+    bot.answer_callback_query(q.id.clone()).send().await?;
+
+    let options = match q.data.as_deref() {
+        Some(TOC_BUTTON_DATA) => JobOptions {
+            toc: !options.toc,
+            ..options
+        },
+        Some(STANDALONE_BUTTON_DATA) => JobOptions {
+            standalone: !options.standalone,
+            ..options
+        },
+        _ => return Ok(()),
+    };
+    if let Some(message) = &q.message {
+        bot.edit_message_reply_markup(message.chat.id, message.id)
+            .reply_markup(make_options_keyboard(&options, &to_filetypes))
+            .send()
+            .await?;
+    }
+    dialogue
+        .update(State::ReceiveMetadata {
+            from_filetype,
+            to_filetypes,
+            options,
+            reused_input,
+        })
+        .await?;
+    Ok(())
+}
+
+/// Longest accepted output name, in characters
+const MAX_OUTPUT_NAME_CHARS: usize = 100;
+
+/// Check a name given with /name, returning why it's rejected otherwise
+fn validate_output_name(name: &str) -> Result<&str, &'static str> {
+    let name = name.trim();
+    if name.is_empty() {
+        Err("Send the name after the command, e.g. /name report.")
+    } else if name.contains(['/', '\\']) {
+        Err("The name can't contain / or \\.")
+    } else if name.starts_with('.') || name.chars().any(char::is_control) {
+        Err("The name can't start with a dot or contain control characters.")
+    } else if name.chars().count() > MAX_OUTPUT_NAME_CHARS {
+        Err("The name is too long.")
+    } else {
+        Ok(name)
+    }
+}
+
+/// Name the output while waiting for the metadata, the extension is added on delivery
+async fn set_output_name(
+    bot: Bot,
+    msg: Message,
+    dialogue: MyDialogue,
+    name: String,
+    (from_filetype, to_filetypes, options, reused_input): (
+        String,
+        Vec<String>,
+        JobOptions,
+        Option<StoredInput>,
+    ),
+) -> HandlerResult {
+    let stem = match validate_output_name(&name) {
+        Ok(stem) => stem.to_owned(),
+        Err(text) => {
+            bot.send_message(msg.chat.id, text).send().await?;
+            return Ok(());
+        }
+    };
+
+    // Sent as plain text, since the name may contain HTML special characters
+    let text =
+        format!("The output will be named \"{stem}\". Send the metadata or /skip to continue.");
+    bot.send_message(msg.chat.id, text).send().await?;
+    dialogue
+        .update(State::ReceiveMetadata {
+            from_filetype,
+            to_filetypes,
+            options: JobOptions {
+                output_filename_stem: Some(stem),
+                ..options
+            },
+            reused_input,
+        })
+        .await?;
+    Ok(())
+}
+
+async fn receive_metadata(
+    bot: Bot,
+    msg: Message,
+    dialogue: MyDialogue,
+    submitter: Arc<JobSubmitter>,
+    (from_filetype, to_filetypes, options, reused_input): (
+        String,
+        Vec<String>,
+        JobOptions,
+        Option<StoredInput>,
+    ),
+) -> HandlerResult {
+    match msg.text().and_then(parse_metadata) {
+        Some(metadata) => {
+            let options = JobOptions {
+                metadata,
+                ..options
+            };
+            finish_options(
+                &bot,
+                msg.chat.id,
+                user_messages(msg.from()),
+                &dialogue,
+                &submitter,
+                JobDraft {
+                    from_filetype,
+                    to_filetypes,
+                    options,
+                    reused_input,
+                },
+            )
+            .await?;
+        }
+        None => {
+            bot.send_message(msg.chat.id, METADATA_PROMPT)
+                .parse_mode(ParseMode::Html)
+                .reply_markup(make_options_keyboard(&options, &to_filetypes))
+                .send()
+                .await?;
+        }
+    }
+
+    Ok(())
+}
+
+async fn skip_metadata(
+    bot: Bot,
+    msg: Message,
+    dialogue: MyDialogue,
+    submitter: Arc<JobSubmitter>,
+    (from_filetype, to_filetypes, options, reused_input): (
+        String,
+        Vec<String>,
+        JobOptions,
+        Option<StoredInput>,
+    ),
+) -> HandlerResult {
+    finish_options(
+        &bot,
+        msg.chat.id,
+        user_messages(msg.from()),
+        &dialogue,
+        &submitter,
+        JobDraft {
+            from_filetype,
+            to_filetypes,
+            options,
+            reused_input,
+        },
+    )
+    .await
+}
+
+/// Callback data of the button submitting the summarized conversion
+const CONFIRM_BUTTON_DATA: &str = "__confirm__";
+
+/// Callback data of the button dropping the summarized conversion
+const CANCEL_BUTTON_DATA: &str = "__cancel__";
+
+/// Show the chosen options to be confirmed if `CONFIRM_JOBS` is set, otherwise go on right away
+async fn finish_options(
+    bot: &Bot,
+    chat_id: ChatId,
+    messages: &'static Messages,
+    dialogue: &MyDialogue,
+    submitter: &JobSubmitter,
+    draft: JobDraft,
+) -> HandlerResult {
+    if !submitter.config.confirm_jobs {
+        return start_job(bot, chat_id, messages, dialogue, submitter, draft).await;
+    }
+
+    let JobDraft {
+        from_filetype,
+        to_filetypes,
+        options,
+        reused_input,
+    } = draft;
+
+    bot.send_message(
+        chat_id,
+        format!(
+            "{}\nConvert with these options?",
+            job_summary(&from_filetype, &to_filetypes, &options)
+        ),
+    )
+    .reply_markup(make_confirm_keyboard())
+    .send()
+    .await?;
+    dialogue
+        .update(State::ConfirmJob {
+            from_filetype,
+            to_filetypes,
+            options,
+            reused_input,
+        })
+        .await?;
+    Ok(())
+}
+
+/// Submit the reused input, or ask for the file to convert
+async fn start_job(
+    bot: &Bot,
+    chat_id: ChatId,
+    messages: &'static Messages,
+    dialogue: &MyDialogue,
+    submitter: &JobSubmitter,
+    draft: JobDraft,
+) -> HandlerResult {
+    let JobDraft {
+        from_filetype,
+        to_filetypes,
+        options,
+        reused_input,
+    } = draft;
+    match reused_input {
+        Some(input) => {
+            let chat = ChatContext {
+                bot: bot.clone(),
+                dialogue: dialogue.clone(),
+                messages,
+            };
+            submitter
+                .submit_document(&chat, input, to_filetypes, options)
+                .await
+        }
+        None => request_input(bot, chat_id, dialogue, from_filetype, to_filetypes, options).await,
+    }
+}
+
+/// One line per choice, e.g. `markdown → pdf` and `xelatex engine, TOC on`
+fn job_summary(from_filetype: &str, to_filetypes: &[String], options: &JobOptions) -> String {
+    let has = |filetype: &str| {
+        to_filetypes
+            .iter()
+            .any(|to_filetype| to_filetype == filetype)
+    };
+    let on_off = |value| if value { "on" } else { "off" };
+
+    let mut choices = vec![];
+    if let Some(variant) = &options.source_format_variant {
+        choices.push(format!("read as {variant}"));
+    }
+    if let Some(pdf_engine) = &options.pdf_engine {
+        choices.push(format!("{pdf_engine} engine"));
+    }
+    if let (Some(page_size), Some(margin_mm)) = (&options.page_size, options.margin_mm) {
+        choices.push(format!("{page_size} with {margin_mm} mm margins"));
+    }
+    choices.push(format!("TOC {}", on_off(options.toc)));
+    if has("html") {
+        choices.push(format!("standalone HTML {}", on_off(options.standalone)));
+    }
+    if let Some(highlight_style) = &options.highlight_style {
+        choices.push(format!("{highlight_style} highlighting"));
+    }
+    if let Some(theme) = &options.revealjs_theme {
+        choices.push(format!("{theme} slide theme"));
+    }
+    if options.cover_file_id.is_some() {
+        choices.push("EPUB cover".to_owned());
+    }
+    if options.reference_doc_file_id.is_some() {
+        choices.push("reference document".to_owned());
+    }
+    if options.bibliography_file_id.is_some() {
+        choices.push("bibliography".to_owned());
+    }
+    if !options.metadata.is_empty() {
+        choices.push(format!("{} metadata fields", options.metadata.len()));
+    }
+    if let Some(stem) = &options.output_filename_stem {
+        choices.push(format!("named {stem}"));
+    }
+
+    format!(
+        "{from_filetype} → {}\n{}",
+        to_filetypes.join(", "),
+        choices.join(", ")
+    )
+}
+
+/// Submit or drop the summarized conversion
+async fn receive_job_confirmation(
+    bot: Bot,
+    q: CallbackQuery,
+    dialogue: MyDialogue,
+    submitter: Arc<JobSubmitter>,
+    (from_filetype, to_filetypes, options, reused_input): (
+        String,
+        Vec<String>,
+        JobOptions,
+        Option<StoredInput>,
+    ),
+) -> HandlerResult {
+    bot.answer_callback_query(q.id.clone()).send().await?;
+    let chat_id = q.chat_id().context("No chat id found")?;
+
+    match q.data.as_deref() {
+        Some(CONFIRM_BUTTON_DATA) => {
+            remove_keyboard_from(&bot, &q).await?;
+            start_job(
+                &bot,
+                chat_id,
+                user_messages(Some(&q.from)),
+                &dialogue,
+                &submitter,
+                JobDraft {
+                    from_filetype,
+                    to_filetypes,
+                    options,
+                    reused_input,
+                },
+            )
+            .await?;
+        }
+        Some(CANCEL_BUTTON_DATA) => {
+            remove_keyboard_from(&bot, &q).await?;
+            dialogue.update(State::Start).await?;
+            bot.send_message(chat_id, "Cancelled, send /start to begin again.")
+                .send()
+                .await?;
+        }
+        _ => {}
+    }
+    Ok(())
+}
+
+/// Point at the summary's buttons for messages sent instead of tapping one
+async fn nudge_job_confirmation(bot: Bot, msg: Message) -> HandlerResult {
+    bot.send_message(msg.chat.id, "Tap Confirm to convert, or Cancel.")
+        .reply_markup(make_confirm_keyboard())
+        .send()
+        .await?;
+    Ok(())
+}
+
+async fn request_input(
+    bot: &Bot,
+    chat_id: ChatId,
+    dialogue: &MyDialogue,
+    from_filetype: String,
+    to_filetypes: Vec<String>,
+    options: JobOptions,
+) -> HandlerResult {
+    bot.send_message(chat_id, "Now send me the file to be converted.")
+        .send()
+        .await?;
+    dialogue
+        .update(State::ReceiveInputFile {
+            from_filetype,
+            to_filetypes,
+            options,
+        })
+        .await?;
+    Ok(())
+}
+
+/// Parse `key: value` lines, or `None` if there are none or any line is malformed
+fn parse_metadata(text: &str) -> Option<HashMap<String, String>> {
+    let metadata: HashMap<_, _> = text
+        .lines()
+        .filter(|line| !line.trim().is_empty())
+        .map(|line| {
+            let (key, value) = line.split_once(':')?;
+            let (key, value) = (key.trim(), value.trim());
+            let is_valid_key = !key.is_empty() && !key.contains(char::is_whitespace);
+            is_valid_key.then(|| (key.to_owned(), value.to_owned()))
+        })
+        .collect::<Option<_>>()?;
+
+    (!metadata.is_empty()).then(|| metadata)
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+struct ConvertRequest {
+    job_id: String,
+    chat_id: i64,
+    #[serde(flatten)]
+    input: JobFile,
+    file_id: String,
+    /// Name of the uploaded document, echoed back in the response
+    original_filename: Option<String>,
+    from_filetype: String,
+    to_filetype: String,
+    #[serde(flatten)]
+    options: JobOptions,
+    /// Passed to pandoc as `--epub-cover-image`, only set for EPUB outputs
+    #[serde(default, with = "serde_bytes", skip_serializing_if = "Option::is_none")]
+    cover_image: Option<Vec<u8>>,
+    /// Passed to pandoc as `--reference-doc`, only set for DOCX outputs
+    #[serde(default, with = "serde_bytes", skip_serializing_if = "Option::is_none")]
+    reference_doc: Option<Vec<u8>>,
+    /// Passed to pandoc as `--bibliography` along with `--citeproc`, only set for PDF and DOCX outputs
+    #[serde(default, with = "serde_bytes", skip_serializing_if = "Option::is_none")]
+    bibliography: Option<Vec<u8>>,
+    /// Passed to pandoc as `--resource-path`, relative to the directory the input was extracted to.
+    /// Unset for single-file inputs.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    resource_path: Option<String>,
+    /// Where the bot caches the output, not sent to the worker
+    #[serde(skip)]
+    cache_key: Option<CacheKey>,
+}
+
+/// Key of the cached output of converting `input` as described by the other arguments,
+/// `None` for dry runs, which have no output
+fn output_cache_key(
+    input: &[u8],
+    from_filetype: &str,
+    to_filetype: &str,
+    options: &JobOptions,
+) -> Option<CacheKey> {
+    if options.dry_run {
+        return None;
+    }
+    // Renaming doesn't change the output. Going through a `Value` sorts the metadata keys.
+    let options = JobOptions {
+        output_filename_stem: None,
+        ..options.clone()
+    };
+    let settings = serde_json::to_vec(&serde_json::to_value(options).ok()?).ok()?;
+    Some(OutputCache::key(&[
+        input,
+        from_filetype.as_bytes(),
+        to_filetype.as_bytes(),
+        &settings,
+    ]))
+}
+
+/// `resource_path` of the requests converting from `from_filetype`
+fn resource_path(from_filetype: &str) -> Option<String> {
+    // Archives are converted with their extraction directory as the base for relative links
+    (from_filetype == "zip").then(|| ".".to_owned())
+}
+
+/// Files chosen as options, each only sent along to the outputs that use it
+struct OptionFiles {
+    cover_image: Option<Vec<u8>>,
+    reference_doc: Option<Vec<u8>>,
+    bibliography: Option<Vec<u8>>,
+}
+
+/// What the jobs converting an input to each of the chosen formats have in common
+struct JobSource<'a> {
+    input: &'a StoredInput,
+    /// What the input consists of, to key the cached outputs by
+    contents: &'a [u8],
+    options: &'a JobOptions,
+    option_files: &'a OptionFiles,
+}
+
+/// The job `job_id` converting the input of `source` to `to_filetype`.
+/// `job_input` carries or points to the job's own copy of the input.
+fn convert_request(
+    job_id: String,
+    chat_id: ChatId,
+    job_input: JobFile,
+    to_filetype: String,
+    source: &JobSource,
+) -> ConvertRequest {
+    let JobSource {
+        input,
+        contents,
+        options,
+        option_files,
+    } = *source;
+    ConvertRequest {
+        cover_image: option_files
+            .cover_image
+            .clone()
+            .filter(|_| to_filetype == "epub"),
+        reference_doc: option_files
+            .reference_doc
+            .clone()
+            .filter(|_| to_filetype == "docx"),
+        bibliography: option_files
+            .bibliography
+            .clone()
+            .filter(|_| BIBLIOGRAPHY_FILETYPES.contains(&to_filetype.as_str())),
+        job_id,
+        chat_id: chat_id.0,
+        input: job_input,
+        file_id: input.file_id.clone(),
+        original_filename: input.original_filename.clone(),
+        resource_path: resource_path(&input.from_filetype),
+        cache_key: output_cache_key(contents, &input.from_filetype, &to_filetype, options),
+        from_filetype: input.from_filetype.clone(),
+        to_filetype,
+        options: options.clone(),
+    }
+}
+
+/// A file exchanged with the worker.
+/// Small files are embedded, large ones are passed by path on the shared volume.
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(untagged)]
+enum JobFile {
+    Inline {
         #[serde(with = "serde_bytes")]
         file: Vec<u8>,
+    },
+    External {
+        path: String,
+    },
+}
+
+/// A named auxiliary output of a conversion
+#[derive(Serialize, Deserialize, Debug)]
+struct OutputFile {
+    /// Path of the file relative to the main output
+    name: String,
+    #[serde(flatten)]
+    file: JobFile,
+}
+
+/// Tagged with a `status` field of `"success"`, `"failure"` or `"validated"`
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(tag = "status", rename_all = "lowercase")]
+enum ConvertResponse {
+    Success {
+        job_id: String,
+        chat_id: i64,
+        #[serde(flatten)]
+        output: JobFile,
         to_filetype: String,
+        original_filename: Option<String>,
+        /// Auxiliary outputs such as images referenced by LaTeX.
+        /// If any are present, everything is sent together as a zip archive.
+        #[serde(default)]
+        extra_files: Vec<OutputFile>,
+        /// Warnings pandoc printed while converting, sent after the output
+        #[serde(default)]
+        warnings: Vec<String>,
     },
     Failure {
+        job_id: String,
         chat_id: i64,
         error_msg: String,
     },
+    /// The input of a dry run parsed fine, parse errors come back as `Failure`
+    Validated { job_id: String, chat_id: i64 },
+}
+
+/// Decode a response of the worker.
+/// Workers from before the `status` field are still understood, their status is told by `error_msg`.
+fn decode_response(data: &[u8]) -> Result<ConvertResponse, bson::de::Error> {
+    let e = match bson::from_slice(data) {
+        Ok(res) => return Ok(res),
+        Err(e) => e,
+    };
+    let mut doc: bson::Document = bson::from_slice(data)?;
+    if doc.contains_key("status") {
+        return Err(e);
+    }
+    let status = if doc.contains_key("error_msg") {
+        "failure"
+    } else {
+        "success"
+    };
+    doc.insert("status", status);
+    bson::from_document(doc)
+}
+
+impl ConvertResponse {
+    fn job_id(&self) -> &str {
+        match self {
+            ConvertResponse::Success { job_id, .. }
+            | ConvertResponse::Failure { job_id, .. }
+            | ConvertResponse::Validated { job_id, .. } => job_id,
+        }
+    }
+
+    fn chat_id(&self) -> i64 {
+        match self {
+            ConvertResponse::Success { chat_id, .. }
+            | ConvertResponse::Failure { chat_id, .. }
+            | ConvertResponse::Validated { chat_id, .. } => *chat_id,
+        }
+    }
+}
+
+/// Whether `msg` is a document or pasted input to convert, rather than a command
+fn is_input(msg: Message) -> bool {
+    msg.document().is_some() || is_pasted_input(msg)
+}
+
+/// Whether `msg` is pasted contents or a link to convert, rather than a command
+fn is_pasted_input(msg: Message) -> bool {
+    msg.text().map_or(false, |text| !text.starts_with('/'))
+}
+
+/// Like `is_input`, for steps answered with text, where only a link is taken as pasted input
+fn is_document_or_link(msg: Message) -> bool {
+    msg.document().is_some() || msg.text().and_then(pasted_url).is_some()
+}
+
+/// Sending the input while an option is asked for skips the remaining ones, keeping their defaults
+async fn skip_to_input(
+    bot: Bot,
+    msg: Message,
+    dialogue: MyDialogue,
+    submitter: Arc<JobSubmitter>,
+    recent_uploads: Arc<RecentUploads>,
+    albums: Arc<Albums>,
+    (from_filetype, to_filetypes, options, _reused_input): (
+        String,
+        Vec<String>,
+        JobOptions,
+        Option<StoredInput>,
+    ),
+) -> HandlerResult {
+    info!("Skipping the remaining options for the received input");
+    dialogue
+        .update(State::ReceiveInputFile {
+            from_filetype: from_filetype.clone(),
+            to_filetypes: to_filetypes.clone(),
+            options: options.clone(),
+        })
+        .await?;
+    receive_input_file(
+        bot,
+        msg,
+        dialogue,
+        submitter,
+        recent_uploads,
+        albums,
+        (from_filetype, to_filetypes, options),
+    )
+    .await
+}
+
+/// `skip_to_input` before the PDF engine was picked, which falls back to the preferred one
+async fn skip_pdf_engine_to_input(
+    bot: Bot,
+    msg: Message,
+    dialogue: MyDialogue,
+    submitter: Arc<JobSubmitter>,
+    recent_uploads: Arc<RecentUploads>,
+    albums: Arc<Albums>,
+    (from_filetype, to_filetypes, reused_input): (String, Vec<String>, Option<StoredInput>),
+) -> HandlerResult {
+    let options = submitter
+        .prefs
+        .clone()
+        .get_dialogue(msg.chat.id)
+        .await?
+        .unwrap_or_default()
+        .initial_options(&to_filetypes);
+    skip_to_input(
+        bot,
+        msg,
+        dialogue,
+        submitter,
+        recent_uploads,
+        albums,
+        (from_filetype, to_filetypes, options, reused_input),
+    )
+    .await
+}
+
+#[instrument(skip_all, fields(chat_id = msg.chat.id.0))]
+async fn receive_input_file(
+    bot: Bot,
+    msg: Message,
+    dialogue: MyDialogue,
+    submitter: Arc<JobSubmitter>,
+    recent_uploads: Arc<RecentUploads>,
+    albums: Arc<Albums>,
+    (from_filetype, to_filetypes, options): (String, Vec<String>, JobOptions),
+) -> HandlerResult {
+    // Refused before anything is downloaded or fetched
+    if submitter.maintenance_mode.is_enabled() {
+        bot.send_message(msg.chat.id, MAINTENANCE_TEXT)
+            .send()
+            .await?;
+        return Ok(());
+    }
+
+    let make_fail_msg = || {
+        bot.send_message(
+            msg.chat.id,
+            "Send me the file to be converted, or paste its contents.",
+        )
+    };
+
+    if let Some(doc) = msg.document() {
+        info!(
+            "Received document with name {:?} and id {}",
+            doc.file_name, doc.file_id
+        );
+
+        if is_empty_upload(doc.file_size.into()) {
+            bot.send_message(msg.chat.id, EMPTY_FILE_TEXT)
+                .send()
+                .await?;
+            return Ok(());
+        }
+
+        let input = StoredInput {
+            file_id: doc.file_id.clone(),
+            file_size: doc.file_size.into(),
+            original_filename: doc.file_name.clone(),
+            from_filetype,
+        };
+
+        // The album is submitted once the rest of it had time to arrive.
+        // Asking about each mismatching extension would interrupt it, so only the contents are checked.
+        if let Some(media_group_id) = msg.media_group_id() {
+            if !recent_uploads.try_insert(msg.chat.id.0, &input.file_id) {
+                info!("Ignoring duplicate submission of {}", input.file_id);
+                return Ok(());
+            }
+            if albums.push(msg.chat.id.0, media_group_id, input) {
+                tokio::spawn(submit_album_later(
+                    ChatContext::of(&bot, &dialogue, &msg),
+                    submitter.clone(),
+                    albums.clone(),
+                    media_group_id.to_owned(),
+                    to_filetypes,
+                    options,
+                ));
+            }
+            return Ok(());
+        }
+
+        if let Some(text) = extension_mismatch_text(&input) {
+            info!("Asking to confirm mismatching document: {text}");
+            bot.send_message(msg.chat.id, text).send().await?;
+            dialogue
+                .update(State::ConfirmMismatch {
+                    input,
+                    to_filetypes,
+                    options,
+                })
+                .await?;
+            return Ok(());
+        }
+
+        if !recent_uploads.try_insert(msg.chat.id.0, &input.file_id) {
+            info!("Ignoring duplicate submission of {}", input.file_id);
+            bot.send_message(msg.chat.id, "Already processing that file.")
+                .send()
+                .await?;
+            return Ok(());
+        }
+
+        submitter
+            .submit_document(
+                &ChatContext::of(&bot, &dialogue, &msg),
+                input,
+                to_filetypes,
+                options,
+            )
+            .await?;
+    } else if let Some(text) = msg
+        .text()
+        .filter(|text| !text.trim().is_empty() && !text.starts_with('/'))
+    {
+        let input = StoredInput {
+            file_id: format!("text-{}-{}", msg.chat.id.0, msg.id),
+            file_size: text.len() as u64,
+            original_filename: None,
+            from_filetype,
+        };
+        submitter
+            .submit_text(
+                &ChatContext::of(&bot, &dialogue, &msg),
+                text,
+                input,
+                to_filetypes,
+                options,
+            )
+            .await?;
+    } else if let Some(kind) = media_kind(&msg) {
+        let text =
+            format!("Please send your {from_filetype} as a document (file), not as a {kind}.");
+        bot.send_message(msg.chat.id, text).send().await?;
+    } else {
+        make_fail_msg().send().await?;
+    }
+
+    Ok(())
 }
 
-async fn receive_input_file(
+/// The URL `text` consists of, if it's a single link like `https://example.com/doc.md`
+fn pasted_url(text: &str) -> Option<Url> {
+    let text = text.trim();
+    if text.contains(char::is_whitespace) || !text.contains("://") {
+        return None;
+    }
+    Url::parse(text).ok()
+}
+
+/// Download the input at `url`, telling the user why if that fails.
+/// Returns the contents and the file name to convert them under.
+async fn fetch_input(
+    bot: &Bot,
+    chat_id: ChatId,
+    config: &Config,
+    url: &Url,
+    from_filetype: &str,
+) -> Result<Option<(Vec<u8>, Option<String>)>> {
+    let text = match fetch::fetch(url, config.max_file_size_bytes).await {
+        Ok(fetched) => {
+            let kind = infer::get(&fetched.contents)
+                .filter(|kind| kind.matcher_type() != MatcherType::Text);
+            if from_filetype == "zip" && kind.map(|kind| kind.extension()) != Some("zip") {
+                "The link doesn't point to a zip archive, please send a valid one.".to_owned()
+            } else if fetched.contents.is_empty() {
+                EMPTY_FILE_TEXT.to_owned()
+            } else {
+                if let Some(kind) = kind.filter(|&kind| !matches_filetype(kind, from_filetype)) {
+                    let text = format!(
+                        "This file looks like <b>{}</b> rather than <b>{from_filetype}</b>. \
+                         Converting it anyway, but it will probably fail.",
+                        kind.extension(),
+                    );
+                    bot.send_message(chat_id, text)
+                        .parse_mode(ParseMode::Html)
+                        .send()
+                        .await?;
+                }
+                return Ok(Some((fetched.contents, fetched.file_name)));
+            }
+        }
+        Err(FetchError::Scheme) => "Only http and https links are supported.".to_owned(),
+        Err(FetchError::Forbidden) => "That link can't be fetched.".to_owned(),
+        Err(FetchError::TooLarge) => format!(
+            "The linked file is too large, the limit is {} MB.",
+            config.max_file_size_bytes / BYTES_PER_MB
+        ),
+        Err(FetchError::Failed(e)) => {
+            warn!("Failed to fetch {url}: {e:?}");
+            "Couldn't download the linked file, please check the link or send the file instead."
+                .to_owned()
+        }
+    };
+    bot.send_message(chat_id, text).send().await?;
+    Ok(None)
+}
+
+/// Ask to confirm a document whose extension isn't that of the chosen source format,
+/// or `None` if it matches or there's no extension to go by
+fn extension_mismatch_text(input: &StoredInput) -> Option<String> {
+    let file_name = input.original_filename.as_ref()?;
+    let extension = Path::new(file_name).extension()?.to_str()?.to_lowercase();
+    if filetype_to_extension(&input.from_filetype) == Some(extension.as_str()) {
+        return None;
+    }
+
+    let text = match extension_to_from_filetype(&extension) {
+        Some(_) => format!(
+            "This looks like a .{extension} but you chose {} — proceed anyway? /yes",
+            input.from_filetype
+        ),
+        None => format!(
+            "This looks like a .{extension}, which isn't a supported input format ({}). \
+             Convert it as {} anyway? /yes",
+            FROM_FILETYPES.join(", "),
+            input.from_filetype
+        ),
+    };
+    Some(text)
+}
+
+/// Convert the document despite its extension
+async fn confirm_mismatch(
     bot: Bot,
     msg: Message,
     dialogue: MyDialogue,
-    amqp_conn: Arc<lapin::Connection>,
-    (from_filetype, to_filetype): (String, String),
+    submitter: Arc<JobSubmitter>,
+    (input, to_filetypes, options): (StoredInput, Vec<String>, JobOptions),
 ) -> HandlerResult {
-    let make_fail_msg = || {
-        let keyboard = make_to_keyboard();
+    submitter
+        .submit_document(
+            &ChatContext::of(&bot, &dialogue, &msg),
+            input,
+            to_filetypes,
+            options,
+        )
+        .await
+}
 
-        let text = format!("Send me the file to be converted.");
-        bot.send_message(msg.chat.id, text).reply_markup(keyboard)
-    };
+/// Take another document in place of the mismatching one, or repeat the question
+async fn reconsider_mismatch(
+    bot: Bot,
+    msg: Message,
+    dialogue: MyDialogue,
+    submitter: Arc<JobSubmitter>,
+    recent_uploads: Arc<RecentUploads>,
+    albums: Arc<Albums>,
+    (input, to_filetypes, options): (StoredInput, Vec<String>, JobOptions),
+) -> HandlerResult {
+    if msg.document().is_some() {
+        return receive_input_file(
+            bot,
+            msg,
+            dialogue,
+            submitter,
+            recent_uploads,
+            albums,
+            (input.from_filetype, to_filetypes, options),
+        )
+        .await;
+    }
 
-    let make_success_msg = || {
-        bot.send_message(msg.chat.id, "The conversion is being performed ...")
-            .parse_mode(ParseMode::Html)
-    };
+    bot.send_message(
+        msg.chat.id,
+        "Send /yes to convert the document anyway, or send another one.",
+    )
+    .send()
+    .await?;
+    Ok(())
+}
 
-    if let Some(doc) = msg.document() {
-        info!(
-            "Received document with name {:?} and id {}",
-            doc.file_name, doc.file_id
-        );
+const EMPTY_FILE_TEXT: &str = "That file appears to be empty, please send a valid document.";
 
-        /* Download file to disk */
-        // Not really file path on the FS, but this is how Telegram name their API
-        let TgFile { file_path, .. } = bot.get_file(&doc.file_id).send().await?;
+/// Whether an upload is empty going by the `file_size` Telegram reported, which is only a hint
+fn is_empty_upload(file_size: u64) -> bool {
+    file_size == 0
+}
 
-        let input_file_path = path_for_input_file(&doc.file_id);
+/// Whether the file downloaded to `path` is empty
+async fn is_empty_file(path: &Path) -> Result<bool> {
+    Ok(tokio::fs::metadata(path).await?.len() == 0)
+}
+
+/// Download the small Telegram file `file_id` into memory
+async fn download_to_memory(bot: &Bot, file_id: &str) -> Result<Vec<u8>> {
+    let TgFile { file_path, .. } = bot.get_file(file_id).send().await?;
+    let mut contents = vec![];
+    bot.download_file(&file_path, &mut contents).await?;
+    Ok(contents)
+}
+
+/// Download the Telegram file `file_id` to `path` and sync it
+async fn download_document(bot: &Bot, file_id: &str, path: &Path) -> Result<()> {
+    // Not really file path on the FS, but this is how Telegram name their API
+    let TgFile { file_path, .. } = bot.get_file(file_id).send().await?;
+
+    let mut file = File::create(path).await?;
+    bot.download_file(&file_path, &mut file).await?;
+    file.sync_all().await?;
+    Ok(())
+}
+
+/// Whether `e` is Telegram refusing to let bots download a file over its 20 MB limit
+fn is_file_too_big(e: &anyhow::Error) -> bool {
+    e.downcast_ref::<RequestError>()
+        .map_or(false, |e| e.to_string().contains("file is too big"))
+}
+
+const SNIFF_LEN: u64 = 8192;
+
+/// Detect the type of the file at `path` from its magic bytes.
+/// Text formats have none to speak of, so they're reported as `None` like unknown types.
+async fn sniff_file_type(path: &Path) -> Result<Option<infer::Type>> {
+    let mut header = vec![];
+    File::open(path)
+        .await?
+        .take(SNIFF_LEN)
+        .read_to_end(&mut header)
+        .await?;
+    Ok(infer::get(&header).filter(|kind| kind.matcher_type() != MatcherType::Text))
+}
+
+/// Whether a sniffed file type is plausible for `filetype`
+fn matches_filetype(kind: infer::Type, filetype: &str) -> bool {
+    match kind.extension() {
+        // Office documents and EPUBs are zip archives that may not be recognized any further
+        "zip" => matches!(filetype, "docx" | "epub" | "zip"),
+        extension => filetype_to_extension(filetype) == Some(extension),
+    }
+}
+
+/// Name of the non-document media attached to `msg`, if any
+fn media_kind(msg: &Message) -> Option<&'static str> {
+    if msg.photo().is_some() {
+        Some("photo")
+    } else if msg.sticker().is_some() {
+        Some("sticker")
+    } else if msg.video().is_some() {
+        Some("video")
+    } else if msg.animation().is_some() {
+        Some("GIF")
+    } else if msg.audio().is_some() {
+        Some("audio file")
+    } else if msg.voice().is_some() {
+        Some("voice message")
+    } else if msg.video_note().is_some() {
+        Some("video message")
+    } else {
+        None
+    }
+}
+
+/// Round `estimate` to seconds, or to minutes once it's a few of them
+fn format_estimate(estimate: Duration) -> String {
+    let secs = estimate.as_secs().max(1);
+    if secs < 120 {
+        format!("{secs}s")
+    } else {
+        format!("{} min", (secs + 30) / 60)
+    }
+}
+
+/// Submit the album `media_group_id` once the rest of its documents had time to arrive
+async fn submit_album_later(
+    chat: ChatContext,
+    submitter: Arc<JobSubmitter>,
+    albums: Arc<Albums>,
+    media_group_id: String,
+    to_filetypes: Vec<String>,
+    options: JobOptions,
+) {
+    tokio::time::sleep(ALBUM_WAIT).await;
+    let chat_id = chat.id();
+    let inputs = albums.take(chat_id.0, &media_group_id);
+    info!(
+        "Submitting album {media_group_id} of {} documents",
+        inputs.len()
+    );
+    if let Err(e) = submitter
+        .submit_album(&chat, inputs, to_filetypes, options)
+        .await
+    {
+        warn!("Failed to submit album {media_group_id}: {e}");
+        reporting::report_error(e.as_ref(), Some(chat_id.0), None);
+    }
+}
+
+/// The chat a submission comes from, and what's needed to answer it
+#[derive(Clone)]
+struct ChatContext {
+    bot: Bot,
+    dialogue: MyDialogue,
+    /// Language of the user who submitted it
+    messages: &'static Messages,
+}
+
+impl ChatContext {
+    /// The chat `msg` was sent in, answered in the language of its sender
+    fn of(bot: &Bot, dialogue: &MyDialogue, msg: &Message) -> Self {
+        Self {
+            bot: bot.clone(),
+            dialogue: dialogue.clone(),
+            messages: user_messages(msg.from()),
+        }
+    }
+
+    fn id(&self) -> ChatId {
+        self.dialogue.chat_id()
+    }
+}
+
+/// Shared state needed to submit jobs, injected into the handlers that do
+struct JobSubmitter {
+    publisher: Arc<dyn JobPublisher>,
+    pending_jobs: PendingJobs,
+    metrics: Arc<Metrics>,
+    rate_limiter: RateLimiter,
+    heartbeat: Arc<WorkerHeartbeat>,
+    last_inputs: LastInputStorage,
+    prefs: PrefsStorage,
+    /// Bounds the documents being downloaded and published at once
+    download_permits: Semaphore,
+    maintenance_mode: Arc<MaintenanceMode>,
+    output_cache: Arc<OutputCache>,
+    last_outputs: Arc<LastOutputs<i64>>,
+    history: Arc<History>,
+    config: Arc<Config>,
+}
+
+impl JobSubmitter {
+    /// Download the document `input` and publish a job converting it to each of `to_filetypes`.
+    /// On success it's remembered as the chat's last input for /again.
+    async fn submit_document(
+        &self,
+        chat: &ChatContext,
+        input: StoredInput,
+        to_filetypes: Vec<String>,
+        options: JobOptions,
+    ) -> HandlerResult {
+        // Reject before fetching anything from Telegram
+        if let Some(text) = self.check_submission(chat.id().0, input.file_size, to_filetypes.len())
+        {
+            info!("Rejected document with id {}: {text}", input.file_id);
+            chat.bot.send_message(chat.id(), text).send().await?;
+            return Ok(());
+        }
+
+        self.download_and_submit(chat, input, to_filetypes, options, true)
+            .await
+    }
+
+    /// Submit the documents of an album with the same formats and options.
+    /// The album is checked against the limits as a single submission, and announced by one message.
+    async fn submit_album(
+        &self,
+        chat: &ChatContext,
+        inputs: Vec<StoredInput>,
+        to_filetypes: Vec<String>,
+        options: JobOptions,
+    ) -> HandlerResult {
+        let (bot, chat_id, messages) = (&chat.bot, chat.id(), chat.messages);
+        let largest = inputs
+            .iter()
+            .map(|input| input.file_size)
+            .max()
+            .unwrap_or(0);
+        let jobs = inputs.len() * to_filetypes.len();
+        if let Some(text) = self.check_submission(chat_id.0, largest, jobs) {
+            info!("Rejected album of {} documents: {text}", inputs.len());
+            bot.send_message(chat_id, text).send().await?;
+            return Ok(());
+        }
+
+        let mut text = (messages.converting_album)(inputs.len());
+        if !self.heartbeat.is_alive() {
+            text = format!("{text} {}", messages.album_waiting_for_workers);
+        }
+        bot.send_message(chat_id, text).send().await?;
+
+        for input in inputs {
+            self.download_and_submit(chat, input, to_filetypes.clone(), options.clone(), false)
+                .await?;
+        }
+        Ok(())
+    }
+
+    /// `submit_document` for input already checked against the limits.
+    /// The jobs are announced with their place in the queue only if `announce` is set.
+    async fn download_and_submit(
+        &self,
+        chat: &ChatContext,
+        input: StoredInput,
+        to_filetypes: Vec<String>,
+        options: JobOptions,
+        announce: bool,
+    ) -> HandlerResult {
+        let (bot, chat_id, messages, dialogue) =
+            (&chat.bot, chat.id(), chat.messages, &chat.dialogue);
+        // Held until the jobs are published, so the input doesn't pile up on disk and in memory
+        let permit = self.acquire_download_permit(chat).await?;
+
+        /* Download file to disk */
+        let input_file_path = self.config.input_file_path(chat_id.0, &input.file_id);
 
         // Create base path for the input file
         tokio::fs::create_dir_all(
@@ -346,71 +5694,794 @@ async fn receive_input_file(
         )
         .await?;
 
-        // Download the file and sync
-        let mut file = File::create(&input_file_path).await?;
-        bot.download_file(&file_path, &mut file).await?;
-        file.sync_all().await?;
+        // Let the user retry just the upload if Telegram fails us
+        if let Err(e) = download_document(bot, &input.file_id, &input_file_path).await {
+            warn!(
+                "Failed to download document with id {}: {e:?}",
+                input.file_id
+            );
+            let text = if is_file_too_big(&e) {
+                "Telegram won't let bots download files over 20 MB — \
+                 please send a smaller file or a download link."
+            } else {
+                "Couldn't download your file, please send it again."
+            };
+            bot.send_message(chat_id, text).send().await?;
+            dialogue
+                .update(State::ReceiveInputFile {
+                    from_filetype: input.from_filetype,
+                    to_filetypes,
+                    options,
+                })
+                .await?;
+            return Ok(());
+        }
 
         info!(
             "Downloaded document with name {:?} and id {}",
-            doc.file_name, doc.file_id
+            input.original_filename, input.file_id
         );
 
-        make_success_msg().send().await?;
+        // Telegram's file size is only a hint, so check what actually arrived
+        if is_empty_file(&input_file_path).await? {
+            info!("Document with id {} is empty", input.file_id);
+            bot.send_message(chat_id, EMPTY_FILE_TEXT).send().await?;
+            tokio::fs::remove_file(&input_file_path).await?;
+            dialogue
+                .update(State::ReceiveInputFile {
+                    from_filetype: input.from_filetype,
+                    to_filetypes,
+                    options,
+                })
+                .await?;
+            return Ok(());
+        }
+
+        // Catch binaries labeled as another format before pandoc fails on them cryptically
+        let sniffed = sniff_file_type(&input_file_path).await;
+        // The worker can't extract anything else, so a fake archive isn't worth queuing
+        if input.from_filetype == "zip"
+            && !matches!(&sniffed, Ok(Some(kind)) if kind.extension() == "zip")
+        {
+            info!("Document with id {} is not a zip archive", input.file_id);
+            bot.send_message(
+                chat_id,
+                "This file is not a zip archive, please send a valid one.",
+            )
+            .send()
+            .await?;
+            tokio::fs::remove_file(&input_file_path).await?;
+            dialogue
+                .update(State::ReceiveInputFile {
+                    from_filetype: input.from_filetype,
+                    to_filetypes,
+                    options,
+                })
+                .await?;
+            return Ok(());
+        }
+        match sniffed {
+            Ok(Some(kind)) if !matches_filetype(kind, &input.from_filetype) => {
+                info!(
+                    "Document with id {} looks like {} rather than {}",
+                    input.file_id,
+                    kind.extension(),
+                    input.from_filetype
+                );
+                let text = format!(
+                    "This file looks like <b>{}</b> rather than <b>{}</b>. \
+                     Converting it anyway, but it will probably fail.",
+                    kind.extension(),
+                    input.from_filetype
+                );
+                bot.send_message(chat_id, text)
+                    .parse_mode(ParseMode::Html)
+                    .send()
+                    .await?;
+            }
+            Ok(_) => {}
+            Err(e) => warn!("Failed to sniff document with id {}: {e:?}", input.file_id),
+        }
+
+        let option_files = self.download_option_files(chat, &options).await?;
+
+        let mut prefs = self
+            .prefs
+            .clone()
+            .get_dialogue(chat_id)
+            .await?
+            .unwrap_or_default();
+        prefs.record(&options, &to_filetypes);
+
+        /* Send to job queue */
+        let contents = tokio::fs::read(&input_file_path).await?;
+        let source = JobSource {
+            input: &input,
+            contents: &contents,
+            options: &options,
+            option_files: &option_files,
+        };
+        let reqs = self.job_requests(chat_id, &source, to_filetypes).await?;
+        self.submit_jobs(chat, Some(&input), reqs, announce).await?;
+        drop(permit);
+
         dialogue.update(State::Start).await?;
 
+        // The request carries or points to its own copy of the file, so this one is no longer needed
+        if let Err(e) = tokio::fs::remove_file(&input_file_path).await {
+            warn!("Failed to remove input file {input_file_path:?}: {e}");
+        }
+
+        self.last_inputs
+            .clone()
+            .update_dialogue(chat_id, input)
+            .await?;
+        // Dry runs only check the input, their options aren't a choice worth keeping
+        if !options.dry_run {
+            self.prefs.clone().update_dialogue(chat_id, prefs).await?;
+        }
+
+        Ok(())
+    }
+
+    /// Publish jobs converting the pasted `text`, or the file at the link it consists of,
+    /// to each of `to_filetypes`. `input` describes the text, and is updated to the fetched file.
+    async fn submit_text(
+        &self,
+        chat: &ChatContext,
+        text: &str,
+        mut input: StoredInput,
+        to_filetypes: Vec<String>,
+        options: JobOptions,
+    ) -> HandlerResult {
+        let (bot, chat_id) = (&chat.bot, chat.id());
+        let url = pasted_url(text);
+        if url.is_none() && input.from_filetype == "zip" {
+            bot.send_message(chat_id, "Send the zip archive as a file or a link to it.")
+                .send()
+                .await?;
+            return Ok(());
+        }
+
+        // Linked files are only checked against the size limit while they're fetched
+        let size = if url.is_some() { 0 } else { input.file_size };
+        if let Some(text) = self.check_submission(chat_id.0, size, to_filetypes.len()) {
+            info!("Rejected text input: {text}");
+            bot.send_message(chat_id, text).send().await?;
+            return Ok(());
+        }
+
+        // Held until the jobs are published, like for documents
+        let permit = self.acquire_download_permit(chat).await?;
+        let contents = match url {
+            Some(url) => {
+                info!("Received URL input {url}");
+                match fetch_input(bot, chat_id, &self.config, &url, &input.from_filetype).await? {
+                    Some((contents, file_name)) => {
+                        input.original_filename = file_name;
+                        input.file_size = contents.len() as u64;
+                        contents
+                    }
+                    None => return Ok(()),
+                }
+            }
+            None => {
+                info!("Received text input of {} bytes", text.len());
+                input.original_filename = Some(format!(
+                    "message.{}",
+                    filetype_to_extension(&input.from_filetype).unwrap_or("txt")
+                ));
+                text.as_bytes().to_vec()
+            }
+        };
+
+        let option_files = self.download_option_files(chat, &options).await?;
+        let source = JobSource {
+            input: &input,
+            contents: &contents,
+            options: &options,
+            option_files: &option_files,
+        };
+
         /* Send to job queue */
-        let binary = tokio::fs::read(&input_file_path).await?;
-        let channel = amqp_conn.create_channel().await?;
-
-        // Create request and convert to BSON
-        let req = {
-            let req = ConvertRequest {
-                chat_id: msg.chat.id.0,
-                file: binary,
-                file_id: doc.file_id.clone(),
-                from_filetype,
-                to_filetype,
+        let reqs = self.job_requests(chat_id, &source, to_filetypes).await?;
+        self.submit_jobs(chat, None, reqs, true).await?;
+        drop(permit);
+
+        chat.dialogue.update(State::Start).await?;
+        Ok(())
+    }
+
+    /// Wait for a free slot to download an input in, telling the user if there's none right away
+    async fn acquire_download_permit(&self, chat: &ChatContext) -> Result<SemaphorePermit<'_>> {
+        match self.download_permits.try_acquire() {
+            Ok(permit) => Ok(permit),
+            Err(_) => {
+                chat.bot
+                    .send_message(chat.id(), "Queuing your upload...")
+                    .send()
+                    .await?;
+                Ok(self.download_permits.acquire().await?)
+            }
+        }
+    }
+
+    /// The jobs converting the input of `source` to each of `to_filetypes`.
+    /// Each job gets its own copy of the input, since the worker consumes shared files,
+    /// so large inputs are copied to the shared volume once per job.
+    async fn job_requests(
+        &self,
+        chat_id: ChatId,
+        source: &JobSource<'_>,
+        to_filetypes: Vec<String>,
+    ) -> Result<Vec<ConvertRequest>> {
+        let mut reqs = vec![];
+        for to_filetype in to_filetypes {
+            let job_id = Uuid::new_v4().to_string();
+            let job_input = match self
+                .config
+                .shared_file_path_for(&job_id, source.input.file_size)
+            {
+                Some(shared_file_path) => {
+                    if let Err(e) = tokio::fs::write(&shared_file_path, source.contents).await {
+                        for req in &reqs {
+                            remove_shared_input(req).await;
+                        }
+                        return Err(e.into());
+                    }
+                    JobFile::External {
+                        path: shared_file_path
+                            .to_str()
+                            .context("Failed to convert shared file path to str")?
+                            .to_owned(),
+                    }
+                }
+                None => JobFile::Inline {
+                    file: source.contents.to_vec(),
+                },
             };
-            bson::to_vec(&req)?
+            reqs.push(convert_request(
+                job_id,
+                chat_id,
+                job_input,
+                to_filetype,
+                source,
+            ));
+        }
+        Ok(reqs)
+    }
+
+    /// Fetch the files chosen in `options`, leaving out those that can't be downloaded
+    async fn download_option_files(
+        &self,
+        chat: &ChatContext,
+        options: &JobOptions,
+    ) -> Result<OptionFiles> {
+        let (bot, chat_id) = (&chat.bot, chat.id());
+        Ok(OptionFiles {
+            cover_image: self
+                .download_option_file(bot, chat_id, &options.cover_file_id, "cover image")
+                .await?,
+            reference_doc: self
+                .download_option_file(
+                    bot,
+                    chat_id,
+                    &options.reference_doc_file_id,
+                    "reference document",
+                )
+                .await?,
+            bibliography: self
+                .download_option_file(bot, chat_id, &options.bibliography_file_id, "bibliography")
+                .await?,
+        })
+    }
+
+    /// Fetch a file chosen as an option, such as the EPUB cover, if there's one.
+    /// A file that can't be downloaded is left out rather than failing the conversion,
+    /// telling the user about the `name`d file.
+    async fn download_option_file(
+        &self,
+        bot: &Bot,
+        chat_id: ChatId,
+        file_id: &Option<String>,
+        name: &str,
+    ) -> Result<Option<Vec<u8>>> {
+        let file_id = match file_id {
+            Some(file_id) => file_id,
+            None => return Ok(None),
         };
 
-        // Send to queue
-        channel
-            .basic_publish(
-                "",
-                "pandoc-bot-jobs",
-                BasicPublishOptions::default(),
+        match download_to_memory(bot, file_id).await {
+            Ok(contents) => Ok(Some(contents)),
+            Err(e) => {
+                warn!("Failed to download {name} with id {file_id}: {e:?}");
+                bot.send_message(
+                    chat_id,
+                    format!("Couldn't download the {name}, converting without it."),
+                )
+                .send()
+                .await?;
+                Ok(None)
+            }
+        }
+    }
+
+    /// Publish `reqs` and tell the user whether they got queued.
+    /// The outputs are delivered with `messages`, failures can be retried if the uploaded `input` is given.
+    async fn submit_jobs(
+        &self,
+        chat: &ChatContext,
+        input: Option<&StoredInput>,
+        reqs: Vec<ConvertRequest>,
+        announce: bool,
+    ) -> HandlerResult {
+        let (bot, chat_id, messages) = (&chat.bot, chat.id(), chat.messages);
+        // Recorded before publishing, so even a quick response finds its entry
+        for req in reqs.iter().filter(|req| !req.options.dry_run) {
+            let entry = HistoryEntry {
+                job_id: req.job_id.clone(),
+                submitted_at: unix_now().as_secs(),
+                from_filetype: req.from_filetype.clone(),
+                to_filetype: req.to_filetype.clone(),
+                outcome: Outcome::Pending,
+            };
+            if let Err(e) = self.history.record(chat_id, entry).await {
+                warn!("Failed to record the conversion in the history: {e}");
+            }
+        }
+
+        let mut published = vec![];
+        let mut reqs = reqs.into_iter();
+        while let Some(req) = reqs.next() {
+            let cached = match self.deliver_cached(bot, chat_id, messages, &req).await {
+                Ok(cached) => cached,
+                Err(e) => {
+                    // No worker will consume the copies of the input of the remaining jobs
+                    for req in reqs.as_slice() {
+                        remove_shared_input(req).await;
+                    }
+                    for req in std::iter::once(&req).chain(reqs.as_slice()) {
+                        self.set_history_outcome(chat_id, &req.job_id, Outcome::Failed)
+                            .await;
+                    }
+                    return Err(e.into());
+                }
+            };
+            if cached {
+                self.set_history_outcome(chat_id, &req.job_id, Outcome::Succeeded)
+                    .await;
+                continue;
+            }
+            if let Err(e) = publish_job(
+                self.publisher.as_ref(),
+                &self.pending_jobs,
+                &self.metrics,
+                &self.config,
+                messages,
+                input,
                 &req,
-                BasicProperties::default(),
             )
-            .await?
+            .await
+            {
+                for req in std::iter::once(&req).chain(reqs.as_slice()) {
+                    remove_shared_input(req).await;
+                    self.set_history_outcome(chat_id, &req.job_id, Outcome::Failed)
+                        .await;
+                }
+                bot.send_message(
+                    chat_id,
+                    "Failed to queue the conversion, please try again later.",
+                )
+                .send()
+                .await?;
+                return Err(e.into());
+            }
+            published.push(req);
+        }
+        let reqs = published;
+        if reqs.is_empty() || !announce {
+            return Ok(());
+        }
+
+        // In the queues the jobs went to. The jobs just published aren't subtracted, since a
+        // worker may already have taken them, and then they're no longer counted.
+        let mut job_queues: Vec<_> = reqs
+            .iter()
+            .map(|req| self.config.queues.queue_for_target(&req.to_filetype))
+            .collect();
+        job_queues.sort_unstable();
+        job_queues.dedup();
+        let mut jobs_ahead = Some(0u32);
+        for job_queue in job_queues {
+            let waiting = self.publisher.jobs_waiting(job_queue).await;
+            jobs_ahead = jobs_ahead
+                .zip(waiting)
+                .map(|(ahead, waiting)| ahead + waiting);
+        }
+        let mut text = match jobs_ahead {
+            Some(jobs_ahead) => format!("Queued — {jobs_ahead} jobs ahead of you."),
+            None => "The conversion is being performed ...".to_owned(),
+        };
+        let estimates: Vec<_> = reqs
+            .iter()
+            .filter_map(|req| {
+                let estimate = self
+                    .metrics
+                    .conversion_durations
+                    .estimate(&req.from_filetype, &req.to_filetype)?;
+                Some(format!(
+                    "~{} for {} → {}",
+                    format_estimate(estimate),
+                    req.from_filetype,
+                    req.to_filetype
+                ))
+            })
+            .collect();
+        if !estimates.is_empty() {
+            text.push_str(&format!(" Typically {}.", estimates.join(", ")));
+        }
+        bot.send_message(chat_id, text).send().await?;
+
+        if !self.heartbeat.is_alive() {
+            bot.send_message(
+                chat_id,
+                "No conversion workers are currently available, \
+                your job is queued and will run when one comes online.",
+            )
+            .send()
             .await?;
-    } else {
-        make_fail_msg().send().await?;
+        }
+
+        Ok(())
+    }
+
+    /// Update the history, which only informs /history, so failing to is just logged
+    async fn set_history_outcome(&self, chat_id: ChatId, job_id: &str, outcome: Outcome) {
+        if let Err(e) = self.history.set_outcome(chat_id, job_id, outcome).await {
+            warn!("Failed to record the outcome in the history: {e}");
+        }
+    }
+
+    /// Send the cached output of `req` if there is one, returning whether it was sent
+    async fn deliver_cached(
+        &self,
+        bot: &Bot,
+        chat_id: ChatId,
+        messages: &'static Messages,
+        req: &ConvertRequest,
+    ) -> Result<bool> {
+        let contents = match req.cache_key.and_then(|key| self.output_cache.get(&key)) {
+            Some(contents) => contents,
+            None => return Ok(false),
+        };
+        info!(
+            "Delivering cached {} output for file with id {}",
+            req.to_filetype, req.file_id
+        );
+        self.metrics.output_cache_hits.inc();
+
+        // The job won't consume its copy of the input
+        remove_shared_input(req).await;
+
+        let force_download = self
+            .prefs
+            .clone()
+            .get_dialogue(chat_id)
+            .await?
+            .unwrap_or_default()
+            .forces_download(&req.to_filetype);
+        let extension = output_extension(&req.to_filetype);
+        let file_name = delivered_filename(
+            req.options.output_filename_stem.as_deref(),
+            req.original_filename.as_deref(),
+            extension,
+        );
+        bot.send_document(
+            chat_id,
+            InputFile::memory(contents.clone()).file_name(file_name.clone()),
+        )
+        .caption((messages.converted)(&req.to_filetype))
+        .parse_mode(ParseMode::Html)
+        .disable_content_type_detection(force_download)
+        .send()
+        .await?;
+        self.last_outputs.insert(
+            chat_id.0,
+            LastOutput {
+                file_name,
+                contents,
+            },
+        );
+        Ok(true)
+    }
+
+    /// Number of jobs from `chat_id` that are still waiting for a response.
+    /// Jobs leave `pending_jobs` once they get one or time out.
+    fn jobs_in_progress(&self, chat_id: i64) -> usize {
+        self.pending_jobs
+            .lock()
+            .unwrap()
+            .values()
+            .filter(|job| job.chat_id == chat_id)
+            .count()
+    }
+
+    /// Why a submission of `size` bytes from `chat_id` for `jobs` jobs can't be accepted,
+    /// if it can't. Accepted submissions count towards the rate limit.
+    fn check_submission(&self, chat_id: i64, size: u64, jobs: usize) -> Option<String> {
+        if self.maintenance_mode.is_enabled() {
+            return Some(MAINTENANCE_TEXT.to_owned());
+        }
+
+        let max_file_size = self.config.max_file_size_bytes;
+        if exceeds_file_size_limit(size, max_file_size) {
+            return Some(format!(
+                "File too large (max {} MB)",
+                max_file_size / BYTES_PER_MB
+            ));
+        }
+
+        // Checked first so a refused submission doesn't count towards the rate limit
+        if self.jobs_in_progress(chat_id) >= self.config.max_concurrent_jobs_per_user {
+            return Some("Please wait for your current conversions to finish.".to_owned());
+        }
+
+        if let Err(wait) = self.rate_limiter.try_submit(chat_id, jobs) {
+            let minutes = (wait.as_secs() + 59) / 60;
+            return Some(format!(
+                "Rate limit reached, try again in {minutes} minutes"
+            ));
+        }
+
+        None
+    }
+}
+
+/// Remove the copy of the input that `req` points to on the shared volume, if any,
+/// for a job that no worker will consume
+async fn remove_shared_input(req: &ConvertRequest) {
+    if let JobFile::External { path } = &req.input {
+        if let Err(e) = tokio::fs::remove_file(path).await {
+            warn!("Failed to remove shared input file {path:?}: {e}");
+        }
+    }
+}
+
+const PUBLISH_MAX_ATTEMPTS: u32 = 5;
+const PUBLISH_INITIAL_BACKOFF: Duration = Duration::from_millis(500);
+
+/// Publish `req` to the job queue and start tracking it.
+/// Transient broker errors are retried with exponential backoff, each attempt on a fresh channel.
+#[instrument(skip_all, fields(job_id = %req.job_id))]
+async fn publish_job(
+    publisher: &dyn JobPublisher,
+    pending_jobs: &PendingJobs,
+    metrics: &Metrics,
+    config: &Config,
+    messages: &'static Messages,
+    input: Option<&StoredInput>,
+    req: &ConvertRequest,
+) -> Result<()> {
+    let payload = bson::to_vec(req)?;
+
+    let mut attempt = 1;
+    let mut backoff = PUBLISH_INITIAL_BACKOFF;
+    let ttl = config.job_timeout;
+    let job_queue = config.queues.queue_for_target(&req.to_filetype);
+    while let Err(e) = publisher.publish(job_queue, &payload, ttl).await {
+        if attempt >= PUBLISH_MAX_ATTEMPTS || !is_retryable(&e) {
+            return Err(e).context(format!("Failed to publish job {}", req.job_id));
+        }
+
+        warn!(
+            "Publish attempt {attempt}/{PUBLISH_MAX_ATTEMPTS} for job {} failed, \
+             retrying in {backoff:?}: {e}",
+            req.job_id
+        );
+        tokio::time::sleep(backoff).await;
+        attempt += 1;
+        backoff *= 2;
     }
 
+    info!(
+        "Published job {} for file with id {}",
+        req.job_id, req.file_id
+    );
+    metrics.jobs_published.inc();
+    pending_jobs.lock().unwrap().insert(
+        req.job_id.clone(),
+        PendingJob {
+            chat_id: req.chat_id,
+            published_at: Instant::now(),
+            messages,
+            dry_run: req.options.dry_run,
+            from_filetype: req.from_filetype.clone(),
+            output_filename_stem: req.options.output_filename_stem.clone(),
+            retry: input.map(|input| RetryableJob {
+                input: input.clone(),
+                to_filetype: req.to_filetype.clone(),
+                options: req.options.clone(),
+            }),
+            cache_key: req.cache_key,
+        },
+    );
+
     Ok(())
 }
 
-const FROM_FILETYPES: &[&str] = &["markdown"];
-const TO_FILETYPES: &[&str] = &["pdf", "latex", "docx", "odt"];
+/// Messages in the language of `user`, if known
+fn user_messages(user: Option<&User>) -> &'static Messages {
+    i18n::messages(user.and_then(|user| user.language_code.as_deref()))
+}
+
+/// Entries double as pandoc reader names and are passed to the worker verbatim
+/// `zip` is an archive of a document and its assets, extracted by the worker
+const FROM_FILETYPES: &[&str] = &[
+    "markdown", "html", "docx", "rst", "latex", "epub", "typst", "zip",
+];
+/// `revealjs` and `beamer` are slide decks, delivered as HTML and PDF respectively
+const TO_FILETYPES: &[&str] = &[
+    "pdf", "latex", "docx", "odt", "epub", "html", "typst", "revealjs", "beamer",
+];
+const PDF_ENGINES: &[&str] = &["pdflatex", "xelatex", "lualatex", "wkhtmltopdf"];
+/// Pandoc readers for the flavors of markdown
+const MARKDOWN_FLAVORS: &[&str] = &["gfm", "commonmark", "markdown"];
+/// Pandoc Markdown extensions users may turn on or off
+const MARKDOWN_EXTENSIONS: &[&str] = &[
+    "pipe_tables",
+    "raw_html",
+    "footnotes",
+    "smart",
+    "tex_math_dollars",
+];
+
+/// Styles built into pandoc's `--highlight-style`
+const HIGHLIGHT_STYLES: &[&str] = &[
+    "pygments",
+    "tango",
+    "espresso",
+    "zenburn",
+    "kate",
+    "monochrome",
+    "breezedark",
+    "haddock",
+];
+
+/// Output formats for which citations can be resolved against an uploaded bibliography
+const BIBLIOGRAPHY_FILETYPES: &[&str] = &["pdf", "docx"];
+
+/// Output formats in which pandoc highlights code blocks
+const HIGHLIGHT_FILETYPES: &[&str] = &["pdf", "latex", "html", "epub", "revealjs", "beamer"];
+
+/// Themes bundled with reveal.js
+const REVEALJS_THEMES: &[&str] = &[
+    "black",
+    "white",
+    "league",
+    "beige",
+    "sky",
+    "night",
+    "serif",
+    "simple",
+    "solarized",
+    "moon",
+];
 
-fn filetype_to_extension(filetype: &str) -> &'static str {
-    match filetype {
+/// Output filetypes each input filetype can be converted to
+const SUPPORTED_CONVERSIONS: &[(&str, &[&str])] = &[
+    (
+        "markdown",
+        &[
+            "pdf", "latex", "docx", "odt", "epub", "html", "typst", "revealjs", "beamer",
+        ],
+    ),
+    ("html", &["pdf", "latex", "docx", "odt", "epub", "typst"]),
+    ("docx", &["pdf", "latex", "odt", "epub", "html", "typst"]),
+    (
+        "rst",
+        &[
+            "pdf", "latex", "docx", "odt", "epub", "html", "typst", "revealjs", "beamer",
+        ],
+    ),
+    (
+        "latex",
+        &["pdf", "docx", "odt", "epub", "html", "typst", "beamer"],
+    ),
+    ("epub", &["pdf", "latex", "docx", "odt", "html", "typst"]),
+    ("typst", &["pdf", "latex", "docx", "odt", "epub", "html"]),
+    (
+        "zip",
+        &[
+            "pdf", "latex", "docx", "odt", "epub", "html", "typst", "revealjs", "beamer",
+        ],
+    ),
+];
+
+fn is_supported_conversion(from: &str, to: &str) -> bool {
+    SUPPORTED_CONVERSIONS
+        .iter()
+        .any(|&(from_filetype, to_filetypes)| from_filetype == from && to_filetypes.contains(&to))
+}
+
+fn filetype_to_extension(filetype: &str) -> Option<&'static str> {
+    let extension = match filetype {
         "markdown" => "md",
         "pdf" => "pdf",
         "latex" => "tex",
         "docx" => "docx",
         "odt" => "odt",
-        _ => "txt",
+        "html" => "html",
+        "rst" => "rst",
+        "typst" => "typ",
+        "epub" => "epub",
+        "zip" => "zip",
+        "revealjs" => "html",
+        "beamer" => "pdf",
+        _ => return None,
+    };
+    Some(extension)
+}
+
+/// Extension of an output in `to_filetype`, `bin` if the worker sent a filetype the bot doesn't know
+fn output_extension(to_filetype: &str) -> &'static str {
+    filetype_to_extension(to_filetype).unwrap_or_else(|| {
+        warn!("Received output of unknown filetype {to_filetype:?}");
+        "bin"
+    })
+}
+
+/// Name an output `stem` if chosen with /name, otherwise after the original file
+fn delivered_filename(
+    stem: Option<&str>,
+    original_filename: Option<&str>,
+    extension: &str,
+) -> String {
+    match stem {
+        Some(stem) => format!("{stem}.{extension}"),
+        None => output_filename(original_filename, extension),
     }
 }
 
+/// Name the output after the original file's stem, falling back to `output`.
+/// Directory components of `original_filename` are dropped.
+fn output_filename(original_filename: Option<&str>, extension: &str) -> String {
+    let stem = original_filename
+        .and_then(|name| name.rsplit(['/', '\\']).next())
+        .and_then(|name| Path::new(name).file_stem())
+        .and_then(|stem| stem.to_str())
+        .filter(|stem| !stem.is_empty())
+        .unwrap_or("output");
+    format!("{stem}.{extension}")
+}
+
+/// Reverse of `filetype_to_extension`, restricted to `FROM_FILETYPES`
+fn extension_to_from_filetype(extension: &str) -> Option<&'static str> {
+    let extension = extension.to_lowercase();
+    FROM_FILETYPES
+        .iter()
+        .copied()
+        .find(|&filetype| filetype_to_extension(filetype) == Some(extension.as_str()))
+}
+
+/// Guess the source filetype from the name of the document attached to `msg`
+fn detect_from_filetype(msg: Message) -> Option<String> {
+    let file_name = msg.document()?.file_name.as_ref()?;
+    let extension = Path::new(file_name).extension()?.to_str()?;
+    extension_to_from_filetype(extension).map(str::to_owned)
+}
+
+fn make_confirm_keyboard() -> InlineKeyboardMarkup {
+    InlineKeyboardMarkup::new([[
+        InlineKeyboardButton::callback("Confirm ✔".to_owned(), CONFIRM_BUTTON_DATA.to_owned()),
+        InlineKeyboardButton::callback("Cancel ✖".to_owned(), CANCEL_BUTTON_DATA.to_owned()),
+    ]])
+}
+
 /// Convert array of `&str` into a keyboard
 fn make_keyboard(contents: &[&str], num_per_row: usize) -> InlineKeyboardMarkup {
     let mut keyboard: Vec<Vec<InlineKeyboardButton>> = vec![];
-    for filetypes in contents.chunks(num_per_row) {
+    // `chunks` panics on 0
+    for filetypes in contents.chunks(num_per_row.max(1)) {
         let row = filetypes
             .iter()
             .map(|&version| InlineKeyboardButton::callback(version.to_owned(), version.to_owned()))
@@ -421,12 +6492,112 @@ fn make_keyboard(contents: &[&str], num_per_row: usize) -> InlineKeyboardMarkup
     InlineKeyboardMarkup::new(keyboard)
 }
 
-fn make_from_keyboard() -> InlineKeyboardMarkup {
-    make_keyboard(FROM_FILETYPES, 3)
+fn make_from_keyboard(config: &Config) -> InlineKeyboardMarkup {
+    make_keyboard(FROM_FILETYPES, config.keyboard_buttons_per_row)
+}
+
+/// Callback data of the button returning to the previous step
+const BACK_BUTTON_DATA: &str = "__back__";
+
+/// Callback data of the button finishing a multiple choice
+const DONE_BUTTON_DATA: &str = "__done__";
+
+/// Output formats with the `selected` ones checked, followed by Back and Done
+fn make_to_keyboard(config: &Config, selected: &[String]) -> InlineKeyboardMarkup {
+    let num_per_row = config.keyboard_buttons_per_row.max(1);
+    let keyboard = TO_FILETYPES
+        .chunks(num_per_row)
+        .map(|filetypes| {
+            filetypes
+                .iter()
+                .map(|&filetype| {
+                    let label = if selected.iter().any(|selected| selected == filetype) {
+                        format!("✓ {filetype}")
+                    } else {
+                        filetype.to_owned()
+                    };
+                    InlineKeyboardButton::callback(label, filetype.to_owned())
+                })
+                .collect()
+        })
+        .collect::<Vec<Vec<_>>>();
+
+    InlineKeyboardMarkup::new(keyboard).append_row(vec![
+        InlineKeyboardButton::callback("⬅ Back".to_owned(), BACK_BUTTON_DATA.to_owned()),
+        InlineKeyboardButton::callback("Done ✔".to_owned(), DONE_BUTTON_DATA.to_owned()),
+    ])
+}
+
+/// PDF engines with the `preferred` one checked
+fn make_pdf_engine_keyboard(preferred: Option<&str>) -> InlineKeyboardMarkup {
+    let keyboard = PDF_ENGINES
+        .chunks(2)
+        .map(|engines| {
+            engines
+                .iter()
+                .map(|&engine| {
+                    let label = if preferred == Some(engine) {
+                        format!("✓ {engine}")
+                    } else {
+                        engine.to_owned()
+                    };
+                    InlineKeyboardButton::callback(label, engine.to_owned())
+                })
+                .collect()
+        })
+        .collect::<Vec<Vec<_>>>();
+    InlineKeyboardMarkup::new(keyboard)
+}
+
+fn make_markdown_flavor_keyboard() -> InlineKeyboardMarkup {
+    make_keyboard(MARKDOWN_FLAVORS, 3)
+}
+
+/// One button per extension showing its setting in the reader `spec`, followed by Done
+fn make_markdown_extensions_keyboard(spec: &str) -> InlineKeyboardMarkup {
+    let mut keyboard: Vec<Vec<InlineKeyboardButton>> = MARKDOWN_EXTENSIONS
+        .iter()
+        .map(|&extension| {
+            let setting = match extension_setting(spec, extension) {
+                Some(true) => "on",
+                Some(false) => "off",
+                None => "default",
+            };
+            vec![InlineKeyboardButton::callback(
+                format!("{extension}: {setting}"),
+                extension.to_owned(),
+            )]
+        })
+        .collect();
+    keyboard.push(vec![InlineKeyboardButton::callback(
+        "Done".to_owned(),
+        DONE_BUTTON_DATA.to_owned(),
+    )]);
+    InlineKeyboardMarkup::new(keyboard)
+}
+
+fn make_highlight_style_keyboard() -> InlineKeyboardMarkup {
+    make_keyboard(HIGHLIGHT_STYLES, 3)
 }
 
-fn make_to_keyboard() -> InlineKeyboardMarkup {
-    make_keyboard(TO_FILETYPES, 3)
+fn make_revealjs_theme_keyboard() -> InlineKeyboardMarkup {
+    make_keyboard(REVEALJS_THEMES, 3)
+}
+
+/// Toggles of the boolean `options`, the standalone one only if HTML is among `to_filetypes`
+fn make_options_keyboard(options: &JobOptions, to_filetypes: &[String]) -> InlineKeyboardMarkup {
+    let on_off = |value| if value { "on" } else { "off" };
+    let mut keyboard = vec![vec![InlineKeyboardButton::callback(
+        format!("Include TOC: {}", on_off(options.toc)),
+        TOC_BUTTON_DATA.to_owned(),
+    )]];
+    if to_filetypes.iter().any(|to_filetype| to_filetype == "html") {
+        keyboard.push(vec![InlineKeyboardButton::callback(
+            format!("Standalone HTML: {}", on_off(options.standalone)),
+            STANDALONE_BUTTON_DATA.to_owned(),
+        )]);
+    }
+    InlineKeyboardMarkup::new(keyboard)
 }
 
 /// Remove keyboard from `CallbackQuery`
@@ -444,20 +6615,924 @@ async fn remove_keyboard_from(bot: &Bot, query: &CallbackQuery) -> Result<()> {
     Ok(())
 }
 
-/// Defaults to `./inputs/<file_id>`.
-/// If the env var is defined, then `$INPUT_BASE_PATH/inputs/<file_id>`.
-fn path_for_input_file<S: AsRef<str>>(file_id: S) -> PathBuf {
-    let mut path = env::var("INPUT_BASE_PATH")
-        .map(PathBuf::from)
-        .unwrap_or(PathBuf::from("inputs"));
-    path.push(file_id.as_ref());
-    path
+/// Whether a file of `size` bytes is over `limit`; a file exactly at the limit is accepted
+fn exceeds_file_size_limit(size: u64, limit: u64) -> bool {
+    size > limit
 }
 
-fn path_for_persistent_state() -> PathBuf {
-    if let Ok(path) = env::var("STATE_PATH") {
-        PathBuf::from(path)
-    } else {
-        PathBuf::from("./")
+/// Remove the per-chat input directories under `base_path` left behind by an interrupted submission,
+/// asking the chats still waiting in `State::ReceiveInputFile` to send their file again.
+/// Returns the number of notified chats.
+async fn reconcile_orphaned_inputs(
+    bot: &Bot,
+    storage: &MyStorage,
+    base_path: &Path,
+) -> Result<usize> {
+    if !base_path.exists() {
+        return Ok(0);
+    }
+
+    let mut count = 0;
+    let mut entries = tokio::fs::read_dir(base_path).await?;
+    while let Some(entry) = entries.next_entry().await? {
+        let chat_id = match entry.file_name().to_str().map(str::parse::<i64>) {
+            Some(Ok(chat_id)) if entry.metadata().await?.is_dir() => ChatId(chat_id),
+            _ => continue,
+        };
+
+        let mut files = tokio::fs::read_dir(entry.path()).await?;
+        let orphaned = files.next_entry().await?.is_some();
+        tokio::fs::remove_dir_all(entry.path()).await?;
+        if !orphaned {
+            continue;
+        }
+
+        info!("Found orphaned input files of chat {}", chat_id.0);
+        let state = storage.clone().get_dialogue(chat_id).await?;
+        if matches!(state, Some(State::ReceiveInputFile { .. })) {
+            match bot
+                .send_message(chat_id, "The bot restarted — please resend your file.")
+                .send()
+                .await
+            {
+                Ok(_) => count += 1,
+                Err(e) => warn!("Failed to notify chat {} of the restart: {e}", chat_id.0),
+            }
+        }
+    }
+    Ok(count)
+}
+
+/// Remove the inputs left behind by crashed submissions on startup and every
+/// `config.input_cleanup_interval` after.
+/// Inputs being downloaded are spared by their age, since writing to them keeps them fresh.
+async fn clean_up_inputs(config: Arc<Config>) {
+    let mut interval = tokio::time::interval(config.input_cleanup_interval);
+    loop {
+        interval.tick().await;
+
+        match remove_stale_chat_inputs(&config.input_base_path, config.input_max_age).await {
+            Ok(count) => info!("Removed {count} stale input files"),
+            Err(e) => warn!("Failed to remove stale input files: {e:?}"),
+        }
+    }
+}
+
+/// Remove files in the per-chat directories under `base_path` last modified longer than
+/// `max_age` ago. The directories are left in place, since a submission may be about to
+/// write to them. Returns the number of removed files.
+async fn remove_stale_chat_inputs(base_path: &Path, max_age: Duration) -> Result<usize> {
+    if !base_path.exists() {
+        return Ok(0);
+    }
+
+    let mut count = 0;
+    let mut entries = tokio::fs::read_dir(base_path).await?;
+    while let Some(entry) = entries.next_entry().await? {
+        if !entry.metadata().await?.is_dir() {
+            continue;
+        }
+
+        let mut files = tokio::fs::read_dir(entry.path()).await?;
+        while let Some(file) = files.next_entry().await? {
+            match remove_if_stale(&file.path(), max_age).await {
+                Ok(true) => count += 1,
+                Ok(false) => {}
+                Err(e) => warn!("Failed to remove stale input file {:?}: {e}", file.path()),
+            }
+        }
+    }
+    Ok(count)
+}
+
+/// Remove the file at `path` if it was last modified longer than `max_age` ago.
+/// A file that is already gone, e.g. removed by the submission it belonged to, is skipped.
+/// Returns whether the file was removed.
+async fn remove_if_stale(path: &Path, max_age: Duration) -> std::io::Result<bool> {
+    let metadata = match tokio::fs::symlink_metadata(path).await {
+        Ok(metadata) => metadata,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(false),
+        Err(e) => return Err(e),
+    };
+    if !metadata.is_file() {
+        return Ok(false);
+    }
+
+    let age = metadata.modified()?.elapsed().unwrap_or_default();
+    if age <= max_age {
+        return Ok(false);
+    }
+    match tokio::fs::remove_file(path).await {
+        Ok(()) => Ok(true),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(false),
+        Err(e) => Err(e),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::publisher::FakePublisher;
+
+    use super::*;
+
+    fn sample_input(from_filetype: &str) -> StoredInput {
+        StoredInput {
+            file_id: "BQACAgIAAxkBAAIC".to_owned(),
+            file_size: 5,
+            original_filename: Some("notes.md".to_owned()),
+            from_filetype: from_filetype.to_owned(),
+        }
+    }
+
+    /// Publish the job converting `input` to `to_filetype` and decode what reached the broker
+    async fn published_request(
+        input: &StoredInput,
+        to_filetype: &str,
+        options: &JobOptions,
+        option_files: &OptionFiles,
+    ) -> (String, bson::Document) {
+        let publisher = FakePublisher::default();
+        let pending_jobs = PendingJobs::default();
+        let config = Config::for_tests();
+        let source = JobSource {
+            input,
+            contents: b"hello",
+            options,
+            option_files,
+        };
+        let req = convert_request(
+            "job-1".to_owned(),
+            ChatId(42),
+            JobFile::Inline {
+                file: b"hello".to_vec(),
+            },
+            to_filetype.to_owned(),
+            &source,
+        );
+        publish_job(
+            &publisher,
+            &pending_jobs,
+            &Metrics::new().unwrap(),
+            &config,
+            i18n::messages(None),
+            Some(input),
+            &req,
+        )
+        .await
+        .unwrap();
+
+        assert!(pending_jobs.lock().unwrap().contains_key("job-1"));
+        let mut published = publisher.published.into_inner().unwrap();
+        assert_eq!(published.len(), 1);
+        let (job_queue, payload) = published.remove(0);
+        (job_queue, bson::from_slice(&payload).unwrap())
+    }
+
+    #[tokio::test]
+    async fn request_carries_document_and_options() {
+        let options = JobOptions {
+            pdf_engine: Some("xelatex".to_owned()),
+            source_format_variant: Some("gfm+footnotes".to_owned()),
+            metadata: HashMap::from([("title".to_owned(), "Notes".to_owned())]),
+            toc: true,
+            ..JobOptions::default()
+        };
+        let option_files = OptionFiles {
+            cover_image: Some(b"cover".to_vec()),
+            reference_doc: None,
+            bibliography: Some(b"@book{a}".to_vec()),
+        };
+        let (job_queue, doc) =
+            published_request(&sample_input("markdown"), "pdf", &options, &option_files).await;
+
+        assert_eq!(job_queue, "pandoc-bot-jobs-heavy");
+        assert_eq!(doc.get_str("job_id").unwrap(), "job-1");
+        assert_eq!(doc.get_i64("chat_id").unwrap(), 42);
+        assert_eq!(doc.get_str("file_id").unwrap(), "BQACAgIAAxkBAAIC");
+        assert_eq!(doc.get_str("original_filename").unwrap(), "notes.md");
+        assert_eq!(doc.get_str("from_filetype").unwrap(), "markdown");
+        assert_eq!(doc.get_str("to_filetype").unwrap(), "pdf");
+        assert_eq!(doc.get_binary_generic("file").unwrap(), b"hello");
+
+        // The options are flattened into the request
+        assert!(!doc.contains_key("options"));
+        assert_eq!(doc.get_str("pdf_engine").unwrap(), "xelatex");
+        assert_eq!(
+            doc.get_str("source_format_variant").unwrap(),
+            "gfm+footnotes"
+        );
+        assert_eq!(
+            doc.get_document("metadata")
+                .unwrap()
+                .get_str("title")
+                .unwrap(),
+            "Notes"
+        );
+        assert!(doc.get_bool("toc").unwrap());
+        assert!(doc.get_bool("standalone").unwrap());
+        assert!(!doc.get_bool("dry_run").unwrap());
+
+        // Only the files the output uses are sent along
+        assert!(!doc.contains_key("cover_image"));
+        assert!(!doc.contains_key("reference_doc"));
+        assert_eq!(doc.get_binary_generic("bibliography").unwrap(), b"@book{a}");
+
+        assert!(!doc.contains_key("resource_path"));
+        assert!(!doc.contains_key("cache_key"));
+    }
+
+    #[tokio::test]
+    async fn archive_request_sets_resource_path() {
+        let option_files = OptionFiles {
+            cover_image: Some(b"cover".to_vec()),
+            reference_doc: None,
+            bibliography: None,
+        };
+        let (job_queue, doc) = published_request(
+            &sample_input("zip"),
+            "epub",
+            &JobOptions::default(),
+            &option_files,
+        )
+        .await;
+
+        assert_eq!(job_queue, "pandoc-bot-jobs-light");
+        assert_eq!(doc.get_str("from_filetype").unwrap(), "zip");
+        assert_eq!(doc.get_str("to_filetype").unwrap(), "epub");
+        assert_eq!(doc.get_str("resource_path").unwrap(), ".");
+        assert_eq!(doc.get_binary_generic("cover_image").unwrap(), b"cover");
+    }
+
+    #[tokio::test]
+    async fn shared_input_of_unpublished_job_is_removed() {
+        let path = std::env::temp_dir().join(format!("pandoc-bot-test-{}", Uuid::new_v4()));
+        tokio::fs::write(&path, b"hello").await.unwrap();
+        let option_files = OptionFiles {
+            cover_image: None,
+            reference_doc: None,
+            bibliography: None,
+        };
+        let source = JobSource {
+            input: &sample_input("markdown"),
+            contents: b"hello",
+            options: &JobOptions::default(),
+            option_files: &option_files,
+        };
+        let req = convert_request(
+            "job-1".to_owned(),
+            ChatId(42),
+            JobFile::External {
+                path: path.to_str().unwrap().to_owned(),
+            },
+            "html".to_owned(),
+            &source,
+        );
+
+        remove_shared_input(&req).await;
+        assert!(!path.exists());
+    }
+
+    /// Reply of the stand-in Telegram server to every method, a message it sent
+    const SENT_MESSAGE: &str = r#"{"ok":true,"result":{"message_id":1,"date":0,"chat":{"id":42,"type":"private","first_name":"A"},"text":"ok"}}"#;
+
+    /// A bot talking to a local stand-in for the Telegram Bot API
+    fn fake_telegram() -> Bot {
+        use hyper::service::{make_service_fn, service_fn};
+
+        let make_service = make_service_fn(|_| async {
+            Ok::<_, std::convert::Infallible>(service_fn(|_| async {
+                Ok::<_, std::convert::Infallible>(hyper::Response::new(hyper::Body::from(
+                    SENT_MESSAGE,
+                )))
+            }))
+        });
+        let server = hyper::Server::bind(&([127, 0, 0, 1], 0).into()).serve(make_service);
+        let api_url = Url::parse(&format!("http://{}/", server.local_addr())).unwrap();
+        tokio::spawn(server);
+        Bot::new("123:test").set_api_url(api_url)
+    }
+
+    /// A text message sent by a user in chat 42
+    fn user_message(text: &str) -> Message {
+        serde_json::from_value(serde_json::json!({
+            "message_id": 7,
+            "date": 0,
+            "chat": {"id": 42, "type": "private", "first_name": "A"},
+            "from": {"id": 42, "is_bot": false, "first_name": "A", "language_code": "en"},
+            "text": text,
+        }))
+        .unwrap()
+    }
+
+    fn test_submitter(publisher: Arc<dyn JobPublisher>) -> JobSubmitter {
+        let config = Arc::new(Config::for_tests());
+        JobSubmitter {
+            publisher,
+            pending_jobs: PendingJobs::default(),
+            metrics: Arc::new(Metrics::new().unwrap()),
+            rate_limiter: RateLimiter::new(config.max_jobs_per_hour, Duration::from_secs(60 * 60)),
+            heartbeat: Arc::new(WorkerHeartbeat::new(config.worker_heartbeat_timeout)),
+            last_inputs: InMemStorage::new().erase(),
+            prefs: InMemStorage::new().erase(),
+            download_permits: Semaphore::new(1),
+            maintenance_mode: Arc::new(MaintenanceMode::load(
+                std::env::temp_dir().join(format!("pandoc-bot-test-{}", Uuid::new_v4())),
+            )),
+            output_cache: Arc::new(OutputCache::new(
+                config.output_cache_entries,
+                config.output_cache_ttl,
+            )),
+            last_outputs: Arc::new(LastOutputs::new(MAX_RESENDABLE_OUTPUTS, RESEND_TTL)),
+            history: Arc::new(History::new(InMemStorage::new().erase())),
+            config,
+        }
+    }
+
+    #[tokio::test]
+    async fn pasted_input_is_published_and_ends_the_dialogue() {
+        let publisher = Arc::new(FakePublisher::default());
+        let storage: MyStorage = InMemStorage::new().erase();
+        let dialogue = MyDialogue::new(storage.clone(), ChatId(42));
+        let options = JobOptions::default();
+        dialogue
+            .update(State::ReceiveInputFile {
+                from_filetype: "markdown".to_owned(),
+                to_filetypes: vec!["html".to_owned()],
+                options: options.clone(),
+            })
+            .await
+            .unwrap();
+
+        receive_input_file(
+            fake_telegram(),
+            user_message("# Notes"),
+            dialogue,
+            Arc::new(test_submitter(publisher.clone())),
+            Arc::new(RecentUploads::new(RECENT_UPLOAD_TTL)),
+            Arc::new(Albums::default()),
+            ("markdown".to_owned(), vec!["html".to_owned()], options),
+        )
+        .await
+        .unwrap();
+
+        let published = publisher.published.lock().unwrap();
+        assert_eq!(published.len(), 1);
+        let doc: bson::Document = bson::from_slice(&published[0].1).unwrap();
+        assert_eq!(doc.get_i64("chat_id").unwrap(), 42);
+        assert_eq!(doc.get_str("file_id").unwrap(), "text-42-7");
+        assert_eq!(doc.get_str("from_filetype").unwrap(), "markdown");
+        assert_eq!(doc.get_str("to_filetype").unwrap(), "html");
+        assert_eq!(doc.get_str("original_filename").unwrap(), "message.md");
+        assert_eq!(doc.get_binary_generic("file").unwrap(), b"# Notes");
+        assert!(matches!(
+            storage.get_dialogue(ChatId(42)).await.unwrap(),
+            Some(State::Start)
+        ));
+    }
+
+    /// Refuses every job, like a broker that's gone
+    struct FailingPublisher;
+
+    #[async_trait::async_trait]
+    impl JobPublisher for FailingPublisher {
+        async fn publish(&self, _job_queue: &str, _payload: &[u8], _ttl: Duration) -> Result<()> {
+            Err(anyhow::anyhow!("The broker rejected the job"))
+        }
+
+        async fn jobs_waiting(&self, _job_queue: &str) -> Option<u32> {
+            None
+        }
+    }
+
+    #[tokio::test]
+    async fn unpublished_jobs_are_failed_in_the_history() {
+        let submitter = Arc::new(test_submitter(Arc::new(FailingPublisher)));
+        let storage: MyStorage = InMemStorage::new().erase();
+        let to_filetypes = vec!["html".to_owned(), "docx".to_owned()];
+
+        let submitted = receive_input_file(
+            fake_telegram(),
+            user_message("# Notes"),
+            MyDialogue::new(storage, ChatId(42)),
+            submitter.clone(),
+            Arc::new(RecentUploads::new(RECENT_UPLOAD_TTL)),
+            Arc::new(Albums::default()),
+            ("markdown".to_owned(), to_filetypes, JobOptions::default()),
+        )
+        .await;
+
+        assert!(submitted.is_err());
+        let entries = submitter.history.recent(ChatId(42)).await.unwrap();
+        assert_eq!(entries.len(), 2);
+        assert!(entries
+            .iter()
+            .all(|entry| matches!(entry.outcome, Outcome::Failed)));
+    }
+
+    #[test]
+    fn file_size_limit_is_inclusive() {
+        let limit = 20 * BYTES_PER_MB;
+        assert!(!exceeds_file_size_limit(limit - 1, limit));
+        assert!(!exceeds_file_size_limit(limit, limit));
+        assert!(exceeds_file_size_limit(limit + 1, limit));
+    }
+
+    #[test]
+    fn every_filetype_has_an_extension() {
+        let expected = [
+            ("markdown", "md"),
+            ("html", "html"),
+            ("docx", "docx"),
+            ("rst", "rst"),
+            ("latex", "tex"),
+            ("epub", "epub"),
+            ("typst", "typ"),
+            ("zip", "zip"),
+            ("pdf", "pdf"),
+            ("odt", "odt"),
+            ("revealjs", "html"),
+            ("beamer", "pdf"),
+        ];
+        for filetype in FROM_FILETYPES.iter().chain(TO_FILETYPES) {
+            let extension = expected
+                .iter()
+                .find(|(known, _)| known == filetype)
+                .map(|&(_, extension)| extension);
+            assert_eq!(
+                filetype_to_extension(filetype),
+                extension,
+                "extension of {filetype}"
+            );
+            assert!(extension.is_some(), "no expected extension for {filetype}");
+        }
+        assert_eq!(filetype_to_extension("mobi"), None);
+    }
+
+    #[test]
+    fn unknown_output_falls_back_to_bin() {
+        assert_eq!(output_extension("pdf"), "pdf");
+        assert_eq!(output_extension("revealjs"), "html");
+        assert_eq!(output_extension("mobi"), "bin");
+    }
+
+    fn round_trip(res: &ConvertResponse) -> ConvertResponse {
+        decode_response(&bson::to_vec(res).unwrap()).unwrap()
+    }
+
+    fn generic_binary(bytes: &[u8]) -> bson::Binary {
+        bson::Binary {
+            subtype: bson::spec::BinarySubtype::Generic,
+            bytes: bytes.to_vec(),
+        }
+    }
+
+    #[test]
+    fn success_with_inline_output_round_trips() {
+        let res = round_trip(&ConvertResponse::Success {
+            job_id: "job-1".to_owned(),
+            chat_id: 42,
+            output: JobFile::Inline {
+                file: b"%PDF-1.5".to_vec(),
+            },
+            to_filetype: "pdf".to_owned(),
+            original_filename: Some("notes.md".to_owned()),
+            extra_files: vec![OutputFile {
+                name: "figure.png".to_owned(),
+                file: JobFile::Inline {
+                    file: b"\x89PNG".to_vec(),
+                },
+            }],
+            warnings: vec!["Missing character".to_owned()],
+        });
+        match res {
+            ConvertResponse::Success {
+                job_id,
+                chat_id,
+                output: JobFile::Inline { file },
+                to_filetype,
+                original_filename,
+                extra_files,
+                warnings,
+            } => {
+                assert_eq!(job_id, "job-1");
+                assert_eq!(chat_id, 42);
+                assert_eq!(file, b"%PDF-1.5");
+                assert_eq!(to_filetype, "pdf");
+                assert_eq!(original_filename.as_deref(), Some("notes.md"));
+                assert_eq!(extra_files.len(), 1);
+                assert_eq!(extra_files[0].name, "figure.png");
+                assert!(
+                    matches!(&extra_files[0].file, JobFile::Inline { file } if file == b"\x89PNG")
+                );
+                assert_eq!(warnings, ["Missing character"]);
+            }
+            res => panic!("unexpected response {res:?}"),
+        }
+    }
+
+    #[test]
+    fn success_with_external_output_round_trips() {
+        let res = round_trip(&ConvertResponse::Success {
+            job_id: "job-1".to_owned(),
+            chat_id: 42,
+            output: JobFile::External {
+                path: "/shared/job-1.pdf".to_owned(),
+            },
+            to_filetype: "pdf".to_owned(),
+            original_filename: None,
+            extra_files: vec![],
+            warnings: vec![],
+        });
+        assert!(matches!(
+            res,
+            ConvertResponse::Success {
+                output: JobFile::External { path },
+                original_filename: None,
+                ..
+            } if path == "/shared/job-1.pdf"
+        ));
+    }
+
+    #[test]
+    fn failure_round_trips() {
+        let res = round_trip(&ConvertResponse::Failure {
+            job_id: "job-1".to_owned(),
+            chat_id: 42,
+            error_msg: "Unknown reader".to_owned(),
+        });
+        assert!(matches!(
+            res,
+            ConvertResponse::Failure { job_id, chat_id: 42, error_msg }
+                if job_id == "job-1" && error_msg == "Unknown reader"
+        ));
+    }
+
+    #[test]
+    fn validated_round_trips() {
+        let res = round_trip(&ConvertResponse::Validated {
+            job_id: "job-1".to_owned(),
+            chat_id: 42,
+        });
+        assert!(matches!(
+            res,
+            ConvertResponse::Validated { job_id, chat_id: 42 } if job_id == "job-1"
+        ));
+    }
+
+    #[test]
+    fn decodes_worker_response() {
+        let doc = bson::doc! {
+            "status": "success",
+            "job_id": "job-1",
+            "chat_id": 42_i64,
+            "file": generic_binary(b"PK\x03\x04"),
+            "to_filetype": "docx",
+            "original_filename": "notes.md",
+            "extra_files": [
+                { "name": "media/figure.png", "file": generic_binary(b"\x89PNG") },
+                { "name": "media/large.png", "path": "/shared/large.png" },
+            ],
+        };
+        let res = decode_response(&bson::to_vec(&doc).unwrap()).unwrap();
+        match res {
+            ConvertResponse::Success {
+                output: JobFile::Inline { file },
+                extra_files,
+                warnings,
+                ..
+            } => {
+                assert_eq!(file, b"PK\x03\x04");
+                assert!(
+                    matches!(&extra_files[0].file, JobFile::Inline { file } if file == b"\x89PNG")
+                );
+                assert!(
+                    matches!(&extra_files[1].file, JobFile::External { path } if path == "/shared/large.png")
+                );
+                assert!(warnings.is_empty());
+            }
+            res => panic!("unexpected response {res:?}"),
+        }
+    }
+
+    #[test]
+    fn decodes_response_without_status() {
+        let success = bson::doc! {
+            "job_id": "job-1",
+            "chat_id": 42_i64,
+            "file": generic_binary(b"%PDF-1.5"),
+            "to_filetype": "pdf",
+            "original_filename": null,
+        };
+        let res = decode_response(&bson::to_vec(&success).unwrap()).unwrap();
+        assert!(matches!(
+            res,
+            ConvertResponse::Success { output: JobFile::Inline { file }, .. } if file == b"%PDF-1.5"
+        ));
+
+        let failure = bson::doc! {
+            "job_id": "job-1",
+            "chat_id": 42_i64,
+            "error_msg": "Unknown reader",
+        };
+        let res = decode_response(&bson::to_vec(&failure).unwrap()).unwrap();
+        assert!(matches!(res, ConvertResponse::Failure { .. }));
+    }
+
+    fn row_texts(keyboard: &InlineKeyboardMarkup) -> Vec<Vec<&str>> {
+        keyboard
+            .inline_keyboard
+            .iter()
+            .map(|row| row.iter().map(|button| button.text.as_str()).collect())
+            .collect()
+    }
+
+    #[test]
+    fn keyboard_rows_have_num_per_row_buttons() {
+        let contents = ["pdf", "latex", "docx", "odt"];
+        assert_eq!(
+            row_texts(&make_keyboard(&contents, 0)),
+            [["pdf"], ["latex"], ["docx"], ["odt"]]
+        );
+        assert_eq!(
+            row_texts(&make_keyboard(&contents, 1)),
+            [["pdf"], ["latex"], ["docx"], ["odt"]]
+        );
+        assert_eq!(
+            row_texts(&make_keyboard(&contents, 3)),
+            [vec!["pdf", "latex", "docx"], vec!["odt"]]
+        );
+    }
+
+    #[test]
+    fn zero_reported_size_is_empty() {
+        assert!(is_empty_upload(0));
+        assert!(!is_empty_upload(1));
+    }
+
+    #[tokio::test]
+    async fn empty_download_is_empty() {
+        let path = std::env::temp_dir().join(format!("pandoc-bot-test-{}", Uuid::new_v4()));
+
+        tokio::fs::write(&path, b"").await.unwrap();
+        assert!(is_empty_file(&path).await.unwrap());
+
+        tokio::fs::write(&path, b"# Notes").await.unwrap();
+        assert!(!is_empty_file(&path).await.unwrap());
+
+        tokio::fs::remove_file(&path).await.unwrap();
+        assert!(is_empty_file(&path).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn only_stale_files_are_removed() {
+        let path = std::env::temp_dir().join(format!("pandoc-bot-test-{}", Uuid::new_v4()));
+        tokio::fs::write(&path, b"# Notes").await.unwrap();
+
+        assert!(!remove_if_stale(&path, Duration::from_secs(60))
+            .await
+            .unwrap());
+        assert!(path.exists());
+
+        std::fs::File::options()
+            .write(true)
+            .open(&path)
+            .unwrap()
+            .set_modified(SystemTime::now() - Duration::from_secs(120))
+            .unwrap();
+        assert!(remove_if_stale(&path, Duration::from_secs(60))
+            .await
+            .unwrap());
+        assert!(!path.exists());
+
+        // Removed in the meantime by the submission it belonged to
+        assert!(!remove_if_stale(&path, Duration::from_secs(60))
+            .await
+            .unwrap());
+    }
+
+    #[test]
+    fn malformed_response_is_dropped_and_next_one_decoded() {
+        let metrics = Metrics::new().unwrap();
+        let valid = bson::to_vec(&ConvertResponse::Failure {
+            job_id: "job-1".to_owned(),
+            chat_id: 42,
+            error_msg: "Unknown reader".to_owned(),
+        })
+        .unwrap();
+        let deliveries = [b"\x05\x00garbage".to_vec(), vec![], valid];
+
+        let decoded: Vec<_> = deliveries
+            .iter()
+            .filter_map(|data| decode_or_drop(data, &metrics))
+            .collect();
+
+        assert_eq!(decoded.len(), 1);
+        assert_eq!(decoded[0].job_id(), "job-1");
+        assert_eq!(metrics.malformed_responses.get(), 2);
+    }
+
+    /// Records what happened to it, in place of a broker's acker
+    struct FakeMessage {
+        data: Vec<u8>,
+        settled: Arc<Mutex<Vec<(Vec<u8>, &'static str)>>>,
+    }
+
+    #[async_trait::async_trait]
+    impl ReturnedMessage for FakeMessage {
+        fn data(&self) -> &[u8] {
+            &self.data
+        }
+
+        async fn ack(&self) -> Result<()> {
+            let settled = (self.data.clone(), "ack");
+            self.settled.lock().unwrap().push(settled);
+            Ok(())
+        }
+
+        async fn requeue(&self) -> Result<()> {
+            let settled = (self.data.clone(), "requeue");
+            self.settled.lock().unwrap().push(settled);
+            Ok(())
+        }
+    }
+
+    /// Records the jobs of the responses it's given
+    #[derive(Default)]
+    struct RecordingHandler {
+        handled: Mutex<Vec<String>>,
+    }
+
+    #[async_trait::async_trait]
+    impl ResponseHandler for RecordingHandler {
+        async fn handle_response(&self, res: ConvertResponse) -> Result<()> {
+            self.handled.lock().unwrap().push(res.job_id().to_owned());
+            Ok(())
+        }
+    }
+
+    #[tokio::test]
+    async fn consumer_survives_garbage_and_handles_the_next_response() {
+        let metrics = Metrics::new().unwrap();
+        let pending_jobs = PendingJobs::default();
+        let handler = RecordingHandler::default();
+        let settled = Arc::new(Mutex::new(vec![]));
+        let garbage = b"\x05\x00garbage".to_vec();
+        let valid = bson::to_vec(&ConvertResponse::Failure {
+            job_id: "job-1".to_owned(),
+            chat_id: 42,
+            error_msg: "Unknown reader".to_owned(),
+        })
+        .unwrap();
+        let messages = [garbage.clone(), valid.clone()].map(|data| {
+            Ok::<_, std::io::Error>(FakeMessage {
+                data,
+                settled: settled.clone(),
+            })
+        });
+
+        handle_deliveries(
+            futures_lite::stream::iter(messages),
+            &handler,
+            &metrics,
+            &pending_jobs,
+        )
+        .await
+        .unwrap();
+
+        assert_eq!(*handler.handled.lock().unwrap(), ["job-1"]);
+        assert_eq!(*settled.lock().unwrap(), [(garbage, "ack"), (valid, "ack")]);
+        assert_eq!(metrics.malformed_responses.get(), 1);
+    }
+
+    #[test]
+    fn only_admin_chats_pass_the_admin_filter() {
+        let config = Config::for_tests();
+        assert!(!is_admin(user_message("/health"), Arc::new(config)));
+        let config = Config {
+            admin_chat_ids: vec![42],
+            ..Config::for_tests()
+        };
+        assert!(is_admin(user_message("/health"), Arc::new(config)));
+
+        assert!(is_admin_command(Command::Maintenance("on".to_owned())));
+        assert!(!is_admin_command(Command::History));
+    }
+
+    #[test]
+    fn html_special_characters_are_escaped() {
+        assert_eq!(
+            escape_html("<b>a & b</b> -> c"),
+            "&lt;b&gt;a &amp; b&lt;/b&gt; -&gt; c"
+        );
+        assert_eq!(escape_html("&lt;"), "&amp;lt;");
+    }
+
+    #[test]
+    fn plain_failure_text_has_no_markup() {
+        let error_msg = "Error at <stdin> line 1: a < b && c > d";
+        for messages in [&i18n::EN, &i18n::DE] {
+            for failure_text in [messages.conversion_failed, messages.validation_failed] {
+                let text = plain_failure_text(failure_text, error_msg);
+                assert!(!text.contains("<pre>"), "{text}");
+                assert!(!text.contains("</pre>"), "{text}");
+                assert!(text.contains(error_msg), "{text}");
+            }
+        }
+    }
+
+    fn inline_success(file: &[u8]) -> ConvertResponse {
+        ConvertResponse::Success {
+            job_id: "job-1".to_owned(),
+            chat_id: 42,
+            output: JobFile::Inline {
+                file: file.to_vec(),
+            },
+            to_filetype: "html".to_owned(),
+            original_filename: None,
+            extra_files: vec![],
+            warnings: vec![],
+        }
+    }
+
+    #[test]
+    fn empty_success_becomes_failure() {
+        assert!(matches!(
+            fail_empty_output(inline_success(b""), None),
+            ConvertResponse::Failure { job_id, chat_id: 42, error_msg }
+                if job_id == "job-1" && error_msg == "Conversion produced no output"
+        ));
+    }
+
+    #[test]
+    fn non_empty_success_is_unchanged() {
+        assert!(matches!(
+            fail_empty_output(inline_success(b"<p>Notes</p>"), None),
+            ConvertResponse::Success { output: JobFile::Inline { file }, .. } if file == b"<p>Notes</p>"
+        ));
+    }
+
+    fn external_success() -> ConvertResponse {
+        ConvertResponse::Success {
+            job_id: "job-1".to_owned(),
+            chat_id: 42,
+            output: JobFile::External {
+                path: "/shared/job-1.html".to_owned(),
+            },
+            to_filetype: "html".to_owned(),
+            original_filename: None,
+            extra_files: vec![],
+            warnings: vec![],
+        }
+    }
+
+    #[test]
+    fn empty_shared_success_becomes_failure() {
+        assert!(matches!(
+            fail_empty_output(external_success(), Some(0)),
+            ConvertResponse::Failure { job_id, chat_id: 42, error_msg }
+                if job_id == "job-1" && error_msg == "Conversion produced no output"
+        ));
+    }
+
+    #[test]
+    fn non_empty_shared_success_is_unchanged() {
+        for size in [Some(12), None] {
+            assert!(matches!(
+                fail_empty_output(external_success(), size),
+                ConvertResponse::Success {
+                    output: JobFile::External { .. },
+                    ..
+                }
+            ));
+        }
+    }
+
+    #[test]
+    fn legacy_input_file_state_keeps_pdf_engine() {
+        let stored = r#"{"ReceiveInputFile":{"from_filetype":"markdown","to_filetype":"pdf","pdf_engine":"xelatex"}}"#;
+        match serde_json::from_str::<State>(stored).unwrap() {
+            State::ReceiveInputFile {
+                from_filetype,
+                to_filetypes,
+                options,
+            } => {
+                assert_eq!(from_filetype, "markdown");
+                assert_eq!(to_filetypes, ["pdf"]);
+                assert_eq!(options.pdf_engine.as_deref(), Some("xelatex"));
+            }
+            _ => panic!("legacy state wasn't mapped"),
+        }
+
+        let stored = r#"{"ReceiveInputFile":{"from_filetype":"markdown","to_filetypes":["pdf"]}}"#;
+        assert!(matches!(
+            serde_json::from_str::<State>(stored).unwrap(),
+            State::ReceiveInputFile { options, .. } if options.pdf_engine.is_none()
+        ));
+
+        let stored = r#"{"ReceiveFullName":null}"#;
+        assert!(matches!(
+            serde_json::from_str::<State>(stored).unwrap(),
+            State::Start
+        ));
     }
 }
@@ -0,0 +1,42 @@
+//! Maintenance mode, in which new conversions are refused while queued ones are still delivered
+
+use std::{
+    fs,
+    path::PathBuf,
+    sync::atomic::{AtomicBool, Ordering},
+};
+
+use anyhow::{Context, Result};
+
+/// Persisted as the existence of a marker file, so it survives restarts
+pub struct MaintenanceMode {
+    path: PathBuf,
+    enabled: AtomicBool,
+}
+
+impl MaintenanceMode {
+    /// Maintenance mode is on if the marker file at `path` exists
+    pub fn load(path: PathBuf) -> Self {
+        let enabled = AtomicBool::new(path.exists());
+        Self { path, enabled }
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.enabled.load(Ordering::Relaxed)
+    }
+
+    /// Turn maintenance mode on or off, creating or removing the marker file
+    pub fn set(&self, enabled: bool) -> Result<()> {
+        if enabled {
+            fs::write(&self.path, "")
+        } else {
+            match fs::remove_file(&self.path) {
+                Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(()),
+                res => res,
+            }
+        }
+        .with_context(|| format!("Failed to update {:?}", self.path))?;
+        self.enabled.store(enabled, Ordering::Relaxed);
+        Ok(())
+    }
+}
@@ -0,0 +1,174 @@
+//! Prometheus metrics and the HTTP server exposing them
+
+use std::{
+    collections::{HashMap, VecDeque},
+    convert::Infallible,
+    net::SocketAddr,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use anyhow::Result;
+use hyper::{
+    header::CONTENT_TYPE,
+    service::{make_service_fn, service_fn},
+    Body, Method, Request, Response, Server, StatusCode,
+};
+use prometheus::{Encoder, Histogram, HistogramOpts, IntCounter, IntGauge, Registry, TextEncoder};
+use tracing::{info, warn};
+
+pub struct Metrics {
+    registry: Registry,
+    pub jobs_published: IntCounter,
+    pub conversions_succeeded: IntCounter,
+    pub conversions_failed: IntCounter,
+    /// Responses from the worker that couldn't be decoded
+    pub malformed_responses: IntCounter,
+    /// Conversions delivered from the output cache without a job
+    pub output_cache_hits: IntCounter,
+    /// Seconds between publishing a job and receiving its response
+    pub conversion_latency: Histogram,
+    /// Unix time of the last worker heartbeat, 0 if none was seen
+    pub worker_last_heartbeat: IntGauge,
+    /// Kept apart from the registry to estimate durations for users
+    pub conversion_durations: ConversionDurations,
+}
+
+impl Metrics {
+    pub fn new() -> Result<Self> {
+        let registry = Registry::new_custom(Some("pandoc_bot".into()), None)?;
+
+        let jobs_published =
+            IntCounter::new("jobs_published_total", "Jobs published to the worker")?;
+        let conversions_succeeded =
+            IntCounter::new("conversions_succeeded_total", "Successful conversions")?;
+        let conversions_failed = IntCounter::new("conversions_failed_total", "Failed conversions")?;
+        let malformed_responses = IntCounter::new(
+            "malformed_responses_total",
+            "Worker responses that couldn't be decoded",
+        )?;
+        let output_cache_hits = IntCounter::new(
+            "output_cache_hits_total",
+            "Conversions delivered from the output cache",
+        )?;
+        let conversion_latency = Histogram::with_opts(
+            HistogramOpts::new(
+                "conversion_latency_seconds",
+                "End-to-end conversion latency",
+            )
+            .buckets(vec![0.5, 1.0, 2.5, 5.0, 10.0, 30.0, 60.0, 120.0, 300.0]),
+        )?;
+        let worker_last_heartbeat = IntGauge::new(
+            "worker_last_heartbeat_timestamp_seconds",
+            "Unix time of the last worker heartbeat",
+        )?;
+
+        registry.register(Box::new(jobs_published.clone()))?;
+        registry.register(Box::new(conversions_succeeded.clone()))?;
+        registry.register(Box::new(conversions_failed.clone()))?;
+        registry.register(Box::new(malformed_responses.clone()))?;
+        registry.register(Box::new(output_cache_hits.clone()))?;
+        registry.register(Box::new(conversion_latency.clone()))?;
+        registry.register(Box::new(worker_last_heartbeat.clone()))?;
+
+        Ok(Self {
+            registry,
+            jobs_published,
+            conversions_succeeded,
+            conversions_failed,
+            malformed_responses,
+            output_cache_hits,
+            conversion_latency,
+            worker_last_heartbeat,
+            conversion_durations: ConversionDurations::default(),
+        })
+    }
+
+    /// Encode all metrics in the Prometheus text format
+    fn render(&self) -> Result<Vec<u8>> {
+        let mut buffer = vec![];
+        TextEncoder::new().encode(&self.registry.gather(), &mut buffer)?;
+        Ok(buffer)
+    }
+}
+
+/// Number of recent durations averaged per format pair
+const DURATION_WINDOW: usize = 20;
+
+/// Format pairs with fewer durations than this get no estimate
+const MIN_DURATION_SAMPLES: usize = 3;
+
+/// Recent end-to-end durations of successful conversions by format pair, since startup
+#[derive(Default)]
+pub struct ConversionDurations {
+    recent: Mutex<HashMap<(String, String), VecDeque<Duration>>>,
+}
+
+impl ConversionDurations {
+    pub fn record(&self, from_filetype: &str, to_filetype: &str, duration: Duration) {
+        let mut recent = self.recent.lock().unwrap();
+        let durations = recent
+            .entry((from_filetype.to_owned(), to_filetype.to_owned()))
+            .or_default();
+        if durations.len() == DURATION_WINDOW {
+            durations.pop_front();
+        }
+        durations.push_back(duration);
+    }
+
+    /// Average of the recent durations, `None` without enough of them
+    pub fn estimate(&self, from_filetype: &str, to_filetype: &str) -> Option<Duration> {
+        let recent = self.recent.lock().unwrap();
+        let durations = recent.get(&(from_filetype.to_owned(), to_filetype.to_owned()))?;
+        if durations.len() < MIN_DURATION_SAMPLES {
+            return None;
+        }
+        Some(durations.iter().sum::<Duration>() / durations.len() as u32)
+    }
+}
+
+/// Serve `/metrics` on `addr`
+pub async fn serve(addr: SocketAddr, metrics: Arc<Metrics>) -> Result<()> {
+    let make_service = make_service_fn(move |_| {
+        let metrics = metrics.clone();
+        async move {
+            Ok::<_, Infallible>(service_fn(move |req| {
+                let metrics = metrics.clone();
+                async move { Ok::<_, Infallible>(handle(req, &metrics)) }
+            }))
+        }
+    });
+
+    info!("Serving metrics on {addr}");
+    Server::try_bind(&addr)?.serve(make_service).await?;
+    Ok(())
+}
+
+fn handle(req: Request<Body>, metrics: &Metrics) -> Response<Body> {
+    if (req.method(), req.uri().path()) != (&Method::GET, "/metrics") {
+        return with_status(StatusCode::NOT_FOUND);
+    }
+
+    match metrics.render() {
+        Ok(body) => {
+            let mut response = Response::new(Body::from(body));
+            response.headers_mut().insert(
+                CONTENT_TYPE,
+                prometheus::TEXT_FORMAT
+                    .parse()
+                    .expect("Invalid content type"),
+            );
+            response
+        }
+        Err(e) => {
+            warn!("Failed to render metrics: {e:?}");
+            with_status(StatusCode::INTERNAL_SERVER_ERROR)
+        }
+    }
+}
+
+fn with_status(status: StatusCode) -> Response<Body> {
+    let mut response = Response::new(Body::empty());
+    *response.status_mut() = status;
+    response
+}
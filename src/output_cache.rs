@@ -0,0 +1,79 @@
+//! Outputs of recent conversions, so converting an identical input the same way again skips the worker
+
+use std::{
+    collections::HashMap,
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+use sha2::{Digest, Sha256};
+
+/// SHA-256 of an input together with everything that affects its output
+pub type CacheKey = [u8; 32];
+
+pub struct OutputCache {
+    max_entries: usize,
+    ttl: Duration,
+    entries: Mutex<HashMap<CacheKey, CachedOutput>>,
+}
+
+struct CachedOutput {
+    contents: Vec<u8>,
+    inserted_at: Instant,
+}
+
+impl OutputCache {
+    /// A cache of at most `max_entries` outputs, each kept for `ttl`. Disabled if `max_entries` is 0.
+    pub fn new(max_entries: usize, ttl: Duration) -> Self {
+        Self {
+            max_entries,
+            ttl,
+            entries: Default::default(),
+        }
+    }
+
+    /// Key of the output determined by `parts`, such as the input and the serialized options
+    pub fn key(parts: &[&[u8]]) -> CacheKey {
+        let mut hasher = Sha256::new();
+        // Lengths first, so the boundaries between the parts can't shift
+        for part in parts {
+            hasher.update((part.len() as u64).to_le_bytes());
+            hasher.update(part);
+        }
+        hasher.finalize().into()
+    }
+
+    pub fn get(&self, key: &CacheKey) -> Option<Vec<u8>> {
+        let entries = self.entries.lock().unwrap();
+        entries
+            .get(key)
+            .filter(|entry| entry.inserted_at.elapsed() < self.ttl)
+            .map(|entry| entry.contents.clone())
+    }
+
+    /// Store `contents`, evicting expired entries and then the oldest one if the cache is full
+    pub fn insert(&self, key: CacheKey, contents: Vec<u8>) {
+        if self.max_entries == 0 {
+            return;
+        }
+
+        let mut entries = self.entries.lock().unwrap();
+        entries.retain(|_, entry| entry.inserted_at.elapsed() < self.ttl);
+        if entries.len() >= self.max_entries && !entries.contains_key(&key) {
+            let oldest = entries
+                .iter()
+                .min_by_key(|(_, entry)| entry.inserted_at)
+                .map(|(&key, _)| key);
+            if let Some(oldest) = oldest {
+                entries.remove(&oldest);
+            }
+        }
+        entries.insert(
+            key,
+            CachedOutput {
+                contents,
+                inserted_at: Instant::now(),
+            },
+        );
+    }
+}
@@ -0,0 +1,72 @@
+//! Options each chat chose last time, offered as the defaults of its next conversion
+
+use serde::{Deserialize, Serialize};
+
+use crate::JobOptions;
+
+/// Outputs sent without letting Telegram detect their content type unless the chat chose otherwise,
+/// so they're offered as a download rather than previewed
+const DOWNLOAD_FILETYPES: &[&str] = &["html", "revealjs"];
+
+/// Saved defaults, each only set once the chat picked it for a format it applies to
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub struct Prefs {
+    /// Preselected when PDF is among the outputs
+    pub pdf_engine: Option<String>,
+    pub toc: Option<bool>,
+    /// Only applied when HTML is among the outputs
+    pub standalone: Option<bool>,
+    /// Chosen with `/prefs download`, outputs follow `DOWNLOAD_FILETYPES` if unset
+    #[serde(default)]
+    pub force_download: Option<bool>,
+}
+
+impl Prefs {
+    /// Options to start the options steps from, leaving out the ones irrelevant to `to_filetypes`
+    pub fn initial_options(&self, to_filetypes: &[String]) -> JobOptions {
+        let has = |filetype: &str| {
+            to_filetypes
+                .iter()
+                .any(|to_filetype| to_filetype == filetype)
+        };
+        let defaults = JobOptions::default();
+        JobOptions {
+            pdf_engine: self.pdf_engine.clone().filter(|_| has("pdf")),
+            toc: self.toc.unwrap_or(defaults.toc),
+            standalone: self
+                .standalone
+                .filter(|_| has("html"))
+                .unwrap_or(defaults.standalone),
+            ..defaults
+        }
+    }
+
+    /// Remember `options` submitted for `to_filetypes`, keeping the saved ones that didn't apply
+    pub fn record(&mut self, options: &JobOptions, to_filetypes: &[String]) {
+        let has = |filetype: &str| {
+            to_filetypes
+                .iter()
+                .any(|to_filetype| to_filetype == filetype)
+        };
+        if let Some(pdf_engine) = options.pdf_engine.clone().filter(|_| has("pdf")) {
+            self.pdf_engine = Some(pdf_engine);
+        }
+        self.toc = Some(options.toc);
+        if has("html") {
+            self.standalone = Some(options.standalone);
+        }
+    }
+
+    /// Whether an output of `to_filetype` is sent as a plain download instead of a preview
+    pub fn forces_download(&self, to_filetype: &str) -> bool {
+        self.force_download
+            .unwrap_or_else(|| DOWNLOAD_FILETYPES.contains(&to_filetype))
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.pdf_engine.is_none()
+            && self.toc.is_none()
+            && self.standalone.is_none()
+            && self.force_download.is_none()
+    }
+}
@@ -0,0 +1,127 @@
+//! Publishing jobs to the broker, behind a trait so the handlers don't depend on a live connection
+
+use std::{sync::Arc, time::Duration};
+
+use anyhow::{anyhow, Result};
+use async_trait::async_trait;
+use lapin::{
+    options::{BasicPublishOptions, ConfirmSelectOptions, QueueDeclareOptions},
+    publisher_confirm::Confirmation,
+    BasicProperties,
+};
+use tracing::warn;
+
+use crate::amqp::AmqpConnection;
+
+#[async_trait]
+pub trait JobPublisher: Send + Sync {
+    /// Publish `payload` to `job_queue`, to be dead-lettered if no worker picks it up within `ttl`.
+    /// Only succeeds once the broker confirmed that it took the job.
+    async fn publish(&self, job_queue: &str, payload: &[u8], ttl: Duration) -> Result<()>;
+
+    /// Number of jobs waiting in `job_queue`, or `None` if the broker can't tell
+    async fn jobs_waiting(&self, job_queue: &str) -> Option<u32>;
+}
+
+/// Publishes over an AMQP connection, each call on a fresh channel
+pub struct AmqpPublisher {
+    pub amqp_conn: Arc<AmqpConnection>,
+}
+
+#[async_trait]
+impl JobPublisher for AmqpPublisher {
+    async fn publish(&self, job_queue: &str, payload: &[u8], ttl: Duration) -> Result<()> {
+        let channel = self.amqp_conn.get().await?.create_channel().await?;
+        channel
+            .confirm_select(ConfirmSelectOptions::default())
+            .await?;
+        let confirmation = channel
+            .basic_publish(
+                "",
+                job_queue,
+                BasicPublishOptions::default(),
+                payload,
+                BasicProperties::default().with_expiration(ttl.as_millis().to_string().into()),
+            )
+            .await?
+            .await?;
+        match confirmation {
+            Confirmation::Ack(_) => Ok(()),
+            Confirmation::Nack(_) => Err(anyhow!("The broker rejected the job")),
+            Confirmation::NotRequested => Err(anyhow!("The broker didn't confirm the job")),
+        }
+    }
+
+    async fn jobs_waiting(&self, job_queue: &str) -> Option<u32> {
+        let channel = self
+            .amqp_conn
+            .get()
+            .await
+            .ok()?
+            .create_channel()
+            .await
+            .ok()?;
+        let queue = channel
+            .queue_declare(
+                job_queue,
+                QueueDeclareOptions {
+                    passive: true,
+                    ..Default::default()
+                },
+                Default::default(),
+            )
+            .await;
+
+        match queue {
+            Ok(queue) => Some(queue.message_count()),
+            Err(e) => {
+                warn!("Failed to get job queue depth: {e}");
+                None
+            }
+        }
+    }
+}
+
+/// Whether `e` is a channel or connection error that may go away on its own
+pub fn is_retryable(e: &anyhow::Error) -> bool {
+    matches!(
+        e.downcast_ref::<lapin::Error>(),
+        Some(
+            lapin::Error::InvalidChannel(_)
+                | lapin::Error::InvalidChannelState(_)
+                | lapin::Error::InvalidConnectionState(_)
+                | lapin::Error::ChannelsLimitReached
+                | lapin::Error::IOError(_)
+        )
+    )
+}
+
+/// Records what would have been published instead of talking to a broker
+#[cfg(test)]
+#[derive(Default)]
+pub struct FakePublisher {
+    /// Queue and payload of each published job, in order
+    pub published: std::sync::Mutex<Vec<(String, Vec<u8>)>>,
+}
+
+#[cfg(test)]
+#[async_trait]
+impl JobPublisher for FakePublisher {
+    async fn publish(&self, job_queue: &str, payload: &[u8], _ttl: Duration) -> Result<()> {
+        self.published
+            .lock()
+            .unwrap()
+            .push((job_queue.to_owned(), payload.to_vec()));
+        Ok(())
+    }
+
+    async fn jobs_waiting(&self, job_queue: &str) -> Option<u32> {
+        let published = self.published.lock().unwrap();
+        Some(
+            published
+                .iter()
+                .filter(|(queue, _)| queue == job_queue)
+                .count() as u32,
+        )
+    }
+}
@@ -0,0 +1,84 @@
+//! Per-chat limit on the number of submitted jobs
+
+use std::{
+    collections::{HashMap, VecDeque},
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+pub struct RateLimiter {
+    max_jobs: usize,
+    window: Duration,
+    /// Submission times within the window, oldest first
+    submissions: Mutex<HashMap<i64, VecDeque<Instant>>>,
+}
+
+impl RateLimiter {
+    pub fn new(max_jobs: usize, window: Duration) -> Self {
+        Self {
+            max_jobs,
+            window,
+            submissions: Default::default(),
+        }
+    }
+
+    /// Record a submission of `jobs` jobs for `chat_id` if it stays under the limit.
+    /// Otherwise returns how long until it would be allowed.
+    /// A submission of more than `max_jobs` jobs is allowed once the chat has none in the window,
+    /// so that it can pass at all.
+    pub fn try_submit(&self, chat_id: i64, jobs: usize) -> Result<(), Duration> {
+        let now = Instant::now();
+        let mut submissions = self.submissions.lock().unwrap();
+
+        // Prune every chat so idle chats don't linger forever
+        submissions.retain(|_, times| {
+            while times
+                .front()
+                .map_or(false, |&time| now.duration_since(time) >= self.window)
+            {
+                times.pop_front();
+            }
+            !times.is_empty()
+        });
+
+        let times = submissions.entry(chat_id).or_default();
+        if !times.is_empty() && times.len() + jobs > self.max_jobs {
+            // Wait for enough of the oldest submissions to leave the window, or all of them
+            let index = (times.len() + jobs - self.max_jobs - 1).min(times.len() - 1);
+            return Err(self.window - now.duration_since(times[index]));
+        }
+
+        times.extend(std::iter::repeat(now).take(jobs));
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const HOUR: Duration = Duration::from_secs(60 * 60);
+
+    #[test]
+    fn submissions_over_the_limit_wait() {
+        let limiter = RateLimiter::new(3, HOUR);
+        assert!(limiter.try_submit(1, 2).is_ok());
+        assert!(limiter.try_submit(1, 1).is_ok());
+        let wait = limiter.try_submit(1, 1).unwrap_err();
+        assert!(wait > HOUR - Duration::from_secs(1) && wait <= HOUR);
+        // Chats are limited separately
+        assert!(limiter.try_submit(2, 3).is_ok());
+    }
+
+    #[test]
+    fn oversized_submission_passes_on_empty_window() {
+        let limiter = RateLimiter::new(10, HOUR);
+        assert!(limiter.try_submit(1, 12).is_ok());
+        assert!(limiter.try_submit(1, 1).is_err());
+
+        let limiter = RateLimiter::new(10, HOUR);
+        assert!(limiter.try_submit(1, 1).is_ok());
+        let wait = limiter.try_submit(1, 12).unwrap_err();
+        assert!(wait <= HOUR);
+    }
+}
@@ -0,0 +1,37 @@
+//! Short-lived memory of submitted uploads, so a redelivered update or a double tap isn't converted twice
+
+use std::{
+    collections::HashMap,
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+pub struct RecentUploads {
+    ttl: Duration,
+    /// When each `(chat_id, file_id)` was last submitted
+    submitted: Mutex<HashMap<(i64, String), Instant>>,
+}
+
+impl RecentUploads {
+    pub fn new(ttl: Duration) -> Self {
+        Self {
+            ttl,
+            submitted: Default::default(),
+        }
+    }
+
+    /// Record a submission of `file_id` by `chat_id`.
+    /// Returns `false` if the same file was already submitted within the TTL.
+    pub fn try_insert(&self, chat_id: i64, file_id: &str) -> bool {
+        let now = Instant::now();
+        let mut submitted = self.submitted.lock().unwrap();
+        submitted.retain(|_, &mut time| now.duration_since(time) < self.ttl);
+
+        let key = (chat_id, file_id.to_owned());
+        if submitted.contains_key(&key) {
+            return false;
+        }
+        submitted.insert(key, now);
+        true
+    }
+}
@@ -0,0 +1,31 @@
+//! Optional reporting of errors and panics to Sentry
+
+use std::error::Error;
+
+use sentry::{types::Dsn, ClientInitGuard, ClientOptions};
+
+/// Start reporting to `dsn`, which also installs a panic hook.
+/// Reports are flushed when the returned guard is dropped.
+pub fn init(dsn: Dsn) -> ClientInitGuard {
+    sentry::init(ClientOptions {
+        dsn: Some(dsn),
+        release: sentry::release_name!(),
+        ..Default::default()
+    })
+}
+
+/// Report `error` tagged with the chat and job it happened for, if known.
+/// Does nothing unless [`init`] was called.
+pub fn report_error(error: &(dyn Error + 'static), chat_id: Option<i64>, job_id: Option<&str>) {
+    sentry::with_scope(
+        |scope| {
+            if let Some(chat_id) = chat_id {
+                scope.set_tag("chat_id", chat_id);
+            }
+            if let Some(job_id) = job_id {
+                scope.set_tag("job_id", job_id);
+            }
+        },
+        || sentry::capture_error(error),
+    );
+}
@@ -0,0 +1,227 @@
+//! Receiving updates through a webhook instead of long polling
+
+use std::{convert::Infallible, net::SocketAddr};
+
+use anyhow::{bail, Result};
+use hyper::{
+    body::{Bytes, HttpBody},
+    service::{make_service_fn, service_fn},
+    Body, Method, Request, Response, Server, StatusCode,
+};
+use serde::Deserialize;
+use teloxide::{
+    dispatching::{
+        stop_token::AsyncStopToken,
+        update_listeners::{StatefulListener, UpdateListener},
+    },
+    prelude::*,
+};
+use tokio::sync::mpsc;
+use tokio_stream::wrappers::UnboundedReceiverStream;
+use tracing::{info, warn};
+use url::Url;
+use uuid::Uuid;
+
+/// Header Telegram sends the secret token given to `setWebhook` in
+const SECRET_TOKEN_HEADER: &str = "X-Telegram-Bot-Api-Secret-Token";
+
+/// Largest request body read, far above any update Telegram sends
+const MAX_BODY_BYTES: usize = 1024 * 1024;
+
+/// Register `url` with Telegram and serve it on `bind_addr`.
+/// The reverse proxy in front is expected to forward `url`'s path unchanged.
+/// Only requests carrying the secret token registered along with `url` are accepted,
+/// a new one on each start.
+pub async fn listener(
+    bot: Bot,
+    url: Url,
+    bind_addr: SocketAddr,
+) -> Result<impl UpdateListener<Infallible>> {
+    let secret_token = Uuid::new_v4().simple().to_string();
+    set_webhook(&bot, &url, &secret_token).await?;
+
+    let (tx, rx) = mpsc::unbounded_channel();
+    let path = url.path().to_owned();
+    let make_service = make_service_fn(move |_| {
+        let tx = tx.clone();
+        let path = path.clone();
+        let secret_token = secret_token.clone();
+        async move {
+            Ok::<_, Infallible>(service_fn(move |req| {
+                let tx = tx.clone();
+                let path = path.clone();
+                let secret_token = secret_token.clone();
+                async move { Ok::<_, Infallible>(handle(req, &tx, &path, &secret_token).await) }
+            }))
+        }
+    });
+
+    let (stop_token, stop_flag) = AsyncStopToken::new_pair();
+    let server = Server::try_bind(&bind_addr)?
+        .serve(make_service)
+        .with_graceful_shutdown(stop_flag);
+    info!("Serving webhook {url} on {bind_addr}");
+    tokio::spawn(async move {
+        if let Err(e) = server.await {
+            warn!("Webhook server failed: {e:?}");
+        }
+    });
+
+    fn stream(
+        state: &mut (
+            UnboundedReceiverStream<Result<Update, Infallible>>,
+            AsyncStopToken,
+        ),
+    ) -> &mut UnboundedReceiverStream<Result<Update, Infallible>> {
+        &mut state.0
+    }
+
+    Ok(StatefulListener::new(
+        (UnboundedReceiverStream::new(rx), stop_token),
+        stream,
+        |state: &mut (_, AsyncStopToken)| state.1.clone(),
+    ))
+}
+
+#[derive(Deserialize)]
+struct SetWebhookResponse {
+    ok: bool,
+    description: Option<String>,
+}
+
+/// Register `url` along with `secret_token` for Telegram to send back on each update.
+/// Called directly, since the `SetWebhook` of this teloxide version predates `secret_token`.
+async fn set_webhook(bot: &Bot, url: &Url, secret_token: &str) -> Result<()> {
+    let method_url = bot
+        .api_url()
+        .join(&format!("bot{}/setWebhook", bot.token()))?;
+    let response: SetWebhookResponse = bot
+        .client()
+        .post(method_url)
+        .json(&serde_json::json!({
+            "url": url.as_str(),
+            "secret_token": secret_token,
+        }))
+        .send()
+        .await?
+        .json()
+        .await?;
+    if !response.ok {
+        bail!(
+            "Telegram refused the webhook: {}",
+            response.description.unwrap_or_default()
+        );
+    }
+    Ok(())
+}
+
+async fn handle(
+    req: Request<Body>,
+    tx: &mpsc::UnboundedSender<Result<Update, Infallible>>,
+    path: &str,
+    secret_token: &str,
+) -> Response<Body> {
+    if (req.method(), req.uri().path()) != (&Method::POST, path) {
+        return with_status(StatusCode::NOT_FOUND);
+    }
+    let token = req.headers().get(SECRET_TOKEN_HEADER);
+    if token.map(|token| token.as_bytes()) != Some(secret_token.as_bytes()) {
+        warn!("Rejected a webhook request without the secret token");
+        return with_status(StatusCode::UNAUTHORIZED);
+    }
+
+    let body = match read_body(req.into_body(), MAX_BODY_BYTES).await {
+        Ok(Some(body)) => body,
+        Ok(None) => {
+            warn!("Rejected a webhook request over {MAX_BODY_BYTES} bytes");
+            return with_status(StatusCode::PAYLOAD_TOO_LARGE);
+        }
+        Err(e) => {
+            warn!("Failed to read webhook request: {e}");
+            return with_status(StatusCode::BAD_REQUEST);
+        }
+    };
+    match serde_json::from_slice::<Update>(&body) {
+        Ok(update) => {
+            // Only fails once the dispatcher is gone, at which point updates don't matter
+            let _ = tx.send(Ok(update));
+            with_status(StatusCode::OK)
+        }
+        Err(e) => {
+            warn!("Failed to parse webhook update: {e}");
+            with_status(StatusCode::BAD_REQUEST)
+        }
+    }
+}
+
+/// Read `body` if it's at most `limit` bytes long, or `None` if it's longer
+async fn read_body(mut body: Body, limit: usize) -> Result<Option<Bytes>, hyper::Error> {
+    if body.size_hint().lower() > limit as u64 {
+        return Ok(None);
+    }
+    let mut bytes = Vec::new();
+    while let Some(chunk) = body.data().await {
+        let chunk = chunk?;
+        if bytes.len() + chunk.len() > limit {
+            return Ok(None);
+        }
+        bytes.extend_from_slice(&chunk);
+    }
+    Ok(Some(bytes.into()))
+}
+
+fn with_status(status: StatusCode) -> Response<Body> {
+    let mut response = Response::new(Body::empty());
+    *response.status_mut() = status;
+    response
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const UPDATE: &str = r#"{"update_id":1,"message":{"message_id":1,"date":0,"chat":{"id":42,"type":"private","first_name":"A"},"from":{"id":42,"is_bot":false,"first_name":"A"},"text":"/start"}}"#;
+
+    fn request(secret_token: Option<&str>, body: impl Into<Body>) -> Request<Body> {
+        let mut req = Request::post("/webhook");
+        if let Some(secret_token) = secret_token {
+            req = req.header(SECRET_TOKEN_HEADER, secret_token);
+        }
+        req.body(body.into()).unwrap()
+    }
+
+    #[tokio::test]
+    async fn only_requests_with_the_secret_token_are_accepted() {
+        let (tx, mut rx) = mpsc::unbounded_channel();
+
+        let response = handle(request(None, UPDATE), &tx, "/webhook", "secret").await;
+        assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+        let response = handle(request(Some("guess"), UPDATE), &tx, "/webhook", "secret").await;
+        assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+        assert!(rx.try_recv().is_err());
+
+        let response = handle(request(Some("secret"), UPDATE), &tx, "/webhook", "secret").await;
+        assert_eq!(response.status(), StatusCode::OK);
+        assert!(rx.try_recv().is_ok());
+    }
+
+    #[tokio::test]
+    async fn oversized_body_is_rejected() {
+        let (tx, mut rx) = mpsc::unbounded_channel();
+        let body = vec![b' '; MAX_BODY_BYTES + 1];
+
+        let response = handle(request(Some("secret"), body), &tx, "/webhook", "secret").await;
+        assert_eq!(response.status(), StatusCode::PAYLOAD_TOO_LARGE);
+        assert!(rx.try_recv().is_err());
+    }
+
+    #[tokio::test]
+    async fn body_up_to_the_limit_is_read() {
+        let body = read_body(Body::from("0123456789"), 10).await.unwrap();
+        assert_eq!(body.as_deref(), Some(&b"0123456789"[..]));
+
+        let chunks = futures_lite::stream::iter(["01234", "56789", "!"].map(Ok::<_, Infallible>));
+        let body = read_body(Body::wrap_stream(chunks), 10).await.unwrap();
+        assert!(body.is_none());
+    }
+}